@@ -0,0 +1,809 @@
+//! Payloads of the clinical ops devices author.
+//!
+//! Every clinical fact is recorded by appending one of these ops; nothing is
+//! edited in place. Corrections are further ops (`demographics_changed`,
+//! `entered_in_error`, a new note revision) so the log keeps what was believed
+//! at each point in time. Coded fields use the [`fhir`](crate::fhir)
+//! primitives.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use time::{Date, OffsetDateTime};
+
+use crate::fhir::{CodeableConcept, FhirError, Identifier, Quantity, Reference};
+use crate::payload::OpPayload;
+use crate::OperationId;
+
+time::serde::format_description!(date, Date, "[year]-[month]-[day]");
+
+/// Why a well-formed payload is not clinically meaningful.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum DomainError {
+    #[error("{field} must not be blank")]
+    Blank { field: &'static str },
+    #[error("{field}: {error}")]
+    Fhir {
+        field: &'static str,
+        error: FhirError,
+    },
+    #[error("{field} must reference a {expected}, not a {found}")]
+    WrongReference {
+        field: &'static str,
+        expected: &'static str,
+        found: String,
+    },
+    #[error("a demographics change must change at least one field")]
+    NothingChanged,
+    #[error("a conflict resolution must resolve at least two ops")]
+    NotAConflict,
+    #[error("the chosen op is not one of the ops being resolved")]
+    ChoiceNotACandidate,
+    #[error("unknown field {0}")]
+    UnknownField(String),
+    #[error("invalid value: {0}")]
+    InvalidValue(String),
+}
+
+/// FHIR `AdministrativeGender`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdministrativeGender {
+    Male,
+    Female,
+    Other,
+    Unknown,
+}
+
+/// A new patient record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatientRegistered {
+    pub family_name: String,
+    #[serde(default)]
+    pub given_names: Vec<String>,
+    #[serde(
+        default,
+        with = "date::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub birth_date: Option<Date>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gender: Option<AdministrativeGender>,
+    /// Medical record numbers, national ids and the like.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identifiers: Vec<Identifier>,
+}
+
+impl OpPayload for PatientRegistered {
+    const ENTITY_TYPE: &'static str = "Patient";
+    const OP_TYPE: &'static str = "patient.registered";
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_not_blank("family_name", &self.family_name)?;
+        check_names(&self.given_names)?;
+        check_identifiers(&self.identifiers)
+    }
+}
+
+impl PatientRegistered {
+    /// v1 had no `gender` or `identifiers`; both default to empty.
+    pub fn upcast_v1(payload: Value) -> Result<Value, String> {
+        Ok(payload)
+    }
+}
+
+/// A correction to a patient's demographics. Fields that are present replace
+/// the current value; absent fields are unchanged.
+///
+/// Demographics merge manually (see [`merge`](crate::merge)): `based_on` lists
+/// the ops whose values the author saw for the changed fields, and a change
+/// that does not build on every current value raises a conflict instead of
+/// overwriting it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatientDemographicsChanged {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub based_on: Vec<OperationId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub given_names: Option<Vec<String>>,
+    #[serde(
+        default,
+        with = "date::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub birth_date: Option<Date>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gender: Option<AdministrativeGender>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifiers: Option<Vec<Identifier>>,
+}
+
+impl OpPayload for PatientDemographicsChanged {
+    const ENTITY_TYPE: &'static str = "Patient";
+    const OP_TYPE: &'static str = "patient.demographics_changed";
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        let PatientDemographicsChanged {
+            based_on: _,
+            family_name,
+            given_names,
+            birth_date,
+            gender,
+            identifiers,
+        } = self;
+        if family_name.is_none()
+            && given_names.is_none()
+            && birth_date.is_none()
+            && gender.is_none()
+            && identifiers.is_none()
+        {
+            return Err(DomainError::NothingChanged);
+        }
+        if let Some(family_name) = family_name {
+            check_not_blank("family_name", family_name)?;
+        }
+        check_names(given_names.as_deref().unwrap_or_default())?;
+        check_identifiers(identifiers.as_deref().unwrap_or_default())
+    }
+}
+
+impl PatientDemographicsChanged {
+    /// v1 had no `based_on`, so v1 changes conflict with any value they
+    /// replace.
+    pub fn upcast_v1(payload: Value) -> Result<Value, String> {
+        Ok(payload)
+    }
+}
+
+/// Settles a [`Conflict`](crate::merge::Conflict) on a manually merged field
+/// by writing the value the clinician chose over every competing op. The
+/// op's `user_id` and `device_time` record who decided and when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConflictResolved {
+    pub field: String,
+    /// The competing ops, all of which this resolution supersedes.
+    pub resolves: Vec<OperationId>,
+    /// The candidate that was kept, or `None` if a new value was entered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chosen: Option<OperationId>,
+    /// The resolved value, in the field's own JSON shape.
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl OpPayload for ConflictResolved {
+    const ENTITY_TYPE: &'static str = "Patient";
+    const OP_TYPE: &'static str = "conflict.resolved";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        let mut resolves = self.resolves.clone();
+        resolves.sort_unstable();
+        resolves.dedup();
+        if resolves.len() < 2 {
+            return Err(DomainError::NotAConflict);
+        }
+        if self
+            .chosen
+            .is_some_and(|chosen| !resolves.contains(&chosen))
+        {
+            return Err(DomainError::ChoiceNotACandidate);
+        }
+        if let Some(reason) = &self.reason {
+            check_not_blank("reason", reason)?;
+        }
+        let field = PatientField::from_name(&self.field)
+            .ok_or_else(|| DomainError::UnknownField(self.field.clone()))?;
+        field.check_value(&self.value)
+    }
+}
+
+/// The manually merged fields of a patient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PatientField {
+    FamilyName,
+    GivenNames,
+    BirthDate,
+    Gender,
+    Identifiers,
+}
+
+impl PatientField {
+    pub const ALL: [PatientField; 5] = [
+        PatientField::FamilyName,
+        PatientField::GivenNames,
+        PatientField::BirthDate,
+        PatientField::Gender,
+        PatientField::Identifiers,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PatientField::FamilyName => "family_name",
+            PatientField::GivenNames => "given_names",
+            PatientField::BirthDate => "birth_date",
+            PatientField::Gender => "gender",
+            PatientField::Identifiers => "identifiers",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name() == name)
+    }
+
+    /// Whether `value` is a valid value of this field. Birth date and gender
+    /// may be `null`.
+    fn check_value(self, value: &Value) -> Result<(), DomainError> {
+        if value.is_null() && matches!(self, PatientField::BirthDate | PatientField::Gender) {
+            return Ok(());
+        }
+        let change = json!({ self.name(): value });
+        let change: PatientDemographicsChanged = serde_json::from_value(change)
+            .map_err(|err| DomainError::InvalidValue(err.to_string()))?;
+        change.validate()
+    }
+}
+
+/// A visit starts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncounterOpened {
+    pub patient: Reference,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<CodeableConcept>,
+}
+
+impl OpPayload for EncounterOpened {
+    const ENTITY_TYPE: &'static str = "Encounter";
+    const OP_TYPE: &'static str = "encounter.opened";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_reference("patient", &self.patient, "Patient")?;
+        check_optional_concept("reason", self.reason.as_ref())
+    }
+}
+
+/// A visit ends.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncounterClosed {
+    #[serde(with = "time::serde::rfc3339")]
+    pub ended_at: OffsetDateTime,
+}
+
+impl OpPayload for EncounterClosed {
+    const ENTITY_TYPE: &'static str = "Encounter";
+    const OP_TYPE: &'static str = "encounter.closed";
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+/// The result of an observation, e.g. `{"quantity": {...}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObservationValue {
+    Quantity(Quantity),
+    CodeableConcept(CodeableConcept),
+    String(String),
+}
+
+/// A vital sign, lab result or other measurement, coded with LOINC where
+/// possible.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObservationRecorded {
+    pub patient: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encounter: Option<Reference>,
+    pub code: CodeableConcept,
+    pub value: ObservationValue,
+    #[serde(with = "time::serde::rfc3339")]
+    pub effective_at: OffsetDateTime,
+}
+
+impl OpPayload for ObservationRecorded {
+    const ENTITY_TYPE: &'static str = "Observation";
+    const OP_TYPE: &'static str = "observation.recorded";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_reference("patient", &self.patient, "Patient")?;
+        check_optional_reference("encounter", self.encounter.as_ref(), "Encounter")?;
+        check_concept("code", &self.code)?;
+        match &self.value {
+            ObservationValue::Quantity(quantity) => quantity.validate().map_err(fhir("value")),
+            ObservationValue::CodeableConcept(concept) => check_concept("value", concept),
+            ObservationValue::String(value) => check_not_blank("value", value),
+        }
+    }
+}
+
+/// Retracts an observation that should never have been recorded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObservationEnteredInError {
+    pub reason: String,
+}
+
+impl OpPayload for ObservationEnteredInError {
+    const ENTITY_TYPE: &'static str = "Observation";
+    const OP_TYPE: &'static str = "observation.entered_in_error";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_not_blank("reason", &self.reason)
+    }
+}
+
+/// A diagnosis or problem list entry, coded with SNOMED CT or ICD-10.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionAdded {
+    pub patient: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encounter: Option<Reference>,
+    pub code: CodeableConcept,
+    #[serde(
+        default,
+        with = "date::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub onset_date: Option<Date>,
+}
+
+impl OpPayload for ConditionAdded {
+    const ENTITY_TYPE: &'static str = "Condition";
+    const OP_TYPE: &'static str = "condition.added";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_reference("patient", &self.patient, "Patient")?;
+        check_optional_reference("encounter", self.encounter.as_ref(), "Encounter")?;
+        check_concept("code", &self.code)
+    }
+}
+
+/// A condition is no longer active.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionResolved {
+    #[serde(with = "date")]
+    pub resolved_on: Date,
+}
+
+impl OpPayload for ConditionResolved {
+    const ENTITY_TYPE: &'static str = "Condition";
+    const OP_TYPE: &'static str = "condition.resolved";
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+/// A prescription, coded with RxNorm or the local formulary.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MedicationOrdered {
+    pub patient: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encounter: Option<Reference>,
+    pub medication: CodeableConcept,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dose: Option<Quantity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<CodeableConcept>,
+    /// Free-text directions, e.g. "1 tablet twice daily with food".
+    pub instructions: String,
+}
+
+impl OpPayload for MedicationOrdered {
+    const ENTITY_TYPE: &'static str = "MedicationRequest";
+    const OP_TYPE: &'static str = "medication.ordered";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_reference("patient", &self.patient, "Patient")?;
+        check_optional_reference("encounter", self.encounter.as_ref(), "Encounter")?;
+        check_concept("medication", &self.medication)?;
+        if let Some(dose) = &self.dose {
+            dose.validate().map_err(fhir("dose"))?;
+        }
+        check_optional_concept("route", self.route.as_ref())?;
+        check_not_blank("instructions", &self.instructions)
+    }
+}
+
+/// A prescription is stopped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MedicationDiscontinued {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl OpPayload for MedicationDiscontinued {
+    const ENTITY_TYPE: &'static str = "MedicationRequest";
+    const OP_TYPE: &'static str = "medication.discontinued";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        match &self.reason {
+            Some(reason) => check_not_blank("reason", reason),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A new revision of a clinical note. Each revision carries the full text
+/// and names the note's patient, so notes follow patient panels like every
+/// other clinical entity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoteRevisionCreated {
+    pub patient: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encounter: Option<Reference>,
+    /// The revision the author was editing, `None` for a note's first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<OperationId>,
+    pub body: String,
+}
+
+impl OpPayload for NoteRevisionCreated {
+    const ENTITY_TYPE: &'static str = "ClinicalNote";
+    const OP_TYPE: &'static str = "clinical_note.revision_created";
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn validate(&self) -> Result<(), DomainError> {
+        check_reference("patient", &self.patient, "Patient")?;
+        check_optional_reference("encounter", self.encounter.as_ref(), "Encounter")?;
+        check_not_blank("body", &self.body)
+    }
+}
+
+fn fhir(field: &'static str) -> impl Fn(FhirError) -> DomainError {
+    move |error| DomainError::Fhir { field, error }
+}
+
+fn check_not_blank(field: &'static str, value: &str) -> Result<(), DomainError> {
+    if value.trim().is_empty() {
+        return Err(DomainError::Blank { field });
+    }
+    Ok(())
+}
+
+fn check_names(names: &[String]) -> Result<(), DomainError> {
+    names
+        .iter()
+        .try_for_each(|name| check_not_blank("given_names", name))
+}
+
+fn check_identifiers(identifiers: &[Identifier]) -> Result<(), DomainError> {
+    identifiers
+        .iter()
+        .try_for_each(|identifier| identifier.validate().map_err(fhir("identifiers")))
+}
+
+fn check_concept(field: &'static str, concept: &CodeableConcept) -> Result<(), DomainError> {
+    concept.validate().map_err(fhir(field))
+}
+
+fn check_optional_concept(
+    field: &'static str,
+    concept: Option<&CodeableConcept>,
+) -> Result<(), DomainError> {
+    concept.map_or(Ok(()), |concept| check_concept(field, concept))
+}
+
+fn check_reference(
+    field: &'static str,
+    reference: &Reference,
+    expected: &'static str,
+) -> Result<(), DomainError> {
+    reference.validate().map_err(fhir(field))?;
+    if reference.resource_type != expected {
+        return Err(DomainError::WrongReference {
+            field,
+            expected,
+            found: reference.resource_type.clone(),
+        });
+    }
+    Ok(())
+}
+
+fn check_optional_reference(
+    field: &'static str,
+    reference: Option<&Reference>,
+    expected: &'static str,
+) -> Result<(), DomainError> {
+    reference.map_or(Ok(()), |reference| {
+        check_reference(field, reference, expected)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::{system, Coding};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn patient() -> Reference {
+        Reference {
+            resource_type: "Patient".into(),
+            id: Uuid::now_v7(),
+        }
+    }
+
+    fn heart_rate(value: ObservationValue) -> ObservationRecorded {
+        ObservationRecorded {
+            patient: patient(),
+            encounter: None,
+            code: Coding::new(system::LOINC, "8867-4").into(),
+            value,
+            effective_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[test]
+    fn patient_payloads_are_validated() {
+        let registered: PatientRegistered =
+            serde_json::from_value(json!({ "family_name": "Okafor", "given_names": ["Ada"] }))
+                .unwrap();
+        assert_eq!(registered.validate(), Ok(()));
+
+        let blank = PatientRegistered {
+            family_name: " ".into(),
+            ..registered.clone()
+        };
+        assert_eq!(
+            blank.validate(),
+            Err(DomainError::Blank {
+                field: "family_name"
+            })
+        );
+        let bad_identifier = PatientRegistered {
+            identifiers: vec![Identifier {
+                system: "mrn".into(),
+                value: "1".into(),
+            }],
+            ..registered
+        };
+        assert!(matches!(
+            bad_identifier.validate(),
+            Err(DomainError::Fhir {
+                field: "identifiers",
+                ..
+            })
+        ));
+
+        let unchanged: PatientDemographicsChanged = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(unchanged.validate(), Err(DomainError::NothingChanged));
+        let renamed: PatientDemographicsChanged =
+            serde_json::from_value(json!({ "family_name": "Okafor-Smith", "gender": "female" }))
+                .unwrap();
+        assert_eq!(renamed.validate(), Ok(()));
+        let blank_given: PatientDemographicsChanged =
+            serde_json::from_value(json!({ "given_names": [""] })).unwrap();
+        assert_eq!(
+            blank_given.validate(),
+            Err(DomainError::Blank {
+                field: "given_names"
+            })
+        );
+    }
+
+    #[test]
+    fn conflict_resolutions_are_validated() {
+        let (a, b) = (Uuid::now_v7(), Uuid::now_v7());
+        let resolution = ConflictResolved {
+            field: "family_name".into(),
+            resolves: vec![a, b],
+            chosen: Some(a),
+            value: json!("Okafor"),
+            reason: None,
+        };
+        assert_eq!(resolution.validate(), Ok(()));
+
+        let cases = [
+            (
+                ConflictResolved {
+                    resolves: vec![a, a],
+                    ..resolution.clone()
+                },
+                DomainError::NotAConflict,
+            ),
+            (
+                ConflictResolved {
+                    chosen: Some(Uuid::now_v7()),
+                    ..resolution.clone()
+                },
+                DomainError::ChoiceNotACandidate,
+            ),
+            (
+                ConflictResolved {
+                    field: "blood_type".into(),
+                    ..resolution.clone()
+                },
+                DomainError::UnknownField("blood_type".into()),
+            ),
+            (
+                ConflictResolved {
+                    value: json!(""),
+                    ..resolution.clone()
+                },
+                DomainError::Blank {
+                    field: "family_name",
+                },
+            ),
+        ];
+        for (invalid, error) in cases {
+            assert_eq!(invalid.validate(), Err(error));
+        }
+        assert!(matches!(
+            ConflictResolved {
+                value: json!(7),
+                ..resolution.clone()
+            }
+            .validate(),
+            Err(DomainError::InvalidValue(_))
+        ));
+
+        let cleared = ConflictResolved {
+            field: "birth_date".into(),
+            value: json!(null),
+            chosen: None,
+            ..resolution
+        };
+        assert_eq!(cleared.validate(), Ok(()));
+        assert_eq!(
+            PatientField::ALL.map(|field| PatientField::from_name(field.name())),
+            PatientField::ALL.map(Some)
+        );
+    }
+
+    #[test]
+    fn references_must_name_the_expected_resource() {
+        let mut encounter = EncounterOpened {
+            patient: patient(),
+            started_at: OffsetDateTime::now_utc(),
+            reason: None,
+        };
+        assert_eq!(encounter.validate(), Ok(()));
+
+        encounter.patient.resource_type = "Encounter".into();
+        assert_eq!(
+            encounter.validate(),
+            Err(DomainError::WrongReference {
+                field: "patient",
+                expected: "Patient",
+                found: "Encounter".into()
+            })
+        );
+
+        let condition = ConditionAdded {
+            patient: patient(),
+            encounter: Some(patient()),
+            code: Coding::new(system::ICD_10, "J45.909").into(),
+            onset_date: None,
+        };
+        assert!(matches!(
+            condition.validate(),
+            Err(DomainError::WrongReference {
+                field: "encounter",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn observation_values_are_validated_by_kind() {
+        assert_eq!(
+            heart_rate(ObservationValue::Quantity(Quantity::ucum(72.0, "/min"))).validate(),
+            Ok(())
+        );
+        assert!(matches!(
+            heart_rate(ObservationValue::Quantity(Quantity::ucum(
+                72.0,
+                "beats per min"
+            )))
+            .validate(),
+            Err(DomainError::Fhir { field: "value", .. })
+        ));
+        assert_eq!(
+            heart_rate(ObservationValue::String("".into())).validate(),
+            Err(DomainError::Blank { field: "value" })
+        );
+
+        let mut uncoded = heart_rate(ObservationValue::String("Regular".into()));
+        uncoded.code = CodeableConcept {
+            coding: vec![],
+            text: None,
+        };
+        assert_eq!(
+            uncoded.validate(),
+            Err(DomainError::Fhir {
+                field: "code",
+                error: FhirError::EmptyConcept
+            })
+        );
+
+        let json = serde_json::to_value(heart_rate(ObservationValue::Quantity(Quantity::ucum(
+            72.0, "/min",
+        ))))
+        .unwrap();
+        assert_eq!(json["value"]["quantity"]["code"], "/min");
+    }
+
+    #[test]
+    fn medication_and_retraction_payloads_are_validated() {
+        let order = MedicationOrdered {
+            patient: patient(),
+            encounter: None,
+            medication: Coding::new(system::RXNORM, "197361")
+                .with_display("Amlodipine 5 MG Oral Tablet")
+                .into(),
+            dose: Some(Quantity::ucum(1.0, "{tbl}")),
+            route: None,
+            instructions: "Once daily".into(),
+        };
+        assert_eq!(order.validate(), Ok(()));
+        let unexplained = MedicationOrdered {
+            instructions: "".into(),
+            ..order
+        };
+        assert_eq!(
+            unexplained.validate(),
+            Err(DomainError::Blank {
+                field: "instructions"
+            })
+        );
+
+        assert_eq!(MedicationDiscontinued { reason: None }.validate(), Ok(()));
+        assert!(MedicationDiscontinued {
+            reason: Some(" ".into())
+        }
+        .validate()
+        .is_err());
+        assert!(ObservationEnteredInError { reason: "".into() }
+            .validate()
+            .is_err());
+        let revision = NoteRevisionCreated {
+            patient: patient(),
+            encounter: None,
+            supersedes: None,
+            body: "Presents with a cough.".into(),
+        };
+        assert_eq!(revision.validate(), Ok(()));
+        assert!(NoteRevisionCreated {
+            body: "\n".into(),
+            ..revision.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(matches!(
+            NoteRevisionCreated {
+                encounter: Some(patient()),
+                ..revision
+            }
+            .validate(),
+            Err(DomainError::WrongReference {
+                field: "encounter",
+                ..
+            })
+        ));
+
+        let resolved: ConditionResolved =
+            serde_json::from_value(json!({ "resolved_on": "2026-01-31" })).unwrap();
+        assert_eq!(resolved.validate(), Ok(()));
+        assert!(
+            serde_json::from_value::<EncounterClosed>(json!({ "ended_at": "yesterday" })).is_err()
+        );
+    }
+}
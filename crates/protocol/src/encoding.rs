@@ -0,0 +1,70 @@
+//! Fixed-size byte strings that travel as lowercase hex.
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid {0} encoding")]
+pub struct InvalidEncoding(pub(crate) &'static str);
+
+/// Defines a `[u8; $len]` newtype with hex `Display`, `FromStr` and serde.
+macro_rules! hex_bytes {
+    ($(#[$meta:meta])* $name:ident, $len:literal, $what:literal) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+        pub struct $name(pub [u8; $len]);
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&hex::encode(self.0))
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}({self})", stringify!($name))
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = $crate::encoding::InvalidEncoding;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let mut bytes = [0u8; $len];
+                hex::decode_to_slice(s, &mut bytes).map_err(|_| $crate::encoding::InvalidEncoding($what))?;
+                Ok($name(bytes))
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = $crate::encoding::InvalidEncoding;
+
+            fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                bytes
+                    .try_into()
+                    .map($name)
+                    .map_err(|_| $crate::encoding::InvalidEncoding($what))
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+pub(crate) use hex_bytes;
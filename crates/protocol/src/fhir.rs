@@ -0,0 +1,549 @@
+//! FHIR-aligned primitives shared by clinical op payloads.
+//!
+//! These mirror the FHIR datatypes of the same names closely enough that a
+//! later export is a field-for-field copy (see `docs/backend/FHIR.md`). Codes
+//! are checked for shape only; value sets are not enforced, and UCUM units are
+//! checked against the UCUM grammar rather than a unit table.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{EntityId, EntityRef};
+
+/// Code system URIs used by medxz payloads.
+pub mod system {
+    pub const UCUM: &str = "http://unitsofmeasure.org";
+    pub const LOINC: &str = "http://loinc.org";
+    pub const SNOMED_CT: &str = "http://snomed.info/sct";
+    pub const ICD_10: &str = "http://hl7.org/fhir/sid/icd-10";
+    pub const RXNORM: &str = "http://www.nlm.nih.gov/research/umls/rxnorm";
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum FhirError {
+    #[error("{field} must not be blank")]
+    Blank { field: &'static str },
+    #[error("{field} must be an absolute URI, got {value:?}")]
+    InvalidUri { field: &'static str, value: String },
+    #[error("invalid code {0:?}")]
+    InvalidCode(String),
+    #[error("a CodeableConcept needs a coding or text")]
+    EmptyConcept,
+    #[error("invalid resource type {0:?}")]
+    InvalidResourceType(String),
+    #[error("quantity value must be finite")]
+    NonFiniteValue,
+    #[error("a quantity code requires a system")]
+    CodeWithoutSystem,
+    #[error("invalid UCUM unit {0:?}")]
+    InvalidUcum(String),
+}
+
+/// A business identifier such as a medical record number.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Identifier {
+    /// The namespace the value is unique in.
+    pub system: String,
+    pub value: String,
+}
+
+impl Identifier {
+    pub fn validate(&self) -> Result<(), FhirError> {
+        check_uri("system", &self.system)?;
+        check_not_blank("value", &self.value)
+    }
+}
+
+/// A code from a code system.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Coding {
+    pub system: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+impl Coding {
+    pub fn new(system: impl Into<String>, code: impl Into<String>) -> Self {
+        Coding {
+            system: system.into(),
+            code: code.into(),
+            display: None,
+        }
+    }
+
+    pub fn with_display(mut self, display: impl Into<String>) -> Self {
+        self.display = Some(display.into());
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), FhirError> {
+        check_uri("system", &self.system)?;
+        check_code(&self.code)?;
+        check_optional_not_blank("display", self.display.as_deref())
+    }
+}
+
+/// A concept given by zero or more equivalent codings and/or free text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CodeableConcept {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub coding: Vec<Coding>,
+    /// What the user saw or typed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl CodeableConcept {
+    /// Whether any coding is `code` in `system`.
+    pub fn has_code(&self, system: &str, code: &str) -> bool {
+        self.coding
+            .iter()
+            .any(|c| c.system == system && c.code == code)
+    }
+
+    pub fn validate(&self) -> Result<(), FhirError> {
+        if self.coding.is_empty() && self.text.is_none() {
+            return Err(FhirError::EmptyConcept);
+        }
+        self.coding.iter().try_for_each(Coding::validate)?;
+        check_optional_not_blank("text", self.text.as_deref())
+    }
+}
+
+impl From<Coding> for CodeableConcept {
+    fn from(coding: Coding) -> Self {
+        CodeableConcept {
+            coding: vec![coding],
+            text: None,
+        }
+    }
+}
+
+/// A reference to another entity, such as an observation's patient.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reference {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub id: EntityId,
+}
+
+impl Reference {
+    pub fn validate(&self) -> Result<(), FhirError> {
+        let mut chars = self.resource_type.chars();
+        let valid = chars.next().is_some_and(|c| c.is_ascii_uppercase())
+            && chars.all(|c| c.is_ascii_alphanumeric());
+        if !valid {
+            return Err(FhirError::InvalidResourceType(self.resource_type.clone()));
+        }
+        Ok(())
+    }
+
+    /// The FHIR literal reference, e.g. `Patient/0190…`.
+    pub fn literal(&self) -> String {
+        format!("{}/{}", self.resource_type, self.id)
+    }
+
+    /// Whether this refers to the entity `entity`.
+    pub fn is(&self, entity: &EntityRef) -> bool {
+        self.resource_type == entity.entity_type && self.id == entity.entity_id
+    }
+}
+
+impl From<EntityRef> for Reference {
+    fn from(entity: EntityRef) -> Self {
+        Reference {
+            resource_type: entity.entity_type,
+            id: entity.entity_id,
+        }
+    }
+}
+
+/// A measured amount.
+///
+/// `unit` is for display; `system` and `code` are the computable unit. Use
+/// [`Quantity::ucum`] for anything with a UCUM unit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quantity {
+    pub value: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+impl Quantity {
+    /// A quantity in the UCUM unit `code`, displayed as the code itself.
+    pub fn ucum(value: f64, code: impl Into<String>) -> Self {
+        let code = code.into();
+        Quantity {
+            value,
+            unit: Some(code.clone()),
+            system: Some(system::UCUM.into()),
+            code: Some(code),
+        }
+    }
+
+    pub fn is_ucum(&self) -> bool {
+        self.system.as_deref() == Some(system::UCUM)
+    }
+
+    pub fn validate(&self) -> Result<(), FhirError> {
+        if !self.value.is_finite() {
+            return Err(FhirError::NonFiniteValue);
+        }
+        check_optional_not_blank("unit", self.unit.as_deref())?;
+        if let Some(system) = &self.system {
+            check_uri("system", system)?;
+        }
+        match (&self.system, &self.code) {
+            (None, Some(_)) => Err(FhirError::CodeWithoutSystem),
+            (Some(_), Some(code)) if self.is_ucum() => check_ucum(code),
+            (_, Some(code)) => check_code(code),
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+fn check_not_blank(field: &'static str, value: &str) -> Result<(), FhirError> {
+    if value.trim().is_empty() {
+        return Err(FhirError::Blank { field });
+    }
+    Ok(())
+}
+
+fn check_optional_not_blank(field: &'static str, value: Option<&str>) -> Result<(), FhirError> {
+    value.map_or(Ok(()), |value| check_not_blank(field, value))
+}
+
+/// An absolute URI: a scheme, a colon and at least one more character, with no
+/// whitespace.
+fn check_uri(field: &'static str, value: &str) -> Result<(), FhirError> {
+    let invalid = || FhirError::InvalidUri {
+        field,
+        value: value.to_string(),
+    };
+    let (scheme, rest) = value.split_once(':').ok_or_else(invalid)?;
+    let mut scheme_chars = scheme.chars();
+    let valid_scheme = scheme_chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && scheme_chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    if !valid_scheme || rest.is_empty() || value.chars().any(char::is_whitespace) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// A FHIR `code`: non-empty, no leading or trailing whitespace and no
+/// whitespace other than single spaces.
+fn check_code(code: &str) -> Result<(), FhirError> {
+    let valid = !code.is_empty()
+        && code.split(' ').all(|word| !word.is_empty())
+        && !code.chars().any(|c| c.is_whitespace() && c != ' ');
+    if !valid {
+        return Err(FhirError::InvalidCode(code.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks `code` against the UCUM case-sensitive grammar, e.g. `mm[Hg]`,
+/// `kg/m2`, `10*3/uL` or `mL/min/{1.73_m2}`.
+fn check_ucum(code: &str) -> Result<(), FhirError> {
+    let mut parser = UcumParser {
+        input: code.as_bytes(),
+        pos: 0,
+    };
+    if parser.main_term() && parser.pos == code.len() {
+        Ok(())
+    } else {
+        Err(FhirError::InvalidUcum(code.to_string()))
+    }
+}
+
+struct UcumParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl UcumParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    // mainTerm := "/" term | term
+    fn main_term(&mut self) -> bool {
+        self.eat(b'/');
+        self.term()
+    }
+
+    // term := component (("." | "/") component)*
+    fn term(&mut self) -> bool {
+        if !self.component() {
+            return false;
+        }
+        while self.eat(b'.') || self.eat(b'/') {
+            if !self.component() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // component := annotatable annotation? | annotation | "(" term ")"
+    fn component(&mut self) -> bool {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                self.term() && self.eat(b')')
+            }
+            Some(b'{') => self.annotation(),
+            _ => self.annotatable() && (self.peek() != Some(b'{') || self.annotation()),
+        }
+    }
+
+    fn annotation(&mut self) -> bool {
+        if !self.eat(b'{') {
+            return false;
+        }
+        while let Some(byte) = self.peek() {
+            self.pos += 1;
+            match byte {
+                b'}' => return true,
+                b'{' => return false,
+                33..=126 => {}
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    // annotatable := factor | simpleUnit exponent?
+    //
+    // Reads the whole run of unit characters, then splits off a trailing
+    // signed integer as the exponent. A run of digits alone is a factor.
+    fn annotatable(&mut self) -> bool {
+        let start = self.pos;
+        while let Some(byte) = self.peek() {
+            match byte {
+                b'[' => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            Some(b']') => break,
+                            Some(b'[') | None => return false,
+                            Some(33..=126) => self.pos += 1,
+                            Some(_) => return false,
+                        }
+                    }
+                    self.pos += 1;
+                }
+                b'.' | b'/' | b'(' | b')' | b'{' | b'}' | b']' => break,
+                33..=126 => self.pos += 1,
+                _ => return false,
+            }
+        }
+        let run = &self.input[start..self.pos];
+        if run.is_empty() {
+            return false;
+        }
+        if run.iter().all(u8::is_ascii_digit) {
+            return true;
+        }
+
+        let digits = run.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+        let mut symbol = &run[..run.len() - digits];
+        if digits > 0 && matches!(symbol.last(), Some(b'+' | b'-')) {
+            symbol = &symbol[..symbol.len() - 1];
+        }
+        let mut depth = 0;
+        let signs_outside_brackets = symbol.iter().any(|&b| {
+            match b {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                _ => {}
+            }
+            depth == 0 && matches!(b, b'+' | b'-')
+        });
+        !symbol.is_empty() && !signs_outside_brackets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn primitives_have_a_stable_json_shape() {
+        let id = Uuid::from_u128(1);
+        let concept = CodeableConcept {
+            coding: vec![Coding::new(system::LOINC, "8867-4").with_display("Heart rate")],
+            text: Some("Pulse".into()),
+        };
+        assert_eq!(
+            serde_json::to_value(&concept).unwrap(),
+            json!({
+                "coding": [{ "system": "http://loinc.org", "code": "8867-4", "display": "Heart rate" }],
+                "text": "Pulse",
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Reference::from(EntityRef {
+                entity_type: "Patient".into(),
+                entity_id: id,
+            }))
+            .unwrap(),
+            json!({ "type": "Patient", "id": id })
+        );
+        assert_eq!(
+            serde_json::to_value(Quantity::ucum(72.0, "/min")).unwrap(),
+            json!({ "value": 72.0, "unit": "/min", "system": "http://unitsofmeasure.org", "code": "/min" })
+        );
+
+        let identifier: Identifier =
+            serde_json::from_value(json!({ "system": "urn:oid:1.2.3", "value": "MRN-1" })).unwrap();
+        assert_eq!(identifier.validate(), Ok(()));
+        assert!(serde_json::from_value::<Coding>(
+            json!({ "system": system::SNOMED_CT, "code": "1", "version": "x" })
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn codings_and_concepts_are_validated() {
+        assert_eq!(Coding::new(system::ICD_10, "J45.909").validate(), Ok(()));
+        assert_eq!(Coding::new(system::SNOMED_CT, "a b").validate(), Ok(()));
+        for code in ["", " J45", "J45 ", "a  b", "a\tb"] {
+            assert_eq!(
+                Coding::new(system::ICD_10, code).validate(),
+                Err(FhirError::InvalidCode(code.into())),
+                "{code:?}"
+            );
+        }
+        for uri in ["", "loinc.org", "http:", "1http://x", "http://a b"] {
+            assert!(matches!(
+                Coding::new(uri, "1").validate(),
+                Err(FhirError::InvalidUri {
+                    field: "system",
+                    ..
+                })
+            ));
+        }
+        assert_eq!(
+            Coding::new(system::LOINC, "1").with_display(" ").validate(),
+            Err(FhirError::Blank { field: "display" })
+        );
+
+        let empty = CodeableConcept {
+            coding: vec![],
+            text: None,
+        };
+        assert_eq!(empty.validate(), Err(FhirError::EmptyConcept));
+        let text_only = CodeableConcept {
+            coding: vec![],
+            text: Some("Wheeze".into()),
+        };
+        assert_eq!(text_only.validate(), Ok(()));
+        let coded = CodeableConcept::from(Coding::new(system::RXNORM, "197361"));
+        assert!(coded.has_code(system::RXNORM, "197361"));
+        assert!(!coded.has_code(system::SNOMED_CT, "197361"));
+
+        let blank_value = Identifier {
+            system: "urn:oid:1.2.3".into(),
+            value: "  ".into(),
+        };
+        assert_eq!(
+            blank_value.validate(),
+            Err(FhirError::Blank { field: "value" })
+        );
+    }
+
+    #[test]
+    fn references_name_a_resource_type() {
+        let entity = EntityRef {
+            entity_type: "Patient".into(),
+            entity_id: Uuid::now_v7(),
+        };
+        let reference = Reference::from(entity.clone());
+        assert_eq!(reference.validate(), Ok(()));
+        assert!(reference.is(&entity));
+        assert_eq!(reference.literal(), format!("Patient/{}", entity.entity_id));
+
+        for resource_type in ["", "patient", "Clinical-Note"] {
+            let reference = Reference {
+                resource_type: resource_type.into(),
+                id: entity.entity_id,
+            };
+            assert!(reference.validate().is_err(), "{resource_type:?}");
+        }
+    }
+
+    #[test]
+    fn quantities_check_ucum_units() {
+        for code in [
+            "mm[Hg]",
+            "kg/m2",
+            "10*3/uL",
+            "10*-3",
+            "mL/min/{1.73_m2}",
+            "/min",
+            "%",
+            "Cel",
+            "[in_i]",
+            "mg/(24.h)",
+            "{score}",
+            "1",
+            "kg.m-2",
+        ] {
+            assert_eq!(Quantity::ucum(1.0, code).validate(), Ok(()), "{code:?}");
+        }
+        for code in [
+            "mm Hg", "kg//m2", "(kg", "mm[Hg", "{a", "kg{a}b}", "m-", "a+b2", "kg.",
+        ] {
+            assert_eq!(
+                Quantity::ucum(1.0, code).validate(),
+                Err(FhirError::InvalidUcum(code.into())),
+                "{code:?}"
+            );
+        }
+
+        assert_eq!(
+            Quantity::ucum(f64::NAN, "kg").validate(),
+            Err(FhirError::NonFiniteValue)
+        );
+        let uncoded = Quantity {
+            value: 2.0,
+            unit: Some("tablets".into()),
+            system: None,
+            code: None,
+        };
+        assert_eq!(uncoded.validate(), Ok(()));
+        let code_only = Quantity {
+            code: Some("TAB".into()),
+            ..uncoded.clone()
+        };
+        assert_eq!(code_only.validate(), Err(FhirError::CodeWithoutSystem));
+        let other_system = Quantity {
+            system: Some("http://snomed.info/sct".into()),
+            code: Some("428673006".into()),
+            ..uncoded
+        };
+        assert_eq!(other_system.validate(), Ok(()));
+        assert!(!other_system.is_ucum());
+    }
+}
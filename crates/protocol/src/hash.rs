@@ -0,0 +1,226 @@
+//! Canonical op encoding and content hashes.
+//!
+//! An op's hash is the SHA-256 of its canonical JSON: every envelope field
+//! except `hash` and `signature`, object keys sorted, no insignificant whitespace,
+//! `device_time` in UTC at microsecond precision and integral floats written
+//! as integers. The encoding survives a round trip through Postgres `JSONB`
+//! and `TIMESTAMPTZ`, so a stored op can be re-hashed during an audit.
+
+use serde_json::{json, Number, Value};
+use sha2::{Digest, Sha256};
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::encoding::hex_bytes;
+use crate::Operation;
+
+const DEVICE_TIME_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6]Z");
+
+/// Largest integer every JSON implementation represents exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+hex_bytes!(
+    /// SHA-256 of an op's canonical JSON. Hex-encoded on the wire.
+    OpHash,
+    32,
+    "op hash"
+);
+
+impl OpHash {
+    pub fn of(bytes: &[u8]) -> Self {
+        OpHash(Sha256::digest(bytes).into())
+    }
+}
+
+impl Operation {
+    /// The bytes [`Operation::compute_hash`] digests.
+    pub fn canonical_json(&self) -> Vec<u8> {
+        let device_time = self
+            .device_time
+            .to_offset(UtcOffset::UTC)
+            .format(DEVICE_TIME_FORMAT)
+            .expect("device_time is always formattable in UTC");
+        let mut envelope = json!({
+            "op_id": self.op_id,
+            "clinic_id": self.clinic_id,
+            "device_id": self.device_id,
+            "user_id": self.user_id,
+            "entity": {
+                "entity_type": self.entity.entity_type,
+                "entity_id": self.entity.entity_id,
+            },
+            "op_type": self.op_type,
+            "device_time": device_time,
+            "device_seq": self.device_seq,
+            "schema_version": self.schema_version,
+            "prev_hash": self.prev_hash,
+            "payload": self.payload,
+        });
+        // Omitted rather than null so ops hashed before HLCs keep their hash.
+        if let Some(hlc) = self.hlc {
+            envelope["hlc"] = json!(hlc);
+        }
+
+        let mut out = String::new();
+        write_canonical(&mut out, &envelope);
+        out.into_bytes()
+    }
+
+    pub fn compute_hash(&self) -> OpHash {
+        OpHash::of(&self.canonical_json())
+    }
+
+    /// Sets `hash` from the op's current contents. Call after every other
+    /// field, including `prev_hash`, is final.
+    pub fn seal(&mut self) {
+        self.hash = Some(self.compute_hash());
+    }
+
+    /// Whether `hash` is present and matches the op's contents.
+    pub fn verify_hash(&self) -> bool {
+        self.hash == Some(self.compute_hash())
+    }
+}
+
+/// Truncates `time` to the precision kept by the canonical encoding.
+pub fn truncate_device_time(time: OffsetDateTime) -> OffsetDateTime {
+    time.replace_nanosecond(time.nanosecond() / 1_000 * 1_000)
+        .expect("truncated nanoseconds are in range")
+}
+
+fn write_canonical(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => write_number(out, n),
+        Value::String(s) => write_string(out, s),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, key);
+                out.push(':');
+                write_canonical(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push_str(&serde_json::to_string(s).expect("strings always serialize"));
+}
+
+fn write_number(out: &mut String, n: &Number) {
+    if n.is_f64() {
+        let f = n.as_f64().expect("is_f64");
+        // `JSONB` forgets whether `2.0` was written as `2.0` or `2`.
+        if f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER {
+            out.push_str(&(f as i64).to_string());
+            return;
+        }
+    }
+    out.push_str(&n.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EntityRef;
+    use uuid::Uuid;
+
+    fn op() -> Operation {
+        Operation {
+            op_id: Uuid::now_v7(),
+            clinic_id: Uuid::now_v7(),
+            device_id: Uuid::now_v7(),
+            user_id: Uuid::now_v7(),
+            entity: EntityRef {
+                entity_type: "Patient".into(),
+                entity_id: Uuid::now_v7(),
+            },
+            op_type: "patient.registered".into(),
+            device_time: OffsetDateTime::now_utc(),
+            device_seq: 1,
+            hlc: None,
+            schema_version: 1,
+            payload: json!({ "family_name": "Okafor", "given_names": ["Ada"] }),
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn canonical_json_is_independent_of_representation() {
+        let mut a = op();
+        a.payload = json!({ "b": [1, 2.0, 0.5], "a": { "y": "é\n", "x": null } });
+        a.device_time = a
+            .device_time
+            .to_offset(UtcOffset::from_hms(5, 45, 0).unwrap());
+
+        let mut b = a.clone();
+        b.payload = serde_json::from_str(r#"{"a":{"x":null,"y":"é\n"},"b":[1.0,2,0.5]}"#).unwrap();
+        b.device_time = truncate_device_time(a.device_time).to_offset(UtcOffset::UTC);
+
+        assert_eq!(a.canonical_json(), b.canonical_json());
+        let text = String::from_utf8(a.canonical_json()).unwrap();
+        assert!(text.contains(r#""payload":{"a":{"x":null,"y":"é\n"},"b":[1,2,0.5]}"#));
+        assert!(text.ends_with(&format!(r#""user_id":"{}"}}"#, a.user_id)));
+    }
+
+    #[test]
+    fn sealing_covers_every_field_but_the_hash() {
+        let mut sealed = op();
+        sealed.seal();
+        assert!(sealed.verify_hash());
+
+        let mut tampered = sealed.clone();
+        tampered.payload["family_name"] = json!("Okafor-Smith");
+        assert!(!tampered.verify_hash());
+
+        let mut relinked = sealed.clone();
+        relinked.prev_hash = Some(OpHash([7; 32]));
+        assert!(!relinked.verify_hash());
+
+        let mut stamped = sealed.clone();
+        stamped.hlc = Some(crate::Hlc::zero(stamped.device_id));
+        assert!(!stamped.verify_hash());
+        assert!(String::from_utf8(stamped.canonical_json())
+            .unwrap()
+            .contains(r#""hlc":"000000000000000-0000000000-"#));
+
+        let mut unsealed = sealed.clone();
+        unsealed.hash = None;
+        assert!(!unsealed.verify_hash());
+    }
+
+    #[test]
+    fn op_hash_is_hex_on_the_wire() {
+        let hash = OpHash::of(b"abc");
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(
+            json,
+            "\"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\""
+        );
+        assert_eq!(serde_json::from_str::<OpHash>(&json).unwrap(), hash);
+        assert!(serde_json::from_str::<OpHash>("\"abcd\"").is_err());
+        assert!(OpHash::try_from(&[0u8; 31][..]).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Hybrid logical clocks.
+//!
+//! Every op is stamped with an [`Hlc`] by its authoring device. HLCs stay
+//! close to wall-clock time but, unlike `device_time`, never go backwards on a
+//! device and always move past any HLC the device has already seen, so
+//! ordering ops by HLC respects causality even when laptop clocks are skewed.
+//! Ties between devices are broken by node id, making the order total.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+use crate::DeviceId;
+
+/// How far ahead of the local clock a remote HLC may be before
+/// [`HlcClock::observe`] refuses to follow it.
+pub const MAX_CLOCK_DRIFT: Duration = Duration::hours(1);
+
+/// Widths of the zero-padded fields of the string form, which sorts in the
+/// same order as the clock.
+const WALL_DIGITS: usize = 15;
+const COUNTER_DIGITS: usize = 10;
+
+/// A hybrid logical clock timestamp.
+///
+/// Ordered by `(wall_ms, counter, node)`. On the wire it is the string
+/// `"<wall_ms>-<counter>-<node>"` with fixed-width decimal fields, which sorts
+/// lexicographically in the same order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hlc {
+    /// Milliseconds since the Unix epoch.
+    pub wall_ms: u64,
+    pub counter: u32,
+    /// The device that issued the timestamp.
+    pub node: DeviceId,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum HlcError {
+    #[error("invalid hlc: {0}")]
+    Invalid(String),
+    #[error("hlc from {node} is {ahead} ahead of the local clock")]
+    ClockDrift { node: DeviceId, ahead: Duration },
+}
+
+impl Hlc {
+    /// The earliest timestamp `node` can issue.
+    pub fn zero(node: DeviceId) -> Self {
+        Hlc {
+            wall_ms: 0,
+            counter: 0,
+            node,
+        }
+    }
+
+    pub fn wall_time(&self) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH + Duration::milliseconds(self.wall_ms as i64)
+    }
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:0wall$}-{:0counter$}-{}",
+            self.wall_ms,
+            self.counter,
+            self.node,
+            wall = WALL_DIGITS,
+            counter = COUNTER_DIGITS
+        )
+    }
+}
+
+impl FromStr for Hlc {
+    type Err = HlcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || HlcError::Invalid(s.to_string());
+        let mut parts = s.splitn(3, '-');
+        let (Some(wall), Some(counter), Some(node)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let digits = |field: &str, len: usize| {
+            field.len() == len && field.bytes().all(|b| b.is_ascii_digit())
+        };
+        if !digits(wall, WALL_DIGITS) || !digits(counter, COUNTER_DIGITS) {
+            return Err(invalid());
+        }
+        Ok(Hlc {
+            wall_ms: wall.parse().map_err(|_| invalid())?,
+            counter: counter.parse().map_err(|_| invalid())?,
+            node: node.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Serialize for Hlc {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hlc {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A device's clock. Persist [`HlcClock::last`] alongside the ops it stamped
+/// and [`HlcClock::resume`] from it after a restart.
+#[derive(Debug, Clone)]
+pub struct HlcClock {
+    last: Hlc,
+}
+
+impl HlcClock {
+    pub fn new(node: DeviceId) -> Self {
+        Self::resume(Hlc::zero(node))
+    }
+
+    pub fn resume(last: Hlc) -> Self {
+        HlcClock { last }
+    }
+
+    pub fn last(&self) -> Hlc {
+        self.last
+    }
+
+    /// Issues a timestamp for a local event at wall-clock time `now`.
+    pub fn tick(&mut self, now: OffsetDateTime) -> Hlc {
+        let physical = wall_ms(now);
+        self.last = if physical > self.last.wall_ms {
+            Hlc {
+                wall_ms: physical,
+                counter: 0,
+                node: self.last.node,
+            }
+        } else {
+            self.advance(self.last.wall_ms, self.last.counter)
+        };
+        self.last
+    }
+
+    /// Moves the clock past `remote`, an HLC received from another device,
+    /// and returns the new local timestamp.
+    ///
+    /// Refuses to follow a remote clock more than [`MAX_CLOCK_DRIFT`] ahead of
+    /// `now`, so one badly skewed laptop cannot drag every clock it syncs with
+    /// into the future.
+    pub fn observe(&mut self, remote: Hlc, now: OffsetDateTime) -> Result<Hlc, HlcError> {
+        let physical = wall_ms(now);
+        let ahead = Duration::milliseconds(remote.wall_ms.saturating_sub(physical) as i64);
+        if ahead > MAX_CLOCK_DRIFT {
+            return Err(HlcError::ClockDrift {
+                node: remote.node,
+                ahead,
+            });
+        }
+
+        let wall = physical.max(self.last.wall_ms).max(remote.wall_ms);
+        self.last = match (wall == self.last.wall_ms, wall == remote.wall_ms) {
+            (true, true) => self.advance(wall, self.last.counter.max(remote.counter)),
+            (true, false) => self.advance(wall, self.last.counter),
+            (false, true) => self.advance(wall, remote.counter),
+            (false, false) => Hlc {
+                wall_ms: wall,
+                counter: 0,
+                node: self.last.node,
+            },
+        };
+        Ok(self.last)
+    }
+
+    /// The next timestamp after `(wall_ms, counter)`.
+    fn advance(&self, wall_ms: u64, counter: u32) -> Hlc {
+        match counter.checked_add(1) {
+            Some(counter) => Hlc {
+                wall_ms,
+                counter,
+                node: self.last.node,
+            },
+            None => Hlc {
+                wall_ms: wall_ms + 1,
+                counter: 0,
+                node: self.last.node,
+            },
+        }
+    }
+}
+
+fn wall_ms(time: OffsetDateTime) -> u64 {
+    u64::try_from(time.unix_timestamp_nanos() / 1_000_000).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::hlc;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    fn at(ms: u64) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH + Duration::milliseconds(ms as i64)
+    }
+
+    #[test]
+    fn hlc_string_form_is_fixed_width() {
+        let node = Uuid::from_u128(1);
+        let hlc = Hlc {
+            wall_ms: 1_769_000_000_123,
+            counter: 7,
+            node,
+        };
+        let s = hlc.to_string();
+        assert_eq!(
+            s,
+            "001769000000123-0000000007-00000000-0000-0000-0000-000000000001"
+        );
+        assert_eq!(s.parse::<Hlc>(), Ok(hlc));
+        assert_eq!(hlc.wall_time(), at(1_769_000_000_123));
+
+        for bad in [
+            "",
+            "1-2-3",
+            "1769000000123-0000000007-x",
+            "001769000000123-7-00000000-0000-0000-0000-000000000001",
+        ] {
+            assert!(bad.parse::<Hlc>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn clock_never_goes_backwards_when_the_wall_clock_does() {
+        let mut clock = HlcClock::new(Uuid::now_v7());
+        let a = clock.tick(at(1_000));
+        let b = clock.tick(at(500));
+        let c = clock.tick(at(1_000));
+        assert!(a < b && b < c);
+        assert_eq!((c.wall_ms, c.counter), (1_000, 2));
+
+        let d = clock.tick(at(2_000));
+        assert_eq!((d.wall_ms, d.counter), (2_000, 0));
+    }
+
+    #[test]
+    fn counter_overflow_spills_into_the_wall_clock() {
+        let node = Uuid::now_v7();
+        let mut clock = HlcClock::resume(Hlc {
+            wall_ms: 1_000,
+            counter: u32::MAX,
+            node,
+        });
+        let next = clock.tick(at(1_000));
+        assert_eq!((next.wall_ms, next.counter), (1_001, 0));
+    }
+
+    #[test]
+    fn observe_refuses_clocks_too_far_ahead() {
+        let mut clock = HlcClock::new(Uuid::now_v7());
+        clock.tick(at(1_000));
+        let remote = Hlc {
+            wall_ms: 1_000 + MAX_CLOCK_DRIFT.whole_milliseconds() as u64 + 1,
+            counter: 0,
+            node: Uuid::now_v7(),
+        };
+        assert!(matches!(
+            clock.observe(remote, at(1_000)),
+            Err(HlcError::ClockDrift { .. })
+        ));
+        assert_eq!(clock.last().wall_ms, 1_000);
+    }
+
+    proptest! {
+        #[test]
+        fn string_order_matches_clock_order(a in hlc(), b in hlc()) {
+            prop_assert_eq!(a.to_string().cmp(&b.to_string()), a.cmp(&b));
+            prop_assert_eq!(a.to_string().parse::<Hlc>(), Ok(a));
+        }
+
+        #[test]
+        fn events_are_ordered_after_everything_they_saw(
+            local in hlc(),
+            remote in hlc(),
+            now in 0u64..1 << 45,
+        ) {
+            let mut clock = HlcClock::resume(local);
+            match clock.observe(remote, at(now)) {
+                Ok(observed) => {
+                    prop_assert!(observed > local);
+                    prop_assert!((observed.wall_ms, observed.counter) > (remote.wall_ms, remote.counter));
+                    prop_assert!(clock.tick(at(now)) > observed);
+                }
+                Err(_) => prop_assert_eq!(clock.last(), local),
+            }
+        }
+    }
+}
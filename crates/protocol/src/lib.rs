@@ -5,6 +5,32 @@ use thiserror::Error;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+pub mod domain;
+pub mod encoding;
+pub mod fhir;
+pub mod hash;
+pub mod hlc;
+pub mod merge;
+pub mod merkle;
+pub mod payload;
+pub mod projection;
+pub mod signing;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod wire;
+
+pub use hash::OpHash;
+pub use hlc::Hlc;
+use payload::{Payload, PayloadError};
+pub use signing::{DevicePublicKey, OpSignature};
+
+/// Version of the sync protocol: the envelope, its encoding and the sync
+/// endpoints. Bumped on any change an older peer would misread.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Request header in which clients send their [`PROTOCOL_VERSION`].
+pub const PROTOCOL_VERSION_HEADER: &str = "x-medxz-protocol-version";
+
 pub type ClinicId = Uuid;
 pub type DeviceId = Uuid;
 pub type UserId = Uuid;
@@ -27,16 +53,40 @@ pub struct Operation {
     pub op_type: String,
     pub device_time: OffsetDateTime,
     pub device_seq: u64,
+    /// Stamped by the authoring device's [`hlc::HlcClock`]; orders ops for
+    /// merging. Required on push, absent only on ops stored before HLCs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hlc: Option<Hlc>,
     pub schema_version: u32,
     pub payload: serde_json::Value,
+    /// Hash of the op with the previous `device_seq` from the same device;
+    /// `None` for a device's first op.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<OpHash>,
+    /// See [`Operation::seal`]. Required by the server on push; absent only on
+    /// ops stored before hashing was introduced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<OpHash>,
+    /// The authoring device's signature over `hash`; see [`Operation::sign`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<OpSignature>,
 }
 
-#[derive(Debug, Error, PartialEq, Eq)]
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum OperationValidationError {
     #[error("op_type must not be empty")]
     EmptyOpType,
     #[error("entity_type must not be empty")]
     EmptyEntityType,
+    #[error("device_seq must be between 1 and {}", i64::MAX)]
+    DeviceSeqOutOfRange,
+    #[error("schema_version must be between 1 and {}", i32::MAX)]
+    SchemaVersionOutOfRange,
+    #[error("hlc is required")]
+    MissingHlc,
+    #[error("hlc node must be the authoring device")]
+    HlcNodeMismatch,
 }
 
 impl Operation {
@@ -47,8 +97,28 @@ impl Operation {
         if self.entity.entity_type.trim().is_empty() {
             return Err(OperationValidationError::EmptyEntityType);
         }
+        // Both the server (Postgres) and devices (SQLite) store these as signed
+        // integers.
+        if self.device_seq == 0 || self.device_seq > i64::MAX as u64 {
+            return Err(OperationValidationError::DeviceSeqOutOfRange);
+        }
+        if self.schema_version == 0 || self.schema_version > i32::MAX as u32 {
+            return Err(OperationValidationError::SchemaVersionOutOfRange);
+        }
+        match self.hlc {
+            None => return Err(OperationValidationError::MissingHlc),
+            Some(hlc) if hlc.node != self.device_id => {
+                return Err(OperationValidationError::HlcNodeMismatch)
+            }
+            Some(_) => {}
+        }
         Ok(())
     }
+
+    /// Decodes `payload` through the standard [`payload::registry`].
+    pub fn decode_payload(&self) -> Result<Payload, PayloadError> {
+        payload::registry().decode(self)
+    }
 }
 
 /// A server-issued monotonic cursor for `/sync/pull`.
@@ -84,16 +154,214 @@ pub struct PushRequest {
     pub ops: Vec<Operation>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// The server's verdict on each op of a push, in request order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PushResponse {
-    pub accepted: u64,
-    pub duplicate: u64,
+    pub results: Vec<PushResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushResult {
+    pub op_id: OperationId,
+    pub outcome: PushOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PushOutcome {
+    /// The op was appended to the log at `cursor`.
+    Accepted { cursor: Cursor },
+    /// An op with the same `op_id` was already stored; safe to treat as acked.
+    Duplicate,
+    /// The op was not stored and will not be until the device fixes it.
+    Rejected {
+        reason: RejectReason,
+        message: String,
+    },
+}
+
+impl PushOutcome {
+    /// Whether the device can drop the op from its outbox.
+    pub fn is_acked(&self) -> bool {
+        matches!(self, PushOutcome::Accepted { .. } | PushOutcome::Duplicate)
+    }
+}
+
+/// Machine-readable cause of a [`PushOutcome::Rejected`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum RejectReason {
+    InvalidOperation {
+        error: OperationValidationError,
+    },
+    /// The op claims a clinic, user or device other than the pushing session's.
+    ProvenanceMismatch {
+        field: ProvenanceField,
+    },
+    /// The author's role may not write this kind of entity.
+    PermissionDenied,
+    /// Another organization already stored an op with this `op_id`.
+    OpIdTaken,
+    /// `hash` is missing or does not match the op's canonical encoding.
+    HashMismatch,
+    /// `prev_hash` does not link the op to its device's previous op, or the
+    /// previous op has not been received.
+    ChainBroken,
+    /// `signature` is missing or was not made with the pushing device's
+    /// registered key.
+    InvalidSignature,
+    /// The server does not know this `(entity_type, op_type, schema_version)`.
+    UnsupportedOperation,
+    /// The payload does not match the schema of its op type.
+    InvalidPayload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvenanceField {
+    ClinicId,
+    UserId,
+    DeviceId,
 }
 
+/// Response of `/sync/capabilities`, fetched by clients before syncing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    /// Clients older than this get `426 Upgrade Required` from the sync
+    /// endpoints.
+    pub min_protocol_version: u32,
+    /// Ops the server can decode, at their current schema version. Every
+    /// older schema version of a listed op is accepted too.
+    pub operations: Vec<SupportedOperation>,
+    pub limits: SyncLimits,
+    /// Content types sync bodies can be sent and received in. Empty from
+    /// servers that predate the field, which speak JSON only.
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// `Content-Encoding`s sync bodies can be sent and received in. Empty
+    /// from servers that predate the field, which compress nothing.
+    #[serde(default)]
+    pub content_encodings: Vec<String>,
+}
+
+impl Capabilities {
+    /// Whether the server will accept `op`'s op type at its schema version.
+    pub fn supports(&self, op: &Operation) -> bool {
+        self.operations.iter().any(|supported| {
+            supported.entity_type == op.entity.entity_type
+                && supported.op_type == op.op_type
+                && op.schema_version <= supported.schema_version
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupportedOperation {
+    pub entity_type: String,
+    pub op_type: String,
+    pub schema_version: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncLimits {
+    pub max_push_ops: u32,
+    pub default_pull_limit: u32,
+    pub max_pull_limit: u32,
+}
+
+/// Query parameters for `/sync/pull`.
+///
+/// Omitting `cursor` pulls from the beginning of the log; omitting `limit` uses
+/// the server default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PullRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<Cursor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+/// A page of ops in server order.
+///
+/// `next_cursor` is the position after the last op in `ops` (or the request
+/// cursor when the page is empty); a device persists it once the page has been
+/// applied. `has_more` is set when further ops were already available.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PullResponse {
     pub ops: Vec<Operation>,
     pub next_cursor: Option<Cursor>,
+    pub has_more: bool,
+    /// Revision of the pulling device's [`DeviceScope`]. When it differs from
+    /// the revision a device last pulled under, its scope changed: it drops
+    /// what the new scope leaves out and pulls again from the start (or from
+    /// a [`Snapshot`]) to backfill what the new scope adds.
+    #[serde(default)]
+    pub scope_revision: u64,
+}
+
+/// Ops requested by id, e.g. those a [`merkle`] comparison found missing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FetchRequest {
+    pub op_ids: Vec<OperationId>,
+}
+
+/// The requested ops the caller may see, in server order. Ids the server
+/// does not have, or will not show the caller, are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchResponse {
+    pub ops: Vec<Operation>,
+}
+
+/// The part of an organization's log a device replicates. A `None` field
+/// does not narrow the scope; the default scope is everything the user's
+/// role may read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncScope {
+    /// The device's patient panel. Ops are attributed to the patient named
+    /// by the op that created their entity (see
+    /// [`Payload::patient`](payload::Payload::patient)), so a clinical note
+    /// follows its patient like every other clinical entity; an op whose
+    /// entity's creating op is unknown is outside every panel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patients: Option<Vec<EntityId>>,
+    /// Entity types the device stores, e.g. everything but `ClinicalNote` on
+    /// a front-desk machine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entity_types: Option<Vec<String>>,
+}
+
+impl SyncScope {
+    pub fn includes_entity_type(&self, entity_type: &str) -> bool {
+        self.entity_types
+            .as_ref()
+            .is_none_or(|types| types.iter().any(|t| t == entity_type))
+    }
+
+    pub fn includes_patient(&self, patient: Option<EntityId>) -> bool {
+        self.patients
+            .as_ref()
+            .is_none_or(|panel| patient.is_some_and(|id| panel.contains(&id)))
+    }
+}
+
+/// A device's [`SyncScope`] and its revision, bumped on every change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceScope {
+    pub scope: SyncScope,
+    pub revision: u64,
+}
+
+/// The projected state of the log up to `cursor`, served by
+/// `/sync/snapshot` so that a new device can start from it and pull only the
+/// ops after `cursor` instead of the whole history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub cursor: Cursor,
+    /// Ops folded into `projection`.
+    pub op_count: u64,
+    pub created_at: OffsetDateTime,
+    pub projection: projection::Projection,
 }
 
 #[cfg(test)]
@@ -124,13 +392,165 @@ mod tests {
             op_type: "".into(),
             device_time: OffsetDateTime::now_utc(),
             device_seq: 1,
+            hlc: None,
             schema_version: 1,
             payload: serde_json::json!({}),
+            prev_hash: None,
+            hash: None,
+            signature: None,
         };
 
         assert_eq!(op.validate(), Err(OperationValidationError::EmptyOpType));
     }
 
+    #[test]
+    fn operation_validation_rejects_out_of_range_counters() {
+        let device_id = Uuid::now_v7();
+        let mut op = Operation {
+            op_id: Uuid::now_v7(),
+            clinic_id: Uuid::now_v7(),
+            device_id,
+            user_id: Uuid::now_v7(),
+            entity: EntityRef {
+                entity_type: "Patient".into(),
+                entity_id: Uuid::now_v7(),
+            },
+            op_type: "patient.registered".into(),
+            device_time: OffsetDateTime::now_utc(),
+            device_seq: i64::MAX as u64,
+            hlc: Some(Hlc::zero(device_id)),
+            schema_version: 1,
+            payload: serde_json::json!({}),
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        };
+        assert_eq!(op.validate(), Ok(()));
+
+        op.device_seq += 1;
+        assert_eq!(
+            op.validate(),
+            Err(OperationValidationError::DeviceSeqOutOfRange)
+        );
+
+        op.device_seq = 1;
+        op.schema_version = 0;
+        assert_eq!(
+            op.validate(),
+            Err(OperationValidationError::SchemaVersionOutOfRange)
+        );
+
+        op.schema_version = 1;
+        op.hlc = None;
+        assert_eq!(op.validate(), Err(OperationValidationError::MissingHlc));
+
+        op.hlc = Some(Hlc::zero(Uuid::now_v7()));
+        assert_eq!(
+            op.validate(),
+            Err(OperationValidationError::HlcNodeMismatch)
+        );
+    }
+
+    #[test]
+    fn capabilities_cover_older_schema_versions() {
+        let capabilities = Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: 1,
+            operations: payload::registry().supported_operations(),
+            limits: SyncLimits {
+                max_push_ops: 500,
+                default_pull_limit: 100,
+                max_pull_limit: 1000,
+            },
+            formats: vec![],
+            content_encodings: vec![],
+        };
+        let device_id = Uuid::now_v7();
+        let mut op = Operation {
+            op_id: Uuid::now_v7(),
+            clinic_id: Uuid::now_v7(),
+            device_id,
+            user_id: Uuid::now_v7(),
+            entity: EntityRef {
+                entity_type: "Patient".into(),
+                entity_id: Uuid::now_v7(),
+            },
+            op_type: "patient.registered".into(),
+            device_time: OffsetDateTime::now_utc(),
+            device_seq: 1,
+            hlc: Some(Hlc::zero(device_id)),
+            schema_version: 1,
+            payload: serde_json::json!({}),
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        };
+        assert!(capabilities.supports(&op));
+
+        op.schema_version = 99;
+        assert!(!capabilities.supports(&op));
+        op.schema_version = 1;
+        op.entity.entity_type = "ClinicalNote".into();
+        assert!(!capabilities.supports(&op));
+
+        let json = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(json["limits"]["max_push_ops"], 500);
+        assert!(json["operations"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!({
+                "entity_type": "Patient",
+                "op_type": "patient.registered",
+                "schema_version": 2,
+            })));
+    }
+
+    #[test]
+    fn push_response_json_shape() {
+        let accepted = Uuid::now_v7();
+        let rejected = Uuid::now_v7();
+        let response = PushResponse {
+            results: vec![
+                PushResult {
+                    op_id: accepted,
+                    outcome: PushOutcome::Accepted { cursor: Cursor(7) },
+                },
+                PushResult {
+                    op_id: rejected,
+                    outcome: PushOutcome::Rejected {
+                        reason: RejectReason::InvalidOperation {
+                            error: OperationValidationError::EmptyOpType,
+                        },
+                        message: "op_type must not be empty".into(),
+                    },
+                },
+            ],
+        };
+
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "results": [
+                    {
+                        "op_id": accepted,
+                        "outcome": { "status": "accepted", "cursor": "7" }
+                    },
+                    {
+                        "op_id": rejected,
+                        "outcome": {
+                            "status": "rejected",
+                            "reason": { "code": "invalid_operation", "error": "empty_op_type" },
+                            "message": "op_type must not be empty"
+                        }
+                    }
+                ]
+            })
+        );
+        let decoded: PushResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, response);
+    }
+
     proptest! {
         #[test]
         fn cursor_roundtrip_prop(n in any::<u64>()) {
@@ -0,0 +1,199 @@
+//! Merge policies and conflicts.
+//!
+//! Each entity type merges concurrent writes under one [`MergePolicy`].
+//! Clinical facts are append-only, so nothing is overwritten; routine fields
+//! take the last write by HLC; demographics, where a silent overwrite could
+//! attach one patient's history to another, surface a [`Conflict`] for a
+//! clinician to settle with a `conflict.resolved` op.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::projection::Stamp;
+use crate::{EntityRef, Hlc, OperationId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    /// Every op adds a record; none replaces another.
+    AppendOnly,
+    /// The write with the greatest HLC wins.
+    LastWriterWins,
+    /// Concurrent writes are kept side by side until someone picks one.
+    Manual,
+}
+
+/// The merge policy of every entity type in the domain.
+pub const MERGE_POLICIES: &[(&str, MergePolicy)] = &[
+    ("Patient", MergePolicy::Manual),
+    ("Encounter", MergePolicy::LastWriterWins),
+    ("Observation", MergePolicy::AppendOnly),
+    ("Condition", MergePolicy::LastWriterWins),
+    ("MedicationRequest", MergePolicy::LastWriterWins),
+    ("ClinicalNote", MergePolicy::AppendOnly),
+];
+
+pub fn merge_policy(entity_type: &str) -> Option<MergePolicy> {
+    MERGE_POLICIES
+        .iter()
+        .find(|(known, _)| *known == entity_type)
+        .map(|(_, policy)| *policy)
+}
+
+/// Concurrent writes to a manually merged field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub entity: EntityRef,
+    pub field: String,
+    /// Oldest first; the last is what the projection shows until resolved.
+    pub candidates: Vec<Candidate>,
+}
+
+impl Conflict {
+    /// The ops a `conflict.resolved` op must list in `resolves`.
+    pub fn op_ids(&self) -> Vec<OperationId> {
+        self.candidates.iter().map(|c| c.op_id).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub op_id: OperationId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hlc: Option<Hlc>,
+    /// In the field's own JSON shape.
+    pub value: Value,
+}
+
+/// A multi-value register: keeps every write no other write was based on.
+///
+/// A write names the ops it supersedes. Writes that nothing supersedes are
+/// the register's heads; more than one head is a conflict. The heads depend
+/// only on the set of writes, not on the order they arrive in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>"))]
+pub struct Mvr<T> {
+    #[serde(with = "crate::projection::by_stamp")]
+    heads: BTreeMap<Stamp, T>,
+    superseded: BTreeSet<OperationId>,
+}
+
+impl<T> Default for Mvr<T> {
+    fn default() -> Self {
+        Mvr {
+            heads: BTreeMap::new(),
+            superseded: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T> Mvr<T> {
+    /// The latest head, shown while a conflict is open.
+    pub fn get(&self) -> Option<&T> {
+        self.heads.values().next_back()
+    }
+
+    /// The current heads, oldest first.
+    pub fn heads(&self) -> impl Iterator<Item = (&Stamp, &T)> {
+        self.heads.iter()
+    }
+
+    /// The op ids a write that replaces the current value must be based on.
+    pub fn head_ids(&self) -> Vec<OperationId> {
+        self.heads.keys().map(|stamp| stamp.op_id).collect()
+    }
+
+    pub fn is_conflicted(&self) -> bool {
+        self.heads.len() > 1
+    }
+
+    pub fn write(&mut self, value: T, stamp: Stamp, based_on: &[OperationId]) {
+        // Record what the write supersedes even if it is itself superseded,
+        // so the heads stay a function of the set of writes.
+        self.superseded.extend(based_on.iter().copied());
+        let superseded = &self.superseded;
+        self.heads
+            .retain(|stamp, _| !superseded.contains(&stamp.op_id));
+        if !superseded.contains(&stamp.op_id) {
+            self.heads.insert(stamp, value);
+        }
+    }
+
+    /// The open conflict on this register, if any.
+    pub fn conflict(
+        &self,
+        entity: &EntityRef,
+        field: &str,
+        to_json: impl Fn(&T) -> Value,
+    ) -> Option<Conflict> {
+        self.is_conflicted().then(|| Conflict {
+            entity: entity.clone(),
+            field: field.to_string(),
+            candidates: self
+                .heads
+                .iter()
+                .map(|(stamp, value)| Candidate {
+                    op_id: stamp.op_id,
+                    hlc: stamp.hlc,
+                    value: to_json(value),
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn stamp(n: u128) -> Stamp {
+        Stamp {
+            hlc: None,
+            op_id: Uuid::from_u128(n),
+        }
+    }
+
+    #[test]
+    fn every_domain_entity_has_a_policy() {
+        for (entity_type, _, _) in crate::payload::registry().operations() {
+            assert!(merge_policy(entity_type).is_some(), "{entity_type}");
+        }
+        assert_eq!(merge_policy("Patient"), Some(MergePolicy::Manual));
+        assert_eq!(merge_policy("Widget"), None);
+    }
+
+    #[test]
+    fn concurrent_writes_conflict_until_one_supersedes_both() {
+        let entity = EntityRef {
+            entity_type: "Patient".into(),
+            entity_id: Uuid::from_u128(9),
+        };
+        let mut register = Mvr::default();
+        register.write("Okafor", stamp(1), &[]);
+        register.write("Okafor-Smith", stamp(2), &[stamp(1).op_id]);
+        assert!(!register.is_conflicted());
+
+        register.write("Okafor-Jones", stamp(3), &[stamp(1).op_id]);
+        assert!(register.is_conflicted());
+        assert_eq!(register.get(), Some(&"Okafor-Jones"));
+        let conflict = register
+            .conflict(&entity, "family_name", |name| json!(name))
+            .unwrap();
+        assert_eq!(conflict.op_ids(), register.head_ids());
+        assert_eq!(conflict.candidates[0].value, json!("Okafor-Smith"));
+
+        register.write("Okafor-Smith", stamp(4), &conflict.op_ids());
+        assert!(register
+            .conflict(&entity, "family_name", |name| json!(name))
+            .is_none());
+        assert_eq!(register.get(), Some(&"Okafor-Smith"));
+
+        // A superseded write arriving late stays superseded.
+        register.write("Okafor-Jones", stamp(3), &[]);
+        assert_eq!(register.head_ids(), vec![stamp(4).op_id]);
+    }
+}
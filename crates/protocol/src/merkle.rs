@@ -0,0 +1,395 @@
+//! Merkle summaries of the op log, for anti-entropy.
+//!
+//! Pulling by cursor trusts the device to have kept everything it pulled; a
+//! device restored from an old backup has not, and its cursor cannot tell.
+//! Instead both sides hash the ids of the ops they hold into a tree of time
+//! buckets: each leaf holds an hour of ops by HLC wall time and each inner
+//! node covers [`FANOUT`] children. Comparing the children of nodes whose
+//! digests differ narrows a divergence to the buckets that hold it, so a
+//! device finds its missing and extra ops in a few round trips instead of
+//! downloading the log again.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use time::OffsetDateTime;
+
+use crate::encoding::hex_bytes;
+use crate::{Hlc, Operation, OperationId};
+
+/// Width of a leaf bucket.
+pub const BUCKET_MS: u64 = 60 * 60 * 1000;
+
+/// Children per inner node.
+pub const FANOUT: u64 = 16;
+
+/// Level of the root. It covers `FANOUT^DEPTH` buckets from the Unix epoch,
+/// about 1900 years; later ops share the last bucket.
+pub const DEPTH: u32 = 6;
+
+hex_bytes!(
+    /// SHA-256 summary of a node's ops. Hex-encoded on the wire.
+    MerkleHash,
+    32,
+    "merkle hash"
+);
+
+/// A node of the tree: `index` counts nodes of its `level` from the epoch,
+/// level 0 being the leaf buckets.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct NodeId {
+    pub level: u32,
+    pub index: u64,
+}
+
+impl NodeId {
+    pub const ROOT: NodeId = NodeId {
+        level: DEPTH,
+        index: 0,
+    };
+
+    /// Whether the node lies inside the tree.
+    pub fn is_valid(self) -> bool {
+        self.level <= DEPTH && self.index < FANOUT.pow(DEPTH - self.level)
+    }
+
+    /// The leaf buckets under the node.
+    pub fn buckets(self) -> Range<u64> {
+        let span = FANOUT.pow(self.level);
+        self.index * span..(self.index + 1) * span
+    }
+
+    /// The wall-clock milliseconds the node covers: from the start, up to
+    /// the end unless the node reaches the last bucket, which is open-ended.
+    pub fn wall_ms(self) -> (u64, Option<u64>) {
+        let buckets = self.buckets();
+        let end = (buckets.end < FANOUT.pow(DEPTH)).then(|| buckets.end * BUCKET_MS);
+        (buckets.start * BUCKET_MS, end)
+    }
+
+    pub fn child(self, index: u64) -> NodeId {
+        NodeId {
+            level: self.level - 1,
+            index,
+        }
+    }
+}
+
+/// The leaf bucket of an op: its HLC wall time, or `device_time` for ops
+/// stored before HLCs.
+pub fn bucket_of(hlc: Option<&Hlc>, device_time: OffsetDateTime) -> u64 {
+    let wall_ms = match hlc {
+        Some(hlc) => hlc.wall_ms,
+        None => u64::try_from(device_time.unix_timestamp_nanos() / 1_000_000).unwrap_or(0),
+    };
+    (wall_ms / BUCKET_MS).min(FANOUT.pow(DEPTH) - 1)
+}
+
+/// Number and hash of the ops under a node. Equal digests mean, up to a hash
+/// collision, equal sets of op ids.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub count: u64,
+    pub hash: MerkleHash,
+}
+
+impl Digest {
+    pub const EMPTY: Digest = Digest {
+        count: 0,
+        hash: MerkleHash([0; 32]),
+    };
+}
+
+/// A node as served by `/sync/merkle`: its digest, the digests of its
+/// non-empty children and, for a leaf, its op ids.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleNode {
+    pub node: NodeId,
+    pub digest: Digest,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<MerkleChild>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub op_ids: Vec<OperationId>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleChild {
+    pub index: u64,
+    pub digest: Digest,
+}
+
+/// Where a local node differs from the server's.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Divergence {
+    /// Children to fetch from the server and compare next.
+    pub descend: Vec<NodeId>,
+    /// Ops the server has and the device lacks; fetch them.
+    pub missing: Vec<OperationId>,
+    /// Ops the device has and the server lacks; push them.
+    pub extra: Vec<OperationId>,
+}
+
+impl Divergence {
+    pub fn is_empty(&self) -> bool {
+        self.descend.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// The op ids one side holds, by leaf bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerkleTree {
+    leaves: BTreeMap<u64, BTreeSet<OperationId>>,
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_ops<'a>(ops: impl IntoIterator<Item = &'a Operation>) -> Self {
+        let mut tree = Self::new();
+        for op in ops {
+            tree.insert(op.op_id, bucket_of(op.hlc.as_ref(), op.device_time));
+        }
+        tree
+    }
+
+    pub fn insert(&mut self, op_id: OperationId, bucket: u64) {
+        self.leaves.entry(bucket).or_default().insert(op_id);
+    }
+
+    pub fn digest(&self, node: NodeId) -> Digest {
+        if node.level == 0 {
+            let Some(ids) = self.leaves.get(&node.index) else {
+                return Digest::EMPTY;
+            };
+            let mut hasher = Sha256::new();
+            for id in ids {
+                hasher.update(id.as_bytes());
+            }
+            return Digest {
+                count: ids.len() as u64,
+                hash: MerkleHash(hasher.finalize().into()),
+            };
+        }
+        let children = self.children(node);
+        if children.is_empty() {
+            return Digest::EMPTY;
+        }
+        let mut hasher = Sha256::new();
+        let mut count = 0;
+        for child in &children {
+            hasher.update(child.index.to_be_bytes());
+            hasher.update(child.digest.count.to_be_bytes());
+            hasher.update(child.digest.hash.0);
+            count += child.digest.count;
+        }
+        Digest {
+            count,
+            hash: MerkleHash(hasher.finalize().into()),
+        }
+    }
+
+    /// The digests of the node's non-empty children, in index order.
+    pub fn children(&self, node: NodeId) -> Vec<MerkleChild> {
+        if node.level == 0 {
+            return Vec::new();
+        }
+        let span = FANOUT.pow(node.level - 1);
+        let mut indexes: Vec<u64> = self
+            .leaves
+            .range(node.buckets())
+            .map(|(bucket, _)| bucket / span)
+            .collect();
+        indexes.dedup();
+        indexes
+            .into_iter()
+            .map(|index| MerkleChild {
+                index,
+                digest: self.digest(node.child(index)),
+            })
+            .collect()
+    }
+
+    /// The node as the server serves it.
+    pub fn node(&self, node: NodeId) -> MerkleNode {
+        let op_ids = match (node.level, self.leaves.get(&node.index)) {
+            (0, Some(ids)) => ids.iter().copied().collect(),
+            _ => Vec::new(),
+        };
+        MerkleNode {
+            node,
+            digest: self.digest(node),
+            children: self.children(node),
+            op_ids,
+        }
+    }
+
+    /// Compares the local tree with the server's copy of one node.
+    pub fn compare(&self, remote: &MerkleNode) -> Divergence {
+        let node = remote.node;
+        let mut divergence = Divergence::default();
+        if self.digest(node) == remote.digest {
+            return divergence;
+        }
+        if node.level == 0 {
+            let local = self.leaves.get(&node.index).cloned().unwrap_or_default();
+            let theirs: BTreeSet<OperationId> = remote.op_ids.iter().copied().collect();
+            divergence.missing = theirs.difference(&local).copied().collect();
+            divergence.extra = local.difference(&theirs).copied().collect();
+            return divergence;
+        }
+        let local: BTreeMap<u64, Digest> = self
+            .children(node)
+            .into_iter()
+            .map(|child| (child.index, child.digest))
+            .collect();
+        let theirs: BTreeMap<u64, Digest> = remote
+            .children
+            .iter()
+            .map(|child| (child.index, child.digest))
+            .collect();
+        let indexes: BTreeSet<u64> = local.keys().chain(theirs.keys()).copied().collect();
+        divergence.descend = indexes
+            .into_iter()
+            .filter(|index| local.get(index) != theirs.get(index))
+            .map(|index| node.child(index))
+            .collect();
+        divergence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    const HOUR: u64 = BUCKET_MS;
+
+    fn tree(ops: &[(u128, u64)]) -> MerkleTree {
+        let mut tree = MerkleTree::new();
+        for (id, wall_ms) in ops {
+            tree.insert(Uuid::from_u128(*id), *wall_ms / BUCKET_MS);
+        }
+        tree
+    }
+
+    /// Walks the server's tree from the root like a device would, returning
+    /// what it finds and how many nodes it fetched.
+    fn reconcile(local: &MerkleTree, server: &MerkleTree) -> (Divergence, usize) {
+        let mut found = Divergence::default();
+        let mut queue = vec![NodeId::ROOT];
+        let mut fetched = 0;
+        while let Some(node) = queue.pop() {
+            fetched += 1;
+            let step = local.compare(&server.node(node));
+            queue.extend(step.descend);
+            found.missing.extend(step.missing);
+            found.extra.extend(step.extra);
+        }
+        found.missing.sort();
+        found.extra.sort();
+        (found, fetched)
+    }
+
+    #[test]
+    fn node_ranges_tile_the_tree() {
+        assert_eq!(NodeId::ROOT.buckets(), 0..FANOUT.pow(DEPTH));
+        assert_eq!(NodeId::ROOT.wall_ms(), (0, None));
+        let day = NodeId { level: 1, index: 2 };
+        assert_eq!(day.buckets(), 32..48);
+        assert_eq!(day.wall_ms(), (32 * HOUR, Some(48 * HOUR)));
+        assert!(day.is_valid());
+        assert!(!NodeId {
+            level: DEPTH,
+            index: 1
+        }
+        .is_valid());
+        assert!(!NodeId {
+            level: DEPTH + 1,
+            index: 0
+        }
+        .is_valid());
+
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        assert_eq!(bucket_of(None, now), 1_700_000_000_000 / HOUR);
+        assert_eq!(
+            bucket_of(None, OffsetDateTime::UNIX_EPOCH - time::Duration::DAY),
+            0
+        );
+        let far = Hlc {
+            wall_ms: u64::MAX / 2,
+            counter: 0,
+            node: Uuid::nil(),
+        };
+        assert_eq!(bucket_of(Some(&far), now), FANOUT.pow(DEPTH) - 1);
+    }
+
+    #[test]
+    fn equal_sets_have_equal_digests() {
+        let ops = [(1, 5 * HOUR), (2, 5 * HOUR + 1), (3, 900 * HOUR)];
+        let a = tree(&ops);
+        let mut reversed = ops;
+        reversed.reverse();
+        let b = tree(&reversed);
+        assert_eq!(a.digest(NodeId::ROOT), b.digest(NodeId::ROOT));
+        assert_eq!(a.digest(NodeId::ROOT).count, 3);
+        assert_eq!(MerkleTree::new().digest(NodeId::ROOT), Digest::EMPTY);
+        assert!(a.compare(&b.node(NodeId::ROOT)).is_empty());
+
+        let c = tree(&[(1, 5 * HOUR), (4, 5 * HOUR + 1), (3, 900 * HOUR)]);
+        assert_ne!(a.digest(NodeId::ROOT), c.digest(NodeId::ROOT));
+    }
+
+    #[test]
+    fn finds_missing_and_extra_ops_without_visiting_equal_branches() {
+        let shared: Vec<(u128, u64)> = (0..200).map(|i| (i, i as u64 * 7 * HOUR)).collect();
+        let server = tree(&[shared.as_slice(), &[(1000, 3 * HOUR)]].concat());
+        let device = tree(&[shared.as_slice(), &[(2000, 1200 * HOUR)]].concat());
+
+        let (found, fetched) = reconcile(&device, &server);
+        assert_eq!(found.missing, [Uuid::from_u128(1000)]);
+        assert_eq!(found.extra, [Uuid::from_u128(2000)]);
+        // Two root-to-leaf paths, sharing the root and maybe more.
+        assert!(fetched <= 2 * (DEPTH as usize + 1), "{fetched}");
+    }
+
+    #[test]
+    fn merkle_nodes_round_trip_through_json() {
+        let node = tree(&[(1, HOUR), (2, 2 * HOUR)]).node(NodeId { level: 0, index: 1 });
+        assert_eq!(node.op_ids, [Uuid::from_u128(1)]);
+        let json = serde_json::to_value(&node).unwrap();
+        assert!(json.get("children").is_none());
+        assert_eq!(serde_json::from_value::<MerkleNode>(json).unwrap(), node);
+    }
+
+    proptest! {
+        #[test]
+        fn reconciling_finds_exactly_the_symmetric_difference(
+            ops in prop::collection::btree_map(0u128..500, 0u64..5000, 0..60),
+            on_server in prop::collection::vec(any::<bool>(), 60),
+            on_device in prop::collection::vec(any::<bool>(), 60),
+        ) {
+            let ops: Vec<(u128, u64)> = ops.into_iter().map(|(id, h)| (id, h * HOUR)).collect();
+            let pick = |keep: &[bool]| -> Vec<(u128, u64)> {
+                ops.iter().zip(keep).filter(|(_, k)| **k).map(|(op, _)| *op).collect()
+            };
+            let server_ops = pick(&on_server);
+            let device_ops = pick(&on_device);
+            let ids = |ops: &[(u128, u64)]| -> BTreeSet<OperationId> {
+                ops.iter().map(|(id, _)| Uuid::from_u128(*id)).collect()
+            };
+
+            let (found, _) = reconcile(&tree(&device_ops), &tree(&server_ops));
+            let expected_missing: Vec<_> =
+                ids(&server_ops).difference(&ids(&device_ops)).copied().collect();
+            let expected_extra: Vec<_> =
+                ids(&device_ops).difference(&ids(&server_ops)).copied().collect();
+            prop_assert_eq!(found.missing, expected_missing);
+            prop_assert_eq!(found.extra, expected_extra);
+        }
+    }
+}
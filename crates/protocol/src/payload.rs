@@ -0,0 +1,544 @@
+//! Typed op payloads.
+//!
+//! `Operation::payload` travels as JSON tagged with `(entity_type, op_type,
+//! schema_version)`. The [`PayloadRegistry`] maps that triple to a Rust type
+//! implementing [`OpPayload`], upcasting payloads written against an older
+//! `schema_version` to the current one before decoding them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::domain::{
+    ConditionAdded, ConditionResolved, ConflictResolved, DomainError, EncounterClosed,
+    EncounterOpened, MedicationDiscontinued, MedicationOrdered, NoteRevisionCreated,
+    ObservationEnteredInError, ObservationRecorded, PatientDemographicsChanged, PatientRegistered,
+};
+use crate::{EntityId, Operation, SupportedOperation};
+
+/// A typed op payload at its current schema version.
+pub trait OpPayload: Serialize + DeserializeOwned {
+    const ENTITY_TYPE: &'static str;
+    const OP_TYPE: &'static str;
+    const SCHEMA_VERSION: u32;
+
+    /// Rules the payload must satisfy beyond its shape. Checked on every
+    /// decode, so an invalid payload is rejected like a malformed one.
+    fn validate(&self) -> Result<(), DomainError> {
+        Ok(())
+    }
+}
+
+/// Migrates a payload from schema version `n` to `n + 1`.
+pub type Upcaster = fn(Value) -> Result<Value, String>;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum PayloadError {
+    #[error("unknown operation {op_type} on {entity_type}")]
+    UnknownOperation {
+        entity_type: String,
+        op_type: String,
+    },
+    #[error(
+        "{op_type} schema_version {schema_version} is newer than the supported version {current}"
+    )]
+    UnsupportedSchemaVersion {
+        op_type: String,
+        schema_version: u32,
+        current: u32,
+    },
+    #[error("{op_type} has no upcaster from schema_version {from}")]
+    MissingUpcaster { op_type: String, from: u32 },
+    #[error("failed to upcast {op_type} from schema_version {from}: {message}")]
+    Upcast {
+        op_type: String,
+        from: u32,
+        message: String,
+    },
+    #[error("invalid {op_type} payload: {message}")]
+    Invalid { op_type: String, message: String },
+}
+
+impl PayloadError {
+    /// Whether the op cannot be understood at all by this build, as opposed to
+    /// being understood and malformed.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            PayloadError::UnknownOperation { .. }
+                | PayloadError::UnsupportedSchemaVersion { .. }
+                | PayloadError::MissingUpcaster { .. }
+        )
+    }
+}
+
+struct Entry {
+    current: u32,
+    /// Keyed by the version each upcaster migrates from.
+    upcasters: BTreeMap<u32, Upcaster>,
+}
+
+#[derive(Default)]
+pub struct PayloadRegistry {
+    entries: HashMap<(&'static str, &'static str), Entry>,
+}
+
+impl PayloadRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `P` as the current shape of its `(entity_type, op_type)`.
+    pub fn register<P: OpPayload>(&mut self) -> &mut Self {
+        let previous = self.entries.insert(
+            (P::ENTITY_TYPE, P::OP_TYPE),
+            Entry {
+                current: P::SCHEMA_VERSION,
+                upcasters: BTreeMap::new(),
+            },
+        );
+        assert!(previous.is_none(), "{} registered twice", P::OP_TYPE);
+        self
+    }
+
+    /// Registers the migration of `P` payloads from `from` to `from + 1`.
+    ///
+    /// # Panics
+    ///
+    /// If `P` has not been registered or `from` is not an older version.
+    pub fn upcaster<P: OpPayload>(&mut self, from: u32, upcaster: Upcaster) -> &mut Self {
+        let entry = self
+            .entries
+            .get_mut(&(P::ENTITY_TYPE, P::OP_TYPE))
+            .unwrap_or_else(|| panic!("{} is not registered", P::OP_TYPE));
+        assert!(
+            from >= 1 && from < entry.current,
+            "{} upcaster from {from} does not target an older version",
+            P::OP_TYPE
+        );
+        entry.upcasters.insert(from, upcaster);
+        self
+    }
+
+    /// The current schema version of an op type, if it is known.
+    pub fn current_version(&self, entity_type: &str, op_type: &str) -> Option<u32> {
+        self.entries
+            .get(&(entity_type, op_type))
+            .map(|entry| entry.current)
+    }
+
+    /// Every registered `(entity_type, op_type, current schema_version)`,
+    /// sorted.
+    pub fn operations(&self) -> Vec<(&'static str, &'static str, u32)> {
+        let mut operations: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(&(entity_type, op_type), entry)| (entity_type, op_type, entry.current))
+            .collect();
+        operations.sort_unstable();
+        operations
+    }
+
+    /// [`PayloadRegistry::operations`] as advertised in
+    /// [`Capabilities`](crate::Capabilities).
+    pub fn supported_operations(&self) -> Vec<SupportedOperation> {
+        self.operations()
+            .into_iter()
+            .map(
+                |(entity_type, op_type, schema_version)| SupportedOperation {
+                    entity_type: entity_type.to_string(),
+                    op_type: op_type.to_string(),
+                    schema_version,
+                },
+            )
+            .collect()
+    }
+
+    /// Migrates `payload` from `schema_version` to the current version of its
+    /// op type.
+    pub fn upcast(
+        &self,
+        entity_type: &str,
+        op_type: &str,
+        schema_version: u32,
+        mut payload: Value,
+    ) -> Result<Value, PayloadError> {
+        let entry = self.entries.get(&(entity_type, op_type)).ok_or_else(|| {
+            PayloadError::UnknownOperation {
+                entity_type: entity_type.to_string(),
+                op_type: op_type.to_string(),
+            }
+        })?;
+        if schema_version > entry.current {
+            return Err(PayloadError::UnsupportedSchemaVersion {
+                op_type: op_type.to_string(),
+                schema_version,
+                current: entry.current,
+            });
+        }
+        for from in schema_version..entry.current {
+            let upcaster =
+                entry
+                    .upcasters
+                    .get(&from)
+                    .ok_or_else(|| PayloadError::MissingUpcaster {
+                        op_type: op_type.to_string(),
+                        from,
+                    })?;
+            payload = upcaster(payload).map_err(|message| PayloadError::Upcast {
+                op_type: op_type.to_string(),
+                from,
+                message,
+            })?;
+        }
+        Ok(payload)
+    }
+
+    /// Decodes the payload of `op` into `P`, upcasting it first if needed.
+    pub fn decode_as<P: OpPayload>(&self, op: &Operation) -> Result<P, PayloadError> {
+        if op.entity.entity_type != P::ENTITY_TYPE || op.op_type != P::OP_TYPE {
+            return Err(PayloadError::UnknownOperation {
+                entity_type: op.entity.entity_type.clone(),
+                op_type: op.op_type.clone(),
+            });
+        }
+        let value = self.upcast(
+            &op.entity.entity_type,
+            &op.op_type,
+            op.schema_version,
+            op.payload.clone(),
+        )?;
+        decode_current(&op.op_type, value)
+    }
+
+    /// Decodes the payload of `op` into whichever known [`Payload`] its op type
+    /// names.
+    pub fn decode(&self, op: &Operation) -> Result<Payload, PayloadError> {
+        let value = self.upcast(
+            &op.entity.entity_type,
+            &op.op_type,
+            op.schema_version,
+            op.payload.clone(),
+        )?;
+        Payload::from_current(&op.entity.entity_type, &op.op_type, value).unwrap_or_else(|| {
+            Err(PayloadError::UnknownOperation {
+                entity_type: op.entity.entity_type.clone(),
+                op_type: op.op_type.clone(),
+            })
+        })
+    }
+}
+
+/// The registry of every payload this build of the protocol understands.
+pub fn registry() -> &'static PayloadRegistry {
+    static REGISTRY: OnceLock<PayloadRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = PayloadRegistry::new();
+        Payload::register_all(&mut registry);
+        registry
+            .upcaster::<PatientRegistered>(1, PatientRegistered::upcast_v1)
+            .upcaster::<PatientDemographicsChanged>(1, PatientDemographicsChanged::upcast_v1);
+        registry
+    })
+}
+
+/// Deserializes and validates a payload already at its current version.
+fn decode_current<P: OpPayload>(op_type: &str, value: Value) -> Result<P, PayloadError> {
+    let invalid = |message: String| PayloadError::Invalid {
+        op_type: op_type.to_string(),
+        message,
+    };
+    let payload: P = serde_json::from_value(value).map_err(|err| invalid(err.to_string()))?;
+    payload.validate().map_err(|err| invalid(err.to_string()))?;
+    Ok(payload)
+}
+
+macro_rules! payloads {
+    ($($variant:ident),* $(,)?) => {
+        /// Any known op payload, at its current schema version.
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Payload {
+            $($variant($variant),)*
+        }
+
+        impl Payload {
+            pub fn entity_type(&self) -> &'static str {
+                match self {
+                    $(Payload::$variant(_) => $variant::ENTITY_TYPE,)*
+                }
+            }
+
+            pub fn op_type(&self) -> &'static str {
+                match self {
+                    $(Payload::$variant(_) => $variant::OP_TYPE,)*
+                }
+            }
+
+            pub fn schema_version(&self) -> u32 {
+                match self {
+                    $(Payload::$variant(_) => $variant::SCHEMA_VERSION,)*
+                }
+            }
+
+            /// The payload as it travels in [`Operation::payload`].
+            pub fn to_json(&self) -> Value {
+                let value = match self {
+                    $(Payload::$variant(payload) => serde_json::to_value(payload),)*
+                };
+                value.expect("payloads serialize to JSON")
+            }
+
+            fn register_all(registry: &mut PayloadRegistry) {
+                $(registry.register::<$variant>();)*
+            }
+
+            fn from_current(
+                entity_type: &str,
+                op_type: &str,
+                value: Value,
+            ) -> Option<Result<Self, PayloadError>> {
+                $(
+                    if entity_type == $variant::ENTITY_TYPE && op_type == $variant::OP_TYPE {
+                        return Some(
+                            decode_current::<$variant>(op_type, value).map(Payload::$variant),
+                        );
+                    }
+                )*
+                None
+            }
+        }
+
+        $(
+            impl From<$variant> for Payload {
+                fn from(payload: $variant) -> Self {
+                    Payload::$variant(payload)
+                }
+            }
+        )*
+    };
+}
+
+payloads!(
+    PatientRegistered,
+    PatientDemographicsChanged,
+    ConflictResolved,
+    EncounterOpened,
+    EncounterClosed,
+    ObservationRecorded,
+    ObservationEnteredInError,
+    ConditionAdded,
+    ConditionResolved,
+    MedicationOrdered,
+    MedicationDiscontinued,
+    NoteRevisionCreated,
+);
+
+impl Payload {
+    /// The patient the op's entity belongs to. Ops creating an entity name
+    /// its patient; later ops on it (closing an encounter, say) do not, and
+    /// belong to whichever patient created it. Every note revision names its
+    /// patient.
+    pub fn patient(&self, entity_id: EntityId) -> Option<EntityId> {
+        match self {
+            Payload::PatientRegistered(_)
+            | Payload::PatientDemographicsChanged(_)
+            | Payload::ConflictResolved(_) => Some(entity_id),
+            Payload::EncounterOpened(opened) => Some(opened.patient.id),
+            Payload::ObservationRecorded(recorded) => Some(recorded.patient.id),
+            Payload::ConditionAdded(added) => Some(added.patient.id),
+            Payload::MedicationOrdered(ordered) => Some(ordered.patient.id),
+            Payload::NoteRevisionCreated(revision) => Some(revision.patient.id),
+            Payload::EncounterClosed(_)
+            | Payload::ObservationEnteredInError(_)
+            | Payload::ConditionResolved(_)
+            | Payload::MedicationDiscontinued(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EntityRef;
+    use serde::Deserialize;
+    use serde_json::json;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct WidgetCreated {
+        label: String,
+        count: u32,
+    }
+
+    impl OpPayload for WidgetCreated {
+        const ENTITY_TYPE: &'static str = "Widget";
+        const OP_TYPE: &'static str = "widget.created";
+        const SCHEMA_VERSION: u32 = 3;
+    }
+
+    fn widget_registry() -> PayloadRegistry {
+        let mut registry = PayloadRegistry::new();
+        registry
+            .register::<WidgetCreated>()
+            // v1 called the label `name`.
+            .upcaster::<WidgetCreated>(1, |mut value| {
+                let name = value
+                    .as_object_mut()
+                    .and_then(|object| object.remove("name"))
+                    .ok_or("missing name")?;
+                value["label"] = name;
+                Ok(value)
+            })
+            // v3 added `count`.
+            .upcaster::<WidgetCreated>(2, |mut value| {
+                value["count"] = json!(1);
+                Ok(value)
+            });
+        registry
+    }
+
+    fn op(entity_type: &str, op_type: &str, schema_version: u32, payload: Value) -> Operation {
+        Operation {
+            op_id: Uuid::now_v7(),
+            clinic_id: Uuid::now_v7(),
+            device_id: Uuid::now_v7(),
+            user_id: Uuid::now_v7(),
+            entity: EntityRef {
+                entity_type: entity_type.into(),
+                entity_id: Uuid::now_v7(),
+            },
+            op_type: op_type.into(),
+            device_time: OffsetDateTime::now_utc(),
+            device_seq: 1,
+            hlc: None,
+            schema_version,
+            payload,
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn old_payloads_are_upcast_through_every_version() {
+        let registry = widget_registry();
+        let expected = WidgetCreated {
+            label: "a".into(),
+            count: 1,
+        };
+
+        let v1 = op("Widget", "widget.created", 1, json!({ "name": "a" }));
+        assert_eq!(registry.decode_as::<WidgetCreated>(&v1), Ok(expected));
+
+        let v3 = op(
+            "Widget",
+            "widget.created",
+            3,
+            json!({ "label": "b", "count": 2 }),
+        );
+        assert_eq!(
+            registry.decode_as::<WidgetCreated>(&v3),
+            Ok(WidgetCreated {
+                label: "b".into(),
+                count: 2
+            })
+        );
+    }
+
+    #[test]
+    fn decoding_reports_why_a_payload_is_not_understood() {
+        let registry = widget_registry();
+
+        let newer = op("Widget", "widget.created", 4, json!({}));
+        let err = registry.decode_as::<WidgetCreated>(&newer).unwrap_err();
+        assert!(err.is_unsupported());
+        assert!(matches!(
+            err,
+            PayloadError::UnsupportedSchemaVersion { current: 3, .. }
+        ));
+
+        let broken_v1 = op("Widget", "widget.created", 1, json!({ "label": "a" }));
+        assert!(matches!(
+            registry.decode_as::<WidgetCreated>(&broken_v1),
+            Err(PayloadError::Upcast { from: 1, .. })
+        ));
+
+        let malformed = op("Widget", "widget.created", 3, json!({ "label": "a" }));
+        let err = registry.decode_as::<WidgetCreated>(&malformed).unwrap_err();
+        assert!(!err.is_unsupported());
+        assert!(matches!(err, PayloadError::Invalid { .. }));
+
+        let unknown = op("Widget", "widget.deleted", 1, json!({}));
+        assert!(matches!(
+            registry.upcast("Widget", "widget.deleted", 1, json!({})),
+            Err(PayloadError::UnknownOperation { .. })
+        ));
+        assert!(registry.decode_as::<WidgetCreated>(&unknown).is_err());
+    }
+
+    #[test]
+    fn missing_upcasters_are_reported() {
+        let mut registry = PayloadRegistry::new();
+        registry.register::<WidgetCreated>();
+
+        let v2 = op("Widget", "widget.created", 2, json!({ "label": "a" }));
+        assert_eq!(
+            registry.decode_as::<WidgetCreated>(&v2),
+            Err(PayloadError::MissingUpcaster {
+                op_type: "widget.created".into(),
+                from: 2
+            })
+        );
+    }
+
+    #[test]
+    fn the_standard_registry_decodes_known_payloads() {
+        let registry = registry();
+        assert_eq!(
+            registry.current_version("Patient", "patient.registered"),
+            Some(PatientRegistered::SCHEMA_VERSION)
+        );
+        assert!(registry.operations().contains(&(
+            "ClinicalNote",
+            "clinical_note.revision_created",
+            1
+        )));
+
+        let patient = op(
+            "Patient",
+            "patient.registered",
+            1,
+            json!({ "family_name": "Okafor", "given_names": ["Ada"], "birth_date": "1984-03-09" }),
+        );
+        let decoded = patient.decode_payload().unwrap();
+        assert_eq!(decoded.op_type(), "patient.registered");
+        let Payload::PatientRegistered(registered) = decoded else {
+            panic!("unexpected payload {decoded:?}");
+        };
+        assert_eq!(registered.family_name, "Okafor");
+
+        // Payloads that parse but break their op type's rules are invalid.
+        let nameless = op(
+            "Patient",
+            "patient.registered",
+            2,
+            json!({ "family_name": " " }),
+        );
+        assert!(matches!(
+            nameless.decode_payload(),
+            Err(PayloadError::Invalid { .. })
+        ));
+
+        let mismatched = op("Patient", "clinical_note.revision_created", 1, json!({}));
+        assert!(matches!(
+            mismatched.decode_payload(),
+            Err(PayloadError::UnknownOperation { .. })
+        ));
+    }
+}
@@ -0,0 +1,1007 @@
+//! Folding the op log into current entity state.
+//!
+//! [`Projection::apply`] is pure and deterministic, and shared by the server
+//! and the desktop so both derive the same state from the same ops. Fields
+//! follow their entity's [`MergePolicy`](crate::merge::MergePolicy): patient
+//! demographics are multi-value registers that surface conflicts, everything
+//! else is a last-writer-wins register ordered by [`Stamp`] or an append-only
+//! collection. Applying a set of ops gives the same result in any order and
+//! applying an op twice changes nothing. Ops that arrive before the op creating their entity (a
+//! demographics change pulled before the registration) are kept and merged
+//! once the rest arrives.
+//!
+//! A projection serializes with everything it needs to keep merging, so a
+//! [`Snapshot`](crate::Snapshot) of it plus the ops after it folds to the same
+//! state as the whole log.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use time::{Date, OffsetDateTime};
+
+use crate::domain::{
+    AdministrativeGender, ConditionAdded, ConflictResolved, EncounterOpened, MedicationOrdered,
+    NoteRevisionCreated, ObservationRecorded, PatientDemographicsChanged, PatientField,
+    PatientRegistered,
+};
+use crate::fhir::{Identifier, Reference};
+use crate::merge::{Conflict, MergePolicy, Mvr};
+use crate::payload::{OpPayload, Payload, PayloadError};
+use crate::{EntityId, EntityRef, Hlc, Operation, OperationId, SyncScope, UserId};
+
+/// Orders writes to the same field. Ops without an HLC predate HLCs and lose
+/// to every op that has one; `op_id` breaks the remaining ties.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hlc: Option<Hlc>,
+    pub op_id: OperationId,
+}
+
+impl Stamp {
+    pub fn of(op: &Operation) -> Self {
+        Stamp {
+            hlc: op.hlc,
+            op_id: op.op_id,
+        }
+    }
+}
+
+/// Serde for maps keyed by [`Stamp`], which JSON cannot use as object keys:
+/// a sequence of `[stamp, value]` pairs.
+pub(crate) mod by_stamp {
+    use super::*;
+
+    pub fn serialize<S, T>(map: &BTreeMap<Stamp, T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<BTreeMap<Stamp, T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        Vec::<(Stamp, T)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+/// A last-writer-wins register. `None` until the first write.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Lww<T> {
+    value: Option<(T, Stamp)>,
+}
+
+impl<T> Default for Lww<T> {
+    fn default() -> Self {
+        Lww { value: None }
+    }
+}
+
+impl<T> Lww<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref().map(|(value, _)| value)
+    }
+
+    pub fn stamp(&self) -> Option<Stamp> {
+        self.value.as_ref().map(|(_, stamp)| *stamp)
+    }
+
+    /// Keeps `value` if `stamp` is newer than the current write.
+    pub fn set(&mut self, value: T, stamp: Stamp) {
+        if self.stamp().is_none_or(|current| stamp > current) {
+            self.value = Some((value, stamp));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PatientState {
+    /// Set once the `patient.registered` op has been applied.
+    pub registered: Lww<()>,
+    pub family_name: Mvr<String>,
+    pub given_names: Mvr<Vec<String>>,
+    pub birth_date: Mvr<Option<Date>>,
+    pub gender: Mvr<Option<AdministrativeGender>>,
+    pub identifiers: Mvr<Vec<Identifier>>,
+}
+
+impl PatientState {
+    /// The ops a change to `field` should list in `based_on`.
+    pub fn head_ids(&self, field: PatientField) -> Vec<OperationId> {
+        match field {
+            PatientField::FamilyName => self.family_name.head_ids(),
+            PatientField::GivenNames => self.given_names.head_ids(),
+            PatientField::BirthDate => self.birth_date.head_ids(),
+            PatientField::Gender => self.gender.head_ids(),
+            PatientField::Identifiers => self.identifiers.head_ids(),
+        }
+    }
+
+    /// Open conflicts on this patient's fields.
+    pub fn conflicts(&self, entity: &EntityRef) -> Vec<Conflict> {
+        // Each value is rendered the way a demographics change carries it.
+        let json = |change: PatientDemographicsChanged, field: PatientField| {
+            serde_json::to_value(change).expect("payloads always serialize")[field.name()].clone()
+        };
+        [
+            self.family_name.conflict(entity, "family_name", |v| {
+                let change = PatientDemographicsChanged {
+                    family_name: Some(v.clone()),
+                    ..Default::default()
+                };
+                json(change, PatientField::FamilyName)
+            }),
+            self.given_names.conflict(entity, "given_names", |v| {
+                let change = PatientDemographicsChanged {
+                    given_names: Some(v.clone()),
+                    ..Default::default()
+                };
+                json(change, PatientField::GivenNames)
+            }),
+            self.birth_date.conflict(entity, "birth_date", |v| {
+                let change = PatientDemographicsChanged {
+                    birth_date: *v,
+                    ..Default::default()
+                };
+                json(change, PatientField::BirthDate)
+            }),
+            self.gender.conflict(entity, "gender", |v| {
+                let change = PatientDemographicsChanged {
+                    gender: *v,
+                    ..Default::default()
+                };
+                json(change, PatientField::Gender)
+            }),
+            self.identifiers.conflict(entity, "identifiers", |v| {
+                let change = PatientDemographicsChanged {
+                    identifiers: Some(v.clone()),
+                    ..Default::default()
+                };
+                json(change, PatientField::Identifiers)
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Writes a resolved `value` for `field` over the ops in `based_on`.
+    /// Values that do not fit the field are ignored; validation keeps them
+    /// out of the log.
+    fn resolve(&mut self, resolved: ConflictResolved, stamp: Stamp) {
+        let Some(field) = PatientField::from_name(&resolved.field) else {
+            return;
+        };
+        let based_on = &resolved.resolves;
+        let change = if resolved.value.is_null() {
+            Ok(PatientDemographicsChanged::default())
+        } else {
+            serde_json::from_value::<PatientDemographicsChanged>(
+                serde_json::json!({ field.name(): resolved.value }),
+            )
+        };
+        let Ok(change) = change else {
+            return;
+        };
+        match field {
+            PatientField::FamilyName => {
+                if let Some(v) = change.family_name {
+                    self.family_name.write(v, stamp, based_on);
+                }
+            }
+            PatientField::GivenNames => {
+                if let Some(v) = change.given_names {
+                    self.given_names.write(v, stamp, based_on);
+                }
+            }
+            PatientField::BirthDate => self.birth_date.write(change.birth_date, stamp, based_on),
+            PatientField::Gender => self.gender.write(change.gender, stamp, based_on),
+            PatientField::Identifiers => {
+                if let Some(v) = change.identifiers {
+                    self.identifiers.write(v, stamp, based_on);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncounterState {
+    pub opened: Lww<EncounterOpened>,
+    pub ended_at: Lww<OffsetDateTime>,
+}
+
+impl EncounterState {
+    pub fn is_open(&self) -> bool {
+        self.opened.get().is_some() && self.ended_at.get().is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObservationState {
+    pub recorded: Lww<ObservationRecorded>,
+    /// Once set the observation stays retracted; only the reason can change.
+    pub entered_in_error: Lww<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConditionState {
+    pub added: Lww<ConditionAdded>,
+    pub resolved_on: Lww<Date>,
+}
+
+impl ConditionState {
+    pub fn is_active(&self) -> bool {
+        self.added.get().is_some() && self.resolved_on.get().is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MedicationState {
+    pub ordered: Lww<MedicationOrdered>,
+    /// Discontinued with an optional reason.
+    pub discontinued: Lww<Option<String>>,
+}
+
+impl MedicationState {
+    pub fn is_active(&self) -> bool {
+        self.ordered.get().is_some() && self.discontinued.get().is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteRevision {
+    pub op_id: OperationId,
+    pub author: UserId,
+    pub patient: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encounter: Option<Reference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<OperationId>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoteState {
+    /// Every revision, oldest first.
+    #[serde(with = "by_stamp")]
+    pub revisions: BTreeMap<Stamp, NoteRevision>,
+}
+
+impl NoteState {
+    pub fn current(&self) -> Option<&NoteRevision> {
+        self.revisions.values().next_back()
+    }
+
+    /// The note's patient. Every revision names it; the oldest is taken.
+    pub fn patient(&self) -> Option<&Reference> {
+        self.revisions
+            .values()
+            .next()
+            .map(|revision| &revision.patient)
+    }
+}
+
+/// The state of one entity type. Its registers implement the
+/// [`MergePolicy`] [`merge_policy`](crate::merge::merge_policy) gives that
+/// entity type, which the tests check against the policy declared here.
+pub trait EntityState {
+    const ENTITY_TYPE: &'static str;
+    const MERGE_POLICY: MergePolicy;
+}
+
+/// Demographics are multi-value registers that surface a [`Conflict`].
+impl EntityState for PatientState {
+    const ENTITY_TYPE: &'static str = PatientRegistered::ENTITY_TYPE;
+    const MERGE_POLICY: MergePolicy = MergePolicy::Manual;
+}
+
+impl EntityState for EncounterState {
+    const ENTITY_TYPE: &'static str = EncounterOpened::ENTITY_TYPE;
+    const MERGE_POLICY: MergePolicy = MergePolicy::LastWriterWins;
+}
+
+/// An observation is recorded once and retracted rather than edited; two
+/// devices recording the same observation id settle on the later one.
+impl EntityState for ObservationState {
+    const ENTITY_TYPE: &'static str = ObservationRecorded::ENTITY_TYPE;
+    const MERGE_POLICY: MergePolicy = MergePolicy::AppendOnly;
+}
+
+impl EntityState for ConditionState {
+    const ENTITY_TYPE: &'static str = ConditionAdded::ENTITY_TYPE;
+    const MERGE_POLICY: MergePolicy = MergePolicy::LastWriterWins;
+}
+
+impl EntityState for MedicationState {
+    const ENTITY_TYPE: &'static str = MedicationOrdered::ENTITY_TYPE;
+    const MERGE_POLICY: MergePolicy = MergePolicy::LastWriterWins;
+}
+
+/// Every revision is kept, concurrent ones included.
+impl EntityState for NoteState {
+    const ENTITY_TYPE: &'static str = NoteRevisionCreated::ENTITY_TYPE;
+    const MERGE_POLICY: MergePolicy = MergePolicy::AppendOnly;
+}
+
+/// The entity types a [`Projection`] holds.
+pub const ENTITY_TYPES: [&str; 6] = [
+    PatientRegistered::ENTITY_TYPE,
+    EncounterOpened::ENTITY_TYPE,
+    ObservationRecorded::ENTITY_TYPE,
+    ConditionAdded::ENTITY_TYPE,
+    MedicationOrdered::ENTITY_TYPE,
+    NoteRevisionCreated::ENTITY_TYPE,
+];
+
+/// Current state of every entity the applied ops touched.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Projection {
+    pub patients: BTreeMap<EntityId, PatientState>,
+    pub encounters: BTreeMap<EntityId, EncounterState>,
+    pub observations: BTreeMap<EntityId, ObservationState>,
+    pub conditions: BTreeMap<EntityId, ConditionState>,
+    pub medications: BTreeMap<EntityId, MedicationState>,
+    pub notes: BTreeMap<EntityId, NoteState>,
+}
+
+impl Projection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every open conflict, by entity.
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        self.patients
+            .iter()
+            .flat_map(|(id, patient)| {
+                patient.conflicts(&EntityRef {
+                    entity_type: PatientRegistered::ENTITY_TYPE.into(),
+                    entity_id: *id,
+                })
+            })
+            .collect()
+    }
+
+    /// Drops every entity of `entity_type`, for readers not allowed to see
+    /// them. Each entity type is projected on its own, so the rest of the
+    /// state is unaffected.
+    pub fn remove_entity_type(&mut self, entity_type: &str) {
+        match entity_type {
+            PatientRegistered::ENTITY_TYPE => self.patients.clear(),
+            EncounterOpened::ENTITY_TYPE => self.encounters.clear(),
+            ObservationRecorded::ENTITY_TYPE => self.observations.clear(),
+            ConditionAdded::ENTITY_TYPE => self.conditions.clear(),
+            MedicationOrdered::ENTITY_TYPE => self.medications.clear(),
+            NoteRevisionCreated::ENTITY_TYPE => self.notes.clear(),
+            _ => {}
+        }
+    }
+
+    /// Narrows the projection to `scope`, the way `/sync/pull` narrows the
+    /// log. Entities whose creating op has not been applied belong to no
+    /// patient and are dropped by a patient panel.
+    pub fn restrict(&mut self, scope: &SyncScope) {
+        for entity_type in ENTITY_TYPES {
+            if !scope.includes_entity_type(entity_type) {
+                self.remove_entity_type(entity_type);
+            }
+        }
+        let panel = |patient: Option<&Reference>| scope.includes_patient(patient.map(|p| p.id));
+        self.patients
+            .retain(|id, _| scope.includes_patient(Some(*id)));
+        self.encounters
+            .retain(|_, e| panel(e.opened.get().map(|o| &o.patient)));
+        self.observations
+            .retain(|_, o| panel(o.recorded.get().map(|r| &r.patient)));
+        self.conditions
+            .retain(|_, c| panel(c.added.get().map(|a| &a.patient)));
+        self.medications
+            .retain(|_, m| panel(m.ordered.get().map(|o| &o.patient)));
+        self.notes.retain(|_, n| panel(n.patient()));
+    }
+
+    /// Applies each op in turn, stopping at the first one that cannot be
+    /// decoded.
+    pub fn from_ops<'a>(
+        ops: impl IntoIterator<Item = &'a Operation>,
+    ) -> Result<Self, PayloadError> {
+        let mut projection = Projection::new();
+        for op in ops {
+            projection.apply(op)?;
+        }
+        Ok(projection)
+    }
+
+    /// Folds `op` into the projection. Fails, leaving the projection
+    /// unchanged, if the payload cannot be decoded.
+    pub fn apply(&mut self, op: &Operation) -> Result<(), PayloadError> {
+        let payload = op.decode_payload()?;
+        self.apply_payload(op, payload);
+        Ok(())
+    }
+
+    /// Folds an already decoded `payload` of `op` into the projection.
+    pub fn apply_payload(&mut self, op: &Operation, payload: Payload) {
+        let id = op.entity.entity_id;
+        let stamp = Stamp::of(op);
+        match payload {
+            Payload::PatientRegistered(patient) => {
+                let state = self.patients.entry(id).or_default();
+                state.registered.set((), stamp);
+                state.family_name.write(patient.family_name, stamp, &[]);
+                state.given_names.write(patient.given_names, stamp, &[]);
+                state.birth_date.write(patient.birth_date, stamp, &[]);
+                state.gender.write(patient.gender, stamp, &[]);
+                state.identifiers.write(patient.identifiers, stamp, &[]);
+            }
+            Payload::PatientDemographicsChanged(change) => {
+                let state = self.patients.entry(id).or_default();
+                let based_on = &change.based_on;
+                if let Some(family_name) = change.family_name {
+                    state.family_name.write(family_name, stamp, based_on);
+                }
+                if let Some(given_names) = change.given_names {
+                    state.given_names.write(given_names, stamp, based_on);
+                }
+                if let Some(birth_date) = change.birth_date {
+                    state.birth_date.write(Some(birth_date), stamp, based_on);
+                }
+                if let Some(gender) = change.gender {
+                    state.gender.write(Some(gender), stamp, based_on);
+                }
+                if let Some(identifiers) = change.identifiers {
+                    state.identifiers.write(identifiers, stamp, based_on);
+                }
+            }
+            Payload::ConflictResolved(resolved) => {
+                self.patients
+                    .entry(id)
+                    .or_default()
+                    .resolve(resolved, stamp);
+            }
+            Payload::EncounterOpened(opened) => {
+                self.encounters
+                    .entry(id)
+                    .or_default()
+                    .opened
+                    .set(opened, stamp);
+            }
+            Payload::EncounterClosed(closed) => {
+                self.encounters
+                    .entry(id)
+                    .or_default()
+                    .ended_at
+                    .set(closed.ended_at, stamp);
+            }
+            Payload::ObservationRecorded(recorded) => {
+                self.observations
+                    .entry(id)
+                    .or_default()
+                    .recorded
+                    .set(recorded, stamp);
+            }
+            Payload::ObservationEnteredInError(retraction) => {
+                self.observations
+                    .entry(id)
+                    .or_default()
+                    .entered_in_error
+                    .set(retraction.reason, stamp);
+            }
+            Payload::ConditionAdded(added) => {
+                self.conditions
+                    .entry(id)
+                    .or_default()
+                    .added
+                    .set(added, stamp);
+            }
+            Payload::ConditionResolved(resolved) => {
+                self.conditions
+                    .entry(id)
+                    .or_default()
+                    .resolved_on
+                    .set(resolved.resolved_on, stamp);
+            }
+            Payload::MedicationOrdered(ordered) => {
+                self.medications
+                    .entry(id)
+                    .or_default()
+                    .ordered
+                    .set(ordered, stamp);
+            }
+            Payload::MedicationDiscontinued(discontinued) => {
+                self.medications
+                    .entry(id)
+                    .or_default()
+                    .discontinued
+                    .set(discontinued.reason, stamp);
+            }
+            Payload::NoteRevisionCreated(revision) => {
+                self.notes.entry(id).or_default().revisions.insert(
+                    stamp,
+                    NoteRevision {
+                        op_id: op.op_id,
+                        author: op.user_id,
+                        patient: revision.patient,
+                        encounter: revision.encounter,
+                        supersedes: revision.supersedes,
+                        body: revision.body,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        ConditionResolved, EncounterClosed, MedicationDiscontinued, ObservationEnteredInError,
+        ObservationValue,
+    };
+    use crate::fhir::{system, Coding, Quantity};
+    use crate::merge::merge_policy;
+    use proptest::prelude::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    const PATIENT: Uuid = Uuid::from_u128(1);
+    const OBSERVATION: Uuid = Uuid::from_u128(2);
+    const NOTE: Uuid = Uuid::from_u128(3);
+
+    fn op<P: OpPayload>(entity_id: EntityId, hlc: Hlc, op_id: u128, payload: &P) -> Operation {
+        Operation {
+            op_id: Uuid::from_u128(op_id),
+            clinic_id: Uuid::from_u128(10),
+            device_id: hlc.node,
+            user_id: Uuid::from_u128(20),
+            entity: EntityRef {
+                entity_type: P::ENTITY_TYPE.into(),
+                entity_id,
+            },
+            op_type: P::OP_TYPE.into(),
+            device_time: OffsetDateTime::UNIX_EPOCH,
+            device_seq: 1,
+            hlc: Some(hlc),
+            schema_version: P::SCHEMA_VERSION,
+            payload: serde_json::to_value(payload).unwrap(),
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        }
+    }
+
+    fn hlc(wall_ms: u64, node: u128) -> Hlc {
+        Hlc {
+            wall_ms,
+            counter: 0,
+            node: Uuid::from_u128(node),
+        }
+    }
+
+    fn registered(family_name: &str) -> PatientRegistered {
+        PatientRegistered {
+            family_name: family_name.into(),
+            given_names: vec!["Ada".into()],
+            birth_date: None,
+            gender: None,
+            identifiers: vec![],
+        }
+    }
+
+    fn renamed(family_name: &str, based_on: &[u128]) -> PatientDemographicsChanged {
+        PatientDemographicsChanged {
+            based_on: based_on.iter().copied().map(Uuid::from_u128).collect(),
+            family_name: Some(family_name.into()),
+            ..Default::default()
+        }
+    }
+
+    fn note(body: &str) -> NoteRevisionCreated {
+        NoteRevisionCreated {
+            patient: Reference {
+                resource_type: "Patient".into(),
+                id: PATIENT,
+            },
+            encounter: None,
+            supersedes: None,
+            body: body.into(),
+        }
+    }
+
+    fn heart_rate(value: f64) -> ObservationRecorded {
+        ObservationRecorded {
+            patient: Reference {
+                resource_type: "Patient".into(),
+                id: PATIENT,
+            },
+            encounter: None,
+            code: Coding::new(system::LOINC, "8867-4").into(),
+            value: ObservationValue::Quantity(Quantity::ucum(value, "/min")),
+            effective_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn demographic_changes_replace_the_values_they_build_on() {
+        let ops = [
+            op(PATIENT, hlc(1, 1), 1, &registered("Okafor")),
+            op(PATIENT, hlc(3, 2), 2, &renamed("Okafor-Smith", &[1])),
+            // Made on a device with a slow clock, but after seeing op 2.
+            op(PATIENT, hlc(2, 1), 3, &renamed("Okafor-Jones", &[2])),
+        ];
+        let projection = Projection::from_ops(&ops).unwrap();
+        let patient = &projection.patients[&PATIENT];
+        assert!(patient.registered.get().is_some());
+        assert_eq!(patient.family_name.get().unwrap(), "Okafor-Jones");
+        assert_eq!(patient.given_names.get().unwrap(), &["Ada".to_string()]);
+        assert!(projection.conflicts().is_empty());
+
+        // A rename pulled before the registration survives it.
+        let early = Projection::from_ops([&ops[1], &ops[0]]).unwrap();
+        assert_eq!(
+            early.patients[&PATIENT].family_name.get().unwrap(),
+            "Okafor-Smith"
+        );
+    }
+
+    #[test]
+    fn concurrent_demographic_changes_conflict_until_resolved() {
+        let mut projection = Projection::from_ops(&[
+            op(PATIENT, hlc(1, 1), 1, &registered("Okafor")),
+            op(PATIENT, hlc(2, 1), 2, &renamed("Okafor-Smith", &[1])),
+            op(PATIENT, hlc(2, 2), 3, &renamed("Okafor-Jones", &[1])),
+        ])
+        .unwrap();
+        let conflicts = projection.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert_eq!(conflict.entity.entity_id, PATIENT);
+        assert_eq!(conflict.field, "family_name");
+        let values: Vec<_> = conflict.candidates.iter().map(|c| &c.value).collect();
+        assert_eq!(values, [&json!("Okafor-Smith"), &json!("Okafor-Jones")]);
+        assert_eq!(
+            projection.patients[&PATIENT].head_ids(PatientField::FamilyName),
+            conflict.op_ids()
+        );
+
+        let resolution = ConflictResolved {
+            field: "family_name".into(),
+            resolves: conflict.op_ids(),
+            chosen: Some(conflict.candidates[0].op_id),
+            value: conflict.candidates[0].value.clone(),
+            reason: Some("Checked the passport".into()),
+        };
+        projection
+            .apply(&op(PATIENT, hlc(3, 1), 4, &resolution))
+            .unwrap();
+        assert!(projection.conflicts().is_empty());
+        assert_eq!(
+            projection.patients[&PATIENT].family_name.get().unwrap(),
+            "Okafor-Smith"
+        );
+
+        // Conflicts on nullable fields resolve to null too.
+        let mut dated = PatientDemographicsChanged {
+            birth_date: Some(time::macros::date!(1984 - 03 - 09)),
+            ..Default::default()
+        };
+        dated.based_on = vec![Uuid::from_u128(1)];
+        projection
+            .apply(&op(PATIENT, hlc(4, 1), 5, &dated))
+            .unwrap();
+        projection
+            .apply(&op(PATIENT, hlc(4, 2), 6, &dated))
+            .unwrap();
+        let conflict = projection.conflicts().remove(0);
+        assert_eq!(conflict.field, "birth_date");
+        assert_eq!(conflict.candidates[0].value, json!("1984-03-09"));
+        let cleared = ConflictResolved {
+            field: "birth_date".into(),
+            resolves: conflict.op_ids(),
+            chosen: None,
+            value: json!(null),
+            reason: None,
+        };
+        projection
+            .apply(&op(PATIENT, hlc(5, 1), 7, &cleared))
+            .unwrap();
+        assert!(projection.conflicts().is_empty());
+        assert_eq!(projection.patients[&PATIENT].birth_date.get(), Some(&None));
+    }
+
+    #[test]
+    fn retractions_and_revisions_are_kept() {
+        let ops = [
+            op(OBSERVATION, hlc(1, 1), 1, &heart_rate(72.0)),
+            op(
+                OBSERVATION,
+                hlc(2, 1),
+                2,
+                &ObservationEnteredInError {
+                    reason: "Wrong patient".into(),
+                },
+            ),
+            op(NOTE, hlc(1, 1), 3, &note("v1")),
+            op(
+                NOTE,
+                hlc(2, 2),
+                4,
+                &NoteRevisionCreated {
+                    supersedes: Some(Uuid::from_u128(3)),
+                    ..note("v2")
+                },
+            ),
+        ];
+        let projection = Projection::from_ops(&ops).unwrap();
+        let observation = &projection.observations[&OBSERVATION];
+        assert!(observation.recorded.get().is_some());
+        assert_eq!(observation.entered_in_error.get().unwrap(), "Wrong patient");
+
+        let note = &projection.notes[&NOTE];
+        assert_eq!(note.revisions.len(), 2);
+        assert_eq!(note.current().unwrap().body, "v2");
+        assert_eq!(note.current().unwrap().supersedes, Some(Uuid::from_u128(3)));
+        assert_eq!(note.patient().map(|p| p.id), Some(PATIENT));
+    }
+
+    #[test]
+    fn closing_ops_end_encounters_conditions_and_orders() {
+        let patient = Reference {
+            resource_type: "Patient".into(),
+            id: PATIENT,
+        };
+        let (encounter, condition, order) =
+            (Uuid::from_u128(4), Uuid::from_u128(5), Uuid::from_u128(6));
+        let date = time::macros::date!(2026 - 01 - 31);
+        let mut projection = Projection::from_ops(&[
+            op(
+                encounter,
+                hlc(1, 1),
+                1,
+                &EncounterOpened {
+                    patient: patient.clone(),
+                    started_at: OffsetDateTime::UNIX_EPOCH,
+                    reason: None,
+                },
+            ),
+            op(
+                condition,
+                hlc(1, 1),
+                2,
+                &ConditionAdded {
+                    patient: patient.clone(),
+                    encounter: None,
+                    code: Coding::new(system::ICD_10, "I10").into(),
+                    onset_date: None,
+                },
+            ),
+            op(
+                order,
+                hlc(1, 1),
+                3,
+                &MedicationOrdered {
+                    patient,
+                    encounter: None,
+                    medication: Coding::new(system::RXNORM, "197361").into(),
+                    dose: None,
+                    route: None,
+                    instructions: "Once daily".into(),
+                },
+            ),
+        ])
+        .unwrap();
+        assert!(projection.encounters[&encounter].is_open());
+        assert!(projection.conditions[&condition].is_active());
+        assert!(projection.medications[&order].is_active());
+
+        for closing in [
+            op(
+                encounter,
+                hlc(2, 1),
+                4,
+                &EncounterClosed {
+                    ended_at: OffsetDateTime::UNIX_EPOCH,
+                },
+            ),
+            op(
+                condition,
+                hlc(2, 1),
+                5,
+                &ConditionResolved { resolved_on: date },
+            ),
+            op(
+                order,
+                hlc(2, 1),
+                6,
+                &MedicationDiscontinued { reason: None },
+            ),
+        ] {
+            projection.apply(&closing).unwrap();
+        }
+        assert!(!projection.encounters[&encounter].is_open());
+        assert!(!projection.conditions[&condition].is_active());
+        assert_eq!(
+            projection.conditions[&condition].resolved_on.get(),
+            Some(&date)
+        );
+        assert!(!projection.medications[&order].is_active());
+    }
+
+    #[test]
+    fn undecodable_ops_leave_the_projection_unchanged() {
+        let mut projection = Projection::new();
+        let mut bad = op(PATIENT, hlc(1, 1), 1, &registered("Okafor"));
+        bad.payload = serde_json::json!({ "family_name": 7 });
+        assert!(projection.apply(&bad).is_err());
+        assert_eq!(projection, Projection::new());
+    }
+
+    /// Ops from three devices against one patient, one observation and one
+    /// note, with distinct op ids.
+    fn log() -> impl Strategy<Value = Vec<Operation>> {
+        let based_on = prop::collection::vec(1u128..24, 0..3);
+        let event = (0u64..8, 1u128..4, 0u8..7, 0u8..4, based_on);
+        prop::collection::vec(event, 1..24).prop_map(|events| {
+            events
+                .into_iter()
+                .enumerate()
+                .map(|(i, (wall_ms, node, kind, variant, based_on))| {
+                    let stamp = hlc(wall_ms, node);
+                    let op_id = i as u128 + 1;
+                    let name = format!("Name {variant}");
+                    match kind {
+                        0 => op(PATIENT, stamp, op_id, &registered(&name)),
+                        1 => op(PATIENT, stamp, op_id, &renamed(&name, &based_on)),
+                        5 => {
+                            let resolves = based_on.iter().chain(&[98, 99]);
+                            let resolution = ConflictResolved {
+                                field: "family_name".into(),
+                                resolves: resolves.copied().map(Uuid::from_u128).collect(),
+                                chosen: None,
+                                value: json!(name),
+                                reason: None,
+                            };
+                            op(PATIENT, stamp, op_id, &resolution)
+                        }
+                        2 => op(OBSERVATION, stamp, op_id, &heart_rate(f64::from(variant))),
+                        3 => op(
+                            OBSERVATION,
+                            stamp,
+                            op_id,
+                            &ObservationEnteredInError { reason: name },
+                        ),
+                        _ => op(NOTE, stamp, op_id, &note(&name)),
+                    }
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn every_entity_state_follows_its_merge_policy() {
+        let states = [
+            (PatientState::ENTITY_TYPE, PatientState::MERGE_POLICY),
+            (EncounterState::ENTITY_TYPE, EncounterState::MERGE_POLICY),
+            (
+                ObservationState::ENTITY_TYPE,
+                ObservationState::MERGE_POLICY,
+            ),
+            (ConditionState::ENTITY_TYPE, ConditionState::MERGE_POLICY),
+            (MedicationState::ENTITY_TYPE, MedicationState::MERGE_POLICY),
+            (NoteState::ENTITY_TYPE, NoteState::MERGE_POLICY),
+        ];
+        assert_eq!(states.map(|(entity_type, _)| entity_type), ENTITY_TYPES);
+        for (entity_type, policy) in states {
+            assert_eq!(merge_policy(entity_type), Some(policy), "{entity_type}");
+        }
+    }
+
+    #[test]
+    fn removing_an_entity_type_leaves_the_others() {
+        let mut projection = Projection::from_ops(&[
+            op(PATIENT, hlc(1, 1), 1, &registered("Okafor")),
+            op(OBSERVATION, hlc(2, 1), 2, &heart_rate(72.0)),
+            op(NOTE, hlc(3, 1), 3, &note("Well")),
+        ])
+        .unwrap();
+        projection.remove_entity_type(ObservationRecorded::ENTITY_TYPE);
+        projection.remove_entity_type("Widget");
+        assert!(projection.observations.is_empty());
+        assert_eq!(projection.patients.len(), 1);
+        assert_eq!(projection.notes.len(), 1);
+    }
+
+    #[test]
+    fn restricting_to_a_scope_keeps_what_it_covers() {
+        let full = Projection::from_ops(&[
+            op(PATIENT, hlc(1, 1), 1, &registered("Okafor")),
+            op(OBSERVATION, hlc(2, 1), 2, &heart_rate(72.0)),
+            op(NOTE, hlc(3, 1), 3, &note("Well")),
+            // Retracted, but never recorded here: no known patient.
+            op(
+                Uuid::from_u128(4),
+                hlc(4, 1),
+                4,
+                &ObservationEnteredInError {
+                    reason: "Wrong chart".into(),
+                },
+            ),
+        ])
+        .unwrap();
+
+        let mut everything = full.clone();
+        everything.restrict(&SyncScope::default());
+        assert_eq!(everything, full);
+
+        let mut panel = full.clone();
+        panel.restrict(&SyncScope {
+            patients: Some(vec![PATIENT]),
+            entity_types: None,
+        });
+        assert_eq!(panel.patients.len(), 1);
+        assert_eq!(
+            panel.observations.keys().collect::<Vec<_>>(),
+            [&OBSERVATION]
+        );
+        assert_eq!(panel.notes.keys().collect::<Vec<_>>(), [&NOTE]);
+
+        let mut elsewhere = full.clone();
+        elsewhere.restrict(&SyncScope {
+            patients: Some(vec![Uuid::from_u128(99)]),
+            entity_types: None,
+        });
+        assert_eq!(elsewhere, Projection::new());
+
+        let mut front_desk = full;
+        front_desk.restrict(&SyncScope {
+            patients: None,
+            entity_types: Some(vec!["Patient".into(), "Encounter".into()]),
+        });
+        assert_eq!(front_desk.patients.len(), 1);
+        assert!(front_desk.observations.is_empty());
+        assert!(front_desk.notes.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn a_serialized_prefix_plus_the_tail_folds_like_the_whole_log(
+            (ops, split) in log().prop_flat_map(|ops| {
+                let len = ops.len();
+                (Just(ops), 0..=len)
+            }),
+        ) {
+            let expected = Projection::from_ops(&ops).unwrap();
+
+            let prefix = Projection::from_ops(&ops[..split]).unwrap();
+            let json = serde_json::to_vec(&prefix).unwrap();
+            let mut restored: Projection = serde_json::from_slice(&json).unwrap();
+            prop_assert_eq!(&restored, &prefix);
+            for op in &ops[split..] {
+                restored.apply(op).unwrap();
+            }
+            prop_assert_eq!(restored, expected);
+        }
+
+        #[test]
+        fn projection_ignores_order_and_duplicates(
+            (ops, shuffled) in log().prop_flat_map(|ops| {
+                let shuffled = Just(ops.clone()).prop_shuffle();
+                (Just(ops), shuffled)
+            }),
+            repeats in prop::collection::vec(any::<prop::sample::Index>(), 0..8),
+        ) {
+            let expected = Projection::from_ops(&ops).unwrap();
+
+            let mut replayed = shuffled.clone();
+            replayed.extend(repeats.iter().map(|index| index.get(&shuffled).clone()));
+            prop_assert_eq!(Projection::from_ops(&replayed).unwrap(), expected);
+        }
+    }
+}
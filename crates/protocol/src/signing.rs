@@ -0,0 +1,123 @@
+//! Ed25519 device signatures over op hashes.
+//!
+//! Every registered device holds a signing key and registers the matching
+//! [`DevicePublicKey`]. A device signs the [`OpHash`](crate::OpHash) of each op
+//! it authors, so the signature covers the canonical encoding and the hash
+//! chain without re-serializing the op.
+
+use ed25519_dalek::{Signature, Signer};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::encoding::{hex_bytes, InvalidEncoding};
+use crate::Operation;
+
+hex_bytes!(DevicePublicKey, 32, "device public key");
+hex_bytes!(OpSignature, 64, "op signature");
+
+impl DevicePublicKey {
+    /// Fails for byte strings that are not a valid Ed25519 point.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, InvalidEncoding> {
+        VerifyingKey::from_bytes(&self.0).map_err(|_| InvalidEncoding("device public key"))
+    }
+}
+
+impl From<&SigningKey> for DevicePublicKey {
+    fn from(key: &SigningKey) -> Self {
+        DevicePublicKey(key.verifying_key().to_bytes())
+    }
+}
+
+impl Operation {
+    /// Seals the op and signs its hash. Call once every other field is final.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.seal();
+        let hash = self.hash.expect("sealed ops have a hash");
+        self.signature = Some(OpSignature(key.sign(&hash.0).to_bytes()));
+    }
+
+    /// Whether the op is correctly sealed and signed by `key`.
+    pub fn verify_signature(&self, key: &VerifyingKey) -> bool {
+        let (Some(hash), Some(signature)) = (self.hash, self.signature) else {
+            return false;
+        };
+        self.verify_hash()
+            && key
+                .verify_strict(&hash.0, &Signature::from_bytes(&signature.0))
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EntityRef;
+    use serde_json::json;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    fn op() -> Operation {
+        Operation {
+            op_id: Uuid::now_v7(),
+            clinic_id: Uuid::now_v7(),
+            device_id: Uuid::now_v7(),
+            user_id: Uuid::now_v7(),
+            entity: EntityRef {
+                entity_type: "Patient".into(),
+                entity_id: Uuid::now_v7(),
+            },
+            op_type: "patient.registered".into(),
+            device_time: OffsetDateTime::now_utc(),
+            device_seq: 1,
+            hlc: None,
+            schema_version: 1,
+            payload: json!({ "family_name": "Okafor" }),
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn signatures_bind_the_op_to_the_device_key() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let mut signed = op();
+        signed.sign(&key);
+
+        assert!(signed.verify_signature(&key.verifying_key()));
+        assert!(!signed.verify_signature(&other.verifying_key()));
+
+        let mut tampered = signed.clone();
+        tampered.payload["family_name"] = json!("Okafor-Smith");
+        assert!(!tampered.verify_signature(&key.verifying_key()));
+
+        // Resealing after tampering does not help without the key.
+        tampered.seal();
+        assert!(!tampered.verify_signature(&key.verifying_key()));
+
+        let mut unsigned = signed.clone();
+        unsigned.signature = None;
+        assert!(!unsigned.verify_signature(&key.verifying_key()));
+    }
+
+    #[test]
+    fn keys_and_signatures_are_hex_on_the_wire() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = DevicePublicKey::from(&key);
+        let json = serde_json::to_string(&public_key).unwrap();
+        assert_eq!(json.len(), 2 + 64);
+        assert_eq!(
+            serde_json::from_str::<DevicePublicKey>(&json).unwrap(),
+            public_key
+        );
+        assert_eq!(public_key.verifying_key().unwrap(), key.verifying_key());
+
+        let mut signed = op();
+        signed.sign(&key);
+        let json = serde_json::to_value(&signed).unwrap();
+        assert_eq!(json["signature"].as_str().unwrap().len(), 128);
+        assert_eq!(serde_json::from_value::<Operation>(json).unwrap(), signed);
+
+        assert!(serde_json::from_str::<OpSignature>("\"00\"").is_err());
+    }
+}
@@ -0,0 +1,565 @@
+//! Property-test strategies and conformance vectors, for checking an
+//! implementation of the protocol against this one. Enabled by the `testing`
+//! feature.
+//!
+//! The strategies generate well-formed values: ops are sealed, carry an HLC
+//! from their own device and a payload that decodes, and a
+//! [`device_log`] is a chain the server accepts as pushed. Tests that need
+//! malformed input start from these and break one thing. The fixed
+//! [`vectors`] pin down the byte-level encoding for implementations that do
+//! not link this crate.
+
+use std::ops::Range;
+
+use proptest::arbitrary::Arbitrary;
+use proptest::prelude::*;
+use proptest::strategy::BoxedStrategy;
+use time::{Date, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::domain::{
+    AdministrativeGender, ConditionAdded, ConditionResolved, ConflictResolved, EncounterClosed,
+    EncounterOpened, MedicationDiscontinued, MedicationOrdered, NoteRevisionCreated,
+    ObservationEnteredInError, ObservationRecorded, ObservationValue, PatientDemographicsChanged,
+    PatientRegistered,
+};
+use crate::fhir::{system, CodeableConcept, Coding, Identifier, Quantity, Reference};
+use crate::hash::truncate_device_time;
+use crate::payload::{OpPayload, Payload};
+use crate::signing::SigningKey;
+use crate::{
+    ClinicId, Cursor, DeviceId, EntityRef, Hlc, OpHash, Operation, PullRequest, PullResponse,
+    PushOutcome, PushRequest, PushResponse, PushResult, RejectReason, UserId,
+};
+
+pub mod vectors;
+
+/// Device times between 2000 and 2100, so their HLC wall times stay in range.
+const DEVICE_TIMES: Range<i64> = 946_684_800_000_000..4_102_444_800_000_000;
+
+pub fn uuid() -> impl Strategy<Value = Uuid> {
+    any::<u128>().prop_map(Uuid::from_u128)
+}
+
+/// A UTC time at the microsecond precision the canonical encoding keeps.
+pub fn device_time() -> impl Strategy<Value = OffsetDateTime> {
+    DEVICE_TIMES.prop_map(|micros| {
+        OffsetDateTime::from_unix_timestamp_nanos(i128::from(micros) * 1_000)
+            .expect("device times are in range")
+    })
+}
+
+pub fn hlc() -> impl Strategy<Value = Hlc> {
+    (0u64..1 << 45, any::<u32>(), uuid()).prop_map(|(wall_ms, counter, node)| Hlc {
+        wall_ms,
+        counter,
+        node,
+    })
+}
+
+pub fn cursor() -> impl Strategy<Value = Cursor> {
+    any::<u64>().prop_map(Cursor)
+}
+
+pub fn op_hash() -> impl Strategy<Value = OpHash> {
+    any::<[u8; 32]>().prop_map(OpHash)
+}
+
+pub fn entity_ref() -> impl Strategy<Value = EntityRef> {
+    let entity_types = crate::projection::ENTITY_TYPES.map(String::from).to_vec();
+    (prop::sample::select(entity_types), uuid()).prop_map(|(entity_type, entity_id)| EntityRef {
+        entity_type,
+        entity_id,
+    })
+}
+
+/// Non-blank text, including characters that need escaping in JSON.
+pub fn text() -> impl Strategy<Value = String> {
+    "[A-Za-zé\"\\\\][A-Za-z0-9 éü'\"\\\\\n-]{0,23}"
+}
+
+pub fn reference(resource_type: &'static str) -> impl Strategy<Value = Reference> {
+    uuid().prop_map(move |id| Reference {
+        resource_type: resource_type.into(),
+        id,
+    })
+}
+
+pub fn coding() -> impl Strategy<Value = Coding> {
+    let systems = vec![
+        system::LOINC,
+        system::SNOMED_CT,
+        system::ICD_10,
+        system::RXNORM,
+    ];
+    (
+        prop::sample::select(systems),
+        "[A-Z0-9][A-Za-z0-9.-]{0,9}",
+        prop::option::of(text()),
+    )
+        .prop_map(|(system, code, display)| Coding {
+            system: system.into(),
+            code,
+            display,
+        })
+}
+
+pub fn codeable_concept() -> impl Strategy<Value = CodeableConcept> {
+    (
+        prop::collection::vec(coding(), 0..3),
+        prop::option::of(text()),
+    )
+        .prop_filter("a concept needs a coding or text", |(coding, text)| {
+            !coding.is_empty() || text.is_some()
+        })
+        .prop_map(|(coding, text)| CodeableConcept { coding, text })
+}
+
+/// A UCUM quantity with a finite value.
+pub fn quantity() -> impl Strategy<Value = Quantity> {
+    let units = vec!["/min", "mm[Hg]", "kg", "Cel", "%", "mg/dL", "10*3/uL"];
+    (-1e6..1e6f64, prop::sample::select(units))
+        .prop_map(|(value, unit)| Quantity::ucum(value, unit))
+}
+
+pub fn identifier() -> impl Strategy<Value = Identifier> {
+    ("[a-z]{2,8}", "[A-Z0-9]{1,12}").prop_map(|(host, value)| Identifier {
+        system: format!("urn:{host}:mrn"),
+        value,
+    })
+}
+
+pub fn date() -> impl Strategy<Value = Date> {
+    (2_400_000..2_500_000i32)
+        .prop_map(|day| Date::from_julian_day(day).expect("julian days are in range"))
+}
+
+pub fn gender() -> impl Strategy<Value = AdministrativeGender> {
+    prop::sample::select(vec![
+        AdministrativeGender::Male,
+        AdministrativeGender::Female,
+        AdministrativeGender::Other,
+        AdministrativeGender::Unknown,
+    ])
+}
+
+pub fn patient_registered() -> impl Strategy<Value = PatientRegistered> {
+    (
+        text(),
+        prop::collection::vec(text(), 0..3),
+        prop::option::of(date()),
+        prop::option::of(gender()),
+        prop::collection::vec(identifier(), 0..2),
+    )
+        .prop_map(
+            |(family_name, given_names, birth_date, gender, identifiers)| PatientRegistered {
+                family_name,
+                given_names,
+                birth_date,
+                gender,
+                identifiers,
+            },
+        )
+}
+
+pub fn patient_demographics_changed() -> impl Strategy<Value = PatientDemographicsChanged> {
+    (
+        prop::collection::vec(uuid(), 0..3),
+        prop::option::of(text()),
+        prop::option::of(prop::collection::vec(text(), 0..3)),
+        prop::option::of(date()),
+        prop::option::of(gender()),
+        prop::option::of(prop::collection::vec(identifier(), 0..2)),
+    )
+        .prop_map(
+            |(based_on, family_name, given_names, birth_date, gender, identifiers)| {
+                PatientDemographicsChanged {
+                    based_on,
+                    family_name,
+                    given_names,
+                    birth_date,
+                    gender,
+                    identifiers,
+                }
+            },
+        )
+        .prop_filter("a change must change something", |change| {
+            change.validate().is_ok()
+        })
+}
+
+pub fn conflict_resolved() -> impl Strategy<Value = ConflictResolved> {
+    (
+        prop::collection::btree_set(uuid(), 2..4),
+        any::<prop::sample::Index>(),
+        any::<bool>(),
+        text(),
+        prop::option::of(text()),
+    )
+        .prop_map(|(resolves, pick, keep, name, reason)| {
+            let resolves: Vec<Uuid> = resolves.into_iter().collect();
+            ConflictResolved {
+                field: "family_name".into(),
+                chosen: keep.then(|| *pick.get(&resolves)),
+                resolves,
+                value: serde_json::json!(name),
+                reason,
+            }
+        })
+}
+
+pub fn encounter_opened() -> impl Strategy<Value = EncounterOpened> {
+    (
+        reference("Patient"),
+        device_time(),
+        prop::option::of(codeable_concept()),
+    )
+        .prop_map(|(patient, started_at, reason)| EncounterOpened {
+            patient,
+            started_at,
+            reason,
+        })
+}
+
+pub fn encounter_closed() -> impl Strategy<Value = EncounterClosed> {
+    device_time().prop_map(|ended_at| EncounterClosed { ended_at })
+}
+
+pub fn observation_value() -> impl Strategy<Value = ObservationValue> {
+    prop_oneof![
+        quantity().prop_map(ObservationValue::Quantity),
+        codeable_concept().prop_map(ObservationValue::CodeableConcept),
+        text().prop_map(ObservationValue::String),
+    ]
+}
+
+pub fn observation_recorded() -> impl Strategy<Value = ObservationRecorded> {
+    (
+        reference("Patient"),
+        prop::option::of(reference("Encounter")),
+        codeable_concept(),
+        observation_value(),
+        device_time(),
+    )
+        .prop_map(
+            |(patient, encounter, code, value, effective_at)| ObservationRecorded {
+                patient,
+                encounter,
+                code,
+                value,
+                effective_at,
+            },
+        )
+}
+
+pub fn observation_entered_in_error() -> impl Strategy<Value = ObservationEnteredInError> {
+    text().prop_map(|reason| ObservationEnteredInError { reason })
+}
+
+pub fn condition_added() -> impl Strategy<Value = ConditionAdded> {
+    (
+        reference("Patient"),
+        prop::option::of(reference("Encounter")),
+        codeable_concept(),
+        prop::option::of(date()),
+    )
+        .prop_map(|(patient, encounter, code, onset_date)| ConditionAdded {
+            patient,
+            encounter,
+            code,
+            onset_date,
+        })
+}
+
+pub fn condition_resolved() -> impl Strategy<Value = ConditionResolved> {
+    date().prop_map(|resolved_on| ConditionResolved { resolved_on })
+}
+
+pub fn medication_ordered() -> impl Strategy<Value = MedicationOrdered> {
+    (
+        reference("Patient"),
+        prop::option::of(reference("Encounter")),
+        codeable_concept(),
+        prop::option::of(quantity()),
+        prop::option::of(codeable_concept()),
+        text(),
+    )
+        .prop_map(
+            |(patient, encounter, medication, dose, route, instructions)| MedicationOrdered {
+                patient,
+                encounter,
+                medication,
+                dose,
+                route,
+                instructions,
+            },
+        )
+}
+
+pub fn medication_discontinued() -> impl Strategy<Value = MedicationDiscontinued> {
+    prop::option::of(text()).prop_map(|reason| MedicationDiscontinued { reason })
+}
+
+pub fn note_revision_created() -> impl Strategy<Value = NoteRevisionCreated> {
+    (
+        reference("Patient"),
+        prop::option::of(reference("Encounter")),
+        prop::option::of(uuid()),
+        text(),
+    )
+        .prop_map(
+            |(patient, encounter, supersedes, body)| NoteRevisionCreated {
+                patient,
+                encounter,
+                supersedes,
+                body,
+            },
+        )
+}
+
+/// Any known payload, at its current schema version.
+pub fn payload() -> impl Strategy<Value = Payload> {
+    prop_oneof![
+        patient_registered().prop_map(Payload::from),
+        patient_demographics_changed().prop_map(Payload::from),
+        conflict_resolved().prop_map(Payload::from),
+        encounter_opened().prop_map(Payload::from),
+        encounter_closed().prop_map(Payload::from),
+        observation_recorded().prop_map(Payload::from),
+        observation_entered_in_error().prop_map(Payload::from),
+        condition_added().prop_map(Payload::from),
+        condition_resolved().prop_map(Payload::from),
+        medication_ordered().prop_map(Payload::from),
+        medication_discontinued().prop_map(Payload::from),
+        note_revision_created().prop_map(Payload::from),
+    ]
+}
+
+/// A sealed, unsigned op that passes [`Operation::validate`] and whose
+/// payload decodes. Its `prev_hash`, if any, links to nothing in particular.
+pub fn operation() -> impl Strategy<Value = Operation> {
+    (
+        (uuid(), uuid(), uuid(), uuid(), uuid()),
+        payload(),
+        device_time(),
+        1u64..1 << 40,
+        any::<u32>(),
+        prop::option::of(op_hash()),
+    )
+        .prop_map(
+            |(
+                (op_id, clinic_id, device_id, user_id, entity_id),
+                payload,
+                time,
+                seq,
+                counter,
+                prev,
+            )| {
+                let mut op = Operation {
+                    op_id,
+                    clinic_id,
+                    device_id,
+                    user_id,
+                    entity: EntityRef {
+                        entity_type: payload.entity_type().into(),
+                        entity_id,
+                    },
+                    op_type: payload.op_type().into(),
+                    device_time: time,
+                    device_seq: seq,
+                    hlc: Some(Hlc {
+                        wall_ms: (time.unix_timestamp_nanos() / 1_000_000) as u64,
+                        counter,
+                        node: device_id,
+                    }),
+                    schema_version: payload.schema_version(),
+                    payload: payload.to_json(),
+                    prev_hash: prev,
+                    hash: None,
+                    signature: None,
+                };
+                op.seal();
+                op
+            },
+        )
+}
+
+/// `len` ops a device would push as its first: numbered from 1, stamped
+/// with increasing HLCs, chained and signed with `key`.
+pub fn device_log(
+    clinic_id: ClinicId,
+    user_id: UserId,
+    device_id: DeviceId,
+    key: SigningKey,
+    len: Range<usize>,
+) -> impl Strategy<Value = Vec<Operation>> {
+    let start_ms = (DEVICE_TIMES.start / 1_000) as u64;
+    let steps = prop::collection::vec((operation(), 0u64..120_000), len);
+    (start_ms..start_ms + (1 << 40), steps).prop_map(move |(wall_ms, steps)| {
+        let mut hlc = Hlc {
+            wall_ms,
+            counter: 0,
+            node: device_id,
+        };
+        let mut prev_hash = None;
+        let mut ops = Vec::with_capacity(steps.len());
+        for (i, (mut op, gap_ms)) in steps.into_iter().enumerate() {
+            hlc = match gap_ms {
+                0 => Hlc {
+                    counter: hlc.counter + 1,
+                    ..hlc
+                },
+                gap => Hlc {
+                    wall_ms: hlc.wall_ms + gap,
+                    counter: 0,
+                    ..hlc
+                },
+            };
+            op.clinic_id = clinic_id;
+            op.user_id = user_id;
+            op.device_id = device_id;
+            op.device_seq = i as u64 + 1;
+            op.device_time = truncate_device_time(hlc.wall_time());
+            op.hlc = Some(hlc);
+            op.prev_hash = prev_hash;
+            op.sign(&key);
+            prev_hash = op.hash;
+            ops.push(op);
+        }
+        ops
+    })
+}
+
+pub fn push_outcome() -> impl Strategy<Value = PushOutcome> {
+    prop_oneof![
+        cursor().prop_map(|cursor| PushOutcome::Accepted { cursor }),
+        Just(PushOutcome::Duplicate),
+        (
+            prop::sample::select(vec![
+                RejectReason::PermissionDenied,
+                RejectReason::OpIdTaken,
+                RejectReason::HashMismatch,
+                RejectReason::ChainBroken,
+                RejectReason::InvalidSignature,
+                RejectReason::UnsupportedOperation,
+                RejectReason::InvalidPayload,
+            ]),
+            text(),
+        )
+            .prop_map(|(reason, message)| PushOutcome::Rejected { reason, message }),
+    ]
+}
+
+pub fn push_request() -> impl Strategy<Value = PushRequest> {
+    prop::collection::vec(operation(), 0..4).prop_map(|ops| PushRequest { ops })
+}
+
+pub fn push_response() -> impl Strategy<Value = PushResponse> {
+    prop::collection::vec((uuid(), push_outcome()), 0..4).prop_map(|results| PushResponse {
+        results: results
+            .into_iter()
+            .map(|(op_id, outcome)| PushResult { op_id, outcome })
+            .collect(),
+    })
+}
+
+pub fn pull_request() -> impl Strategy<Value = PullRequest> {
+    (prop::option::of(cursor()), prop::option::of(1u32..=1000))
+        .prop_map(|(cursor, limit)| PullRequest { cursor, limit })
+}
+
+pub fn pull_response() -> impl Strategy<Value = PullResponse> {
+    (
+        prop::collection::vec(operation(), 0..4),
+        prop::option::of(cursor()),
+        any::<bool>(),
+        any::<u64>(),
+    )
+        .prop_map(
+            |(ops, next_cursor, has_more, scope_revision)| PullResponse {
+                ops,
+                next_cursor,
+                has_more,
+                scope_revision,
+            },
+        )
+}
+
+macro_rules! arbitrary {
+    ($($ty:ty => $strategy:expr),* $(,)?) => {
+        $(
+            impl Arbitrary for $ty {
+                type Parameters = ();
+                type Strategy = BoxedStrategy<$ty>;
+
+                fn arbitrary_with(_: ()) -> Self::Strategy {
+                    $strategy.boxed()
+                }
+            }
+        )*
+    };
+}
+
+arbitrary!(
+    Cursor => cursor(),
+    Hlc => hlc(),
+    OpHash => op_hash(),
+    EntityRef => entity_ref(),
+    Payload => payload(),
+    Operation => operation(),
+    PushRequest => push_request(),
+    PushResponse => push_response(),
+    PullRequest => pull_request(),
+    PullResponse => pull_response(),
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::WireFormat;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    proptest! {
+        #[test]
+        fn generated_ops_are_well_formed(op in any::<Operation>()) {
+            prop_assert_eq!(op.validate(), Ok(()));
+            prop_assert!(op.verify_hash());
+            let payload = op.decode_payload().unwrap();
+            prop_assert_eq!(payload.to_json(), op.payload.clone());
+        }
+
+        #[test]
+        fn device_logs_are_signed_chains(
+            ops in device_log(Uuid::nil(), Uuid::nil(), Uuid::from_u128(7), key(7), 1..6),
+        ) {
+            let verifying_key = key(7).verifying_key();
+            for (i, op) in ops.iter().enumerate() {
+                prop_assert_eq!(op.device_seq, i as u64 + 1);
+                prop_assert!(op.verify_signature(&verifying_key));
+                prop_assert_eq!(op.validate(), Ok(()));
+            }
+            for pair in ops.windows(2) {
+                prop_assert_eq!(pair[1].prev_hash, pair[0].hash);
+                prop_assert!(pair[1].hlc > pair[0].hlc);
+            }
+        }
+
+        #[test]
+        fn sync_messages_round_trip_through_every_format(
+            push in any::<PushRequest>(),
+            pushed in any::<PushResponse>(),
+            pull in any::<PullRequest>(),
+            pulled in any::<PullResponse>(),
+        ) {
+            for format in WireFormat::ALL {
+                prop_assert_eq!(&format.decode::<PushRequest>(&format.encode(&push).unwrap()).unwrap(), &push);
+                prop_assert_eq!(&format.decode::<PushResponse>(&format.encode(&pushed).unwrap()).unwrap(), &pushed);
+                prop_assert_eq!(&format.decode::<PullRequest>(&format.encode(&pull).unwrap()).unwrap(), &pull);
+                prop_assert_eq!(&format.decode::<PullResponse>(&format.encode(&pulled).unwrap()).unwrap(), &pulled);
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Fixed conformance vectors.
+//!
+//! The vectors live as plain JSON under `crates/protocol/vectors/`, so an
+//! implementation in another language can load the files directly; each
+//! file's `description` says what to check. The types here read them and
+//! [`OperationVector::check`] and [`MerkleVector::check`] run them against
+//! this crate.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::merkle::{bucket_of, MerkleNode, MerkleTree};
+use crate::signing::DevicePublicKey;
+use crate::{Hlc, OpHash, Operation, OperationId, OperationValidationError};
+
+pub const OPERATIONS_JSON: &str = include_str!("../../vectors/operations.json");
+pub const MERKLE_JSON: &str = include_str!("../../vectors/merkle.json");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationVectors {
+    pub description: String,
+    /// The key every signed vector was signed with.
+    pub verifying_key: DevicePublicKey,
+    pub vectors: Vec<OperationVector>,
+}
+
+/// One op and everything an implementation must conclude about it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationVector {
+    pub name: String,
+    /// The op as it travels on the wire.
+    pub op: Value,
+    /// The bytes the op's hash is computed over, as UTF-8.
+    pub canonical_json: String,
+    /// The hash of `canonical_json`, which need not be the op's `hash`.
+    pub computed_hash: OpHash,
+    /// Whether the op's `hash` matches its contents.
+    pub hash_valid: bool,
+    /// Whether the op is sealed and signed by the vectors' key.
+    pub signature_valid: bool,
+    /// What [`Operation::validate`] reports, if anything.
+    pub validation_error: Option<OperationValidationError>,
+    pub payload: PayloadVerdict,
+}
+
+/// What decoding an op's payload concludes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadVerdict {
+    Valid,
+    /// The op type is known but the payload breaks its schema.
+    Invalid,
+    /// The op type or its schema version is unknown.
+    Unsupported,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleVectors {
+    pub description: String,
+    pub vectors: Vec<MerkleVector>,
+}
+
+/// A set of ops and some of the nodes of its Merkle tree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleVector {
+    pub name: String,
+    pub ops: Vec<MerkleOp>,
+    pub nodes: Vec<MerkleNode>,
+}
+
+/// The fields of an op that place it in the tree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleOp {
+    pub op_id: OperationId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hlc: Option<Hlc>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub device_time: OffsetDateTime,
+}
+
+pub fn operations() -> OperationVectors {
+    serde_json::from_str(OPERATIONS_JSON).expect("operation vectors are valid")
+}
+
+pub fn merkle() -> MerkleVectors {
+    serde_json::from_str(MERKLE_JSON).expect("merkle vectors are valid")
+}
+
+impl OperationVector {
+    /// Checks this crate against the vector, describing the first mismatch.
+    pub fn check(&self, key: &DevicePublicKey) -> Result<(), String> {
+        let op: Operation = serde_json::from_value(self.op.clone())
+            .map_err(|err| format!("{}: does not decode: {err}", self.name))?;
+        let mismatch = |what: &str| Err(format!("{}: {what} differs", self.name));
+
+        if op.canonical_json() != self.canonical_json.as_bytes() {
+            return mismatch("canonical_json");
+        }
+        if op.compute_hash() != self.computed_hash {
+            return mismatch("computed_hash");
+        }
+        if op.verify_hash() != self.hash_valid {
+            return mismatch("hash_valid");
+        }
+        let key = key
+            .verifying_key()
+            .map_err(|err| format!("{}: {err}", self.name))?;
+        if op.verify_signature(&key) != self.signature_valid {
+            return mismatch("signature_valid");
+        }
+        if op.validate().err() != self.validation_error {
+            return mismatch("validation_error");
+        }
+        let payload = match op.decode_payload() {
+            Ok(_) => PayloadVerdict::Valid,
+            Err(err) if err.is_unsupported() => PayloadVerdict::Unsupported,
+            Err(_) => PayloadVerdict::Invalid,
+        };
+        if payload != self.payload {
+            return mismatch("payload");
+        }
+        if serde_json::to_value(&op).ok().as_ref() != Some(&self.op) {
+            return mismatch("re-encoded op");
+        }
+        Ok(())
+    }
+}
+
+impl MerkleVector {
+    /// Checks this crate against the vector, describing the first mismatch.
+    pub fn check(&self) -> Result<(), String> {
+        let mut tree = MerkleTree::new();
+        for op in &self.ops {
+            tree.insert(op.op_id, bucket_of(op.hlc.as_ref(), op.device_time));
+        }
+        for expected in &self.nodes {
+            if tree.node(expected.node) != *expected {
+                return Err(format!(
+                    "{}: node {}/{} differs",
+                    self.name, expected.node.level, expected.node.index
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn this_crate_passes_the_operation_vectors() {
+        let vectors = operations();
+        assert!(!vectors.vectors.is_empty());
+        for vector in &vectors.vectors {
+            vector.check(&vectors.verifying_key).unwrap();
+        }
+    }
+
+    #[test]
+    fn this_crate_passes_the_merkle_vectors() {
+        let vectors = merkle();
+        assert!(!vectors.vectors.is_empty());
+        for vector in &vectors.vectors {
+            vector.check().unwrap();
+        }
+    }
+
+    #[test]
+    fn checks_report_the_first_mismatch() {
+        let vectors = operations();
+        let mut vector = vectors.vectors[0].clone();
+        vector.hash_valid = !vector.hash_valid;
+        assert_eq!(
+            vector.check(&vectors.verifying_key),
+            Err(format!("{}: hash_valid differs", vector.name))
+        );
+
+        let mut vector = merkle().vectors[0].clone();
+        vector.nodes[0].digest.count += 1;
+        assert!(vector.check().is_err());
+    }
+}
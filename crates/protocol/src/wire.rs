@@ -0,0 +1,277 @@
+//! Encodings of sync request and response bodies.
+//!
+//! JSON stays the default. CBOR carries the same serde model in roughly half
+//! the bytes, which matters on the links rural clinics sync over. Hashes and
+//! signatures are computed over canonical JSON, never over the wire bytes, so
+//! an op verifies the same whichever encoding carried it.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Cbor,
+}
+
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error("invalid {format} body: {message}")]
+    Decode {
+        format: &'static str,
+        message: String,
+    },
+    #[error("failed to encode {format} body: {message}")]
+    Encode {
+        format: &'static str,
+        message: String,
+    },
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 2] = [WireFormat::Json, WireFormat::Cbor];
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_CONTENT_TYPE,
+            WireFormat::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WireFormat::Json => "JSON",
+            WireFormat::Cbor => "CBOR",
+        }
+    }
+
+    /// The format of a `Content-Type` value; parameters such as `charset`
+    /// are ignored.
+    pub fn from_content_type(value: &str) -> Option<Self> {
+        let essence = value.split(';').next().unwrap_or_default().trim();
+        Self::ALL
+            .into_iter()
+            .find(|format| essence.eq_ignore_ascii_case(format.content_type()))
+    }
+
+    /// The preferred format among those an `Accept` value lists, honouring
+    /// `q` weights. `None` when it lists none of ours and no wildcard.
+    pub fn from_accept(value: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+        for range in value.split(',') {
+            let mut parts = range.split(';');
+            let essence = parts.next().unwrap_or_default().trim();
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match essence {
+                "*/*" | "application/*" => Some(WireFormat::Json),
+                _ => Self::from_content_type(essence),
+            };
+            if let Some(format) = format {
+                if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                    best = Some((format, q));
+                }
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, WireError> {
+        let encoded = match self {
+            WireFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            WireFormat::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf)
+                    .map(|()| buf)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        encoded.map_err(|message| WireError::Encode {
+            format: self.name(),
+            message,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, WireError> {
+        let decoded = match self {
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            WireFormat::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|message| WireError::Decode {
+            format: self.name(),
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Cursor, EntityRef, Hlc, Operation, ProvenanceField, PullResponse, PushOutcome, PushRequest,
+        PushResponse, PushResult, RejectReason,
+    };
+    use proptest::prelude::*;
+    use serde_json::json;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    fn sealed_op(payload: serde_json::Value, device_seq: u64) -> Operation {
+        let device_id = Uuid::from_u128(2);
+        let mut op = Operation {
+            op_id: Uuid::from_u128(1),
+            clinic_id: Uuid::from_u128(3),
+            device_id,
+            user_id: Uuid::from_u128(4),
+            entity: EntityRef {
+                entity_type: "Patient".into(),
+                entity_id: Uuid::from_u128(5),
+            },
+            op_type: "patient.registered".into(),
+            device_time: OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_123_456_000)
+                .unwrap(),
+            device_seq,
+            hlc: Some(Hlc {
+                wall_ms: 1_700_000_000_123,
+                counter: 7,
+                node: device_id,
+            }),
+            schema_version: 2,
+            payload,
+            prev_hash: None,
+            hash: None,
+            signature: None,
+        };
+        op.seal();
+        op
+    }
+
+    fn round_trip<T>(value: &T)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        for format in WireFormat::ALL {
+            let bytes = format.encode(value).unwrap();
+            let decoded: T = format.decode(&bytes).unwrap();
+            assert_eq!(&decoded, value, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn sync_bodies_round_trip_in_every_format() {
+        let op = sealed_op(
+            json!({"given_name": "Ada", "weight": 61.5, "scores": [1, -2, 3.0], "note": null}),
+            u64::MAX,
+        );
+        round_trip(&PushRequest {
+            ops: vec![op.clone()],
+        });
+        round_trip(&PullResponse {
+            ops: vec![op],
+            next_cursor: Some(Cursor(u64::MAX)),
+            has_more: true,
+            scope_revision: 2,
+        });
+        round_trip(&PushResponse {
+            results: vec![
+                PushResult {
+                    op_id: Uuid::from_u128(1),
+                    outcome: PushOutcome::Accepted { cursor: Cursor(9) },
+                },
+                PushResult {
+                    op_id: Uuid::from_u128(2),
+                    outcome: PushOutcome::Rejected {
+                        reason: RejectReason::ProvenanceMismatch {
+                            field: ProvenanceField::DeviceId,
+                        },
+                        message: "wrong device".into(),
+                    },
+                },
+            ],
+        });
+    }
+
+    #[test]
+    fn cbor_is_smaller_and_keeps_the_op_verifiable() {
+        let op = sealed_op(json!({"given_name": "Ada", "family_name": "Okafor"}), 1);
+        let json = WireFormat::Json.encode(&op).unwrap();
+        let cbor = WireFormat::Cbor.encode(&op).unwrap();
+        assert!(cbor.len() < json.len(), "{} >= {}", cbor.len(), json.len());
+
+        let decoded: Operation = WireFormat::Cbor.decode(&cbor).unwrap();
+        assert_eq!(decoded.compute_hash(), op.hash.unwrap());
+    }
+
+    #[test]
+    fn cursor_stays_a_string_in_every_format() {
+        let cbor = WireFormat::Cbor.encode(&Cursor(u64::MAX)).unwrap();
+        let as_text: String = WireFormat::Cbor.decode(&cbor).unwrap();
+        assert_eq!(as_text, u64::MAX.to_string());
+    }
+
+    #[test]
+    fn negotiates_formats_from_headers() {
+        assert_eq!(
+            WireFormat::from_content_type("application/json; charset=utf-8"),
+            Some(WireFormat::Json)
+        );
+        assert_eq!(
+            WireFormat::from_content_type("Application/CBOR"),
+            Some(WireFormat::Cbor)
+        );
+        assert_eq!(WireFormat::from_content_type("text/plain"), None);
+
+        assert_eq!(
+            WireFormat::from_accept("application/cbor"),
+            Some(WireFormat::Cbor)
+        );
+        assert_eq!(
+            WireFormat::from_accept("application/json;q=0.5, application/cbor"),
+            Some(WireFormat::Cbor)
+        );
+        assert_eq!(
+            WireFormat::from_accept("application/cbor;q=0.2, */*;q=0.8"),
+            Some(WireFormat::Json)
+        );
+        assert_eq!(WireFormat::from_accept("application/cbor;q=0"), None);
+        assert_eq!(WireFormat::from_accept("text/html"), None);
+    }
+
+    #[test]
+    fn decode_errors_name_the_format() {
+        let err = WireFormat::Cbor
+            .decode::<PushRequest>(b"\xff\x00")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("invalid CBOR body"), "{err}");
+        let err = WireFormat::Json.decode::<PushRequest>(b"{").unwrap_err();
+        assert!(err.to_string().starts_with("invalid JSON body"), "{err}");
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_round_trip_in_every_format(
+            text in ".{0,24}",
+            int in any::<i64>(),
+            float in -1.0e12f64..1.0e12,
+            flag in any::<bool>(),
+            device_seq in any::<u64>(),
+        ) {
+            let op = sealed_op(
+                json!({"text": text, "int": int, "float": float, "flag": flag, "list": [int, text]}),
+                device_seq,
+            );
+            for format in WireFormat::ALL {
+                let decoded: Operation = format.decode(&format.encode(&op).unwrap()).unwrap();
+                prop_assert_eq!(&decoded, &op);
+                prop_assert_eq!(decoded.compute_hash(), op.hash.unwrap());
+            }
+        }
+    }
+}
@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS ops (
+  server_seq BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  op_id UUID NOT NULL UNIQUE,
+  organization_id UUID NOT NULL REFERENCES organizations(id),
+  clinic_id UUID NOT NULL,
+  device_id UUID NOT NULL,
+  user_id UUID NOT NULL,
+  entity_type TEXT NOT NULL,
+  entity_id UUID NOT NULL,
+  op_type TEXT NOT NULL,
+  device_time TIMESTAMPTZ NOT NULL,
+  device_seq BIGINT NOT NULL,
+  schema_version INTEGER NOT NULL,
+  payload JSONB NOT NULL,
+  server_received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS ops_organization_seq_idx ON ops(organization_id, server_seq);
+CREATE INDEX IF NOT EXISTS ops_device_seq_idx ON ops(device_id, device_seq);
+
+CREATE OR REPLACE FUNCTION ops_reject_mutation() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'ops is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER ops_append_only
+  BEFORE UPDATE OR DELETE ON ops
+  FOR EACH ROW EXECUTE FUNCTION ops_reject_mutation();
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS devices (
+  id UUID PRIMARY KEY,
+  organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+  registered_by UUID NOT NULL REFERENCES users(id),
+  name TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  revoked_at TIMESTAMPTZ NULL
+);
+
+CREATE INDEX IF NOT EXISTS devices_organization_idx ON devices(organization_id);
+
+-- Sessions created before devices were registered may reference unknown ids;
+-- those no longer authenticate, so only new rows need to satisfy the key.
+ALTER TABLE sessions
+  ADD CONSTRAINT sessions_device_id_fkey
+  FOREIGN KEY (device_id) REFERENCES devices(id) NOT VALID;
+
+CREATE INDEX IF NOT EXISTS sessions_device_id_idx ON sessions(device_id);
@@ -0,0 +1,5 @@
+-- Existing rows are left for an operator to fix; users with an unknown role
+-- cannot sign in.
+ALTER TABLE users
+  ADD CONSTRAINT users_role_check
+  CHECK (role IN ('admin', 'clinician', 'front_desk')) NOT VALID;
@@ -0,0 +1,3 @@
+-- NULL only for ops stored before devices hashed them.
+ALTER TABLE ops ADD COLUMN hash BYTEA;
+ALTER TABLE ops ADD COLUMN prev_hash BYTEA;
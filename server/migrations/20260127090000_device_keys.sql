@@ -0,0 +1,6 @@
+-- Ed25519 public key ops from the device are verified against. NULL only for
+-- devices registered before signing; they must re-register before pushing.
+ALTER TABLE devices ADD COLUMN public_key BYTEA;
+
+-- NULL only for ops stored before devices signed them.
+ALTER TABLE ops ADD COLUMN signature BYTEA;
@@ -0,0 +1,3 @@
+-- The op's hybrid logical clock in its string form, which sorts in clock
+-- order. NULL only for ops stored before devices stamped HLCs.
+ALTER TABLE ops ADD COLUMN hlc TEXT;
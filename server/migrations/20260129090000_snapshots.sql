@@ -0,0 +1,10 @@
+-- Projected state of an organization's log up to server_seq, served to new
+-- devices so they only pull the ops after it. Only the latest is kept.
+CREATE TABLE IF NOT EXISTS snapshots (
+  organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+  server_seq BIGINT NOT NULL,
+  op_count BIGINT NOT NULL,
+  projection JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (organization_id, server_seq)
+);
@@ -0,0 +1,29 @@
+-- The part of the log each device replicates. NULL columns do not narrow it.
+ALTER TABLE devices
+  ADD COLUMN scope_patients UUID[],
+  ADD COLUMN scope_entity_types TEXT[],
+  ADD COLUMN scope_revision BIGINT NOT NULL DEFAULT 0;
+
+-- The patient each entity belongs to, taken from the op that created it, so
+-- that ops which do not name their patient can be filtered by patient panel.
+CREATE TABLE IF NOT EXISTS entity_patients (
+  organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+  entity_type TEXT NOT NULL,
+  entity_id UUID NOT NULL,
+  patient_id UUID NOT NULL,
+  PRIMARY KEY (organization_id, entity_type, entity_id)
+);
+
+CREATE INDEX IF NOT EXISTS entity_patients_patient_idx
+  ON entity_patients(organization_id, patient_id);
+
+INSERT INTO entity_patients (organization_id, entity_type, entity_id, patient_id)
+SELECT DISTINCT ON (organization_id, entity_type, entity_id)
+       organization_id, entity_type, entity_id,
+       CASE WHEN entity_type = 'Patient' THEN entity_id
+            ELSE (payload->'patient'->>'id')::uuid END
+  FROM ops
+ WHERE entity_type = 'Patient'
+    OR payload->'patient'->>'id' ~* '^[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$'
+ ORDER BY organization_id, entity_type, entity_id, server_seq
+ON CONFLICT DO NOTHING;
@@ -1,10 +1,12 @@
 use axum::{
-    routing::{get, post},
+    routing::{get, post, put},
     Json, Router,
 };
 use serde::Serialize;
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
 
-use crate::{auth, state::AppState};
+use crate::{auth, devices, merkle, snapshot, state::AppState, sync};
 
 #[derive(Debug, Serialize)]
 struct HealthResponse {
@@ -17,9 +19,41 @@ pub fn router(state: AppState) -> Router {
         .route("/v1/auth/login", post(auth::login))
         .route("/v1/auth/me", get(auth::me))
         .route("/v1/auth/logout", post(auth::logout))
+        .route("/v1/devices", get(devices::list).post(devices::register))
+        .route("/v1/devices/:device_id/revoke", post(devices::revoke))
+        .route("/v1/devices/:device_id/scope", put(devices::set_scope))
+        .merge(sync_routes())
         .with_state(state)
 }
 
+/// Sync bodies are the bulk of the traffic from clinics on slow links, so
+/// these routes accept and send the `Content-Encoding`s capabilities list in
+/// [`sync::CONTENT_ENCODINGS`].
+fn sync_routes() -> Router<AppState> {
+    Router::new()
+        .route("/v1/sync/capabilities", get(sync::capabilities))
+        .route("/v1/sync/push", post(sync::push))
+        .route("/v1/sync/pull", get(sync::pull))
+        .route("/v1/sync/snapshot", get(snapshot::latest))
+        .route("/v1/sync/scope", get(devices::own_scope))
+        .route("/v1/sync/merkle", get(merkle::node))
+        .route("/v1/sync/fetch", post(merkle::fetch))
+        .layer(
+            CompressionLayer::new()
+                .no_br()
+                .no_deflate()
+                .gzip(true)
+                .zstd(true),
+        )
+        .layer(
+            RequestDecompressionLayer::new()
+                .no_br()
+                .no_deflate()
+                .gzip(true)
+                .zstd(true),
+        )
+}
+
 async fn healthz() -> Json<HealthResponse> {
     Json(HealthResponse { status: "ok" })
 }
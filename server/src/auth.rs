@@ -1,8 +1,10 @@
 use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
 use argon2::{Algorithm, Argon2, Params, Version};
+use axum::async_trait;
 use axum::extract::rejection::JsonRejection;
-use axum::extract::State;
+use axum::extract::{FromRequestParts, State};
 use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
 use axum::http::HeaderMap;
 use axum::Json;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
@@ -15,7 +17,9 @@ use thiserror::Error;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+use crate::devices;
 use crate::error::ApiError;
+use crate::rbac::{Permission, Role};
 use crate::state::AppState;
 
 const ARGON2_PARAMS: Params = match Params::new(8192, 2, 1, None) {
@@ -28,6 +32,9 @@ pub struct LoginRequest {
     pub organization_code: String,
     pub email: String,
     pub password: String,
+    /// A device registered via `/v1/devices` that this session will push ops
+    /// from.
+    pub device_id: Option<Uuid>,
 }
 
 #[derive(Debug, Serialize)]
@@ -41,7 +48,7 @@ pub struct OrganizationInfo {
 pub struct UserInfo {
     pub id: Uuid,
     pub email: String,
-    pub role: String,
+    pub role: Role,
 }
 
 #[derive(Debug, Serialize)]
@@ -153,6 +160,12 @@ pub async fn login(
         return Err(ApiError::unauthorized("incorrect password"));
     }
 
+    let role = parse_role(&user.role)?;
+
+    if let Some(device_id) = req.device_id {
+        devices::ensure_device_usable(&state.pool, organization.id, device_id).await?;
+    }
+
     let session_token = generate_session_token();
     let token_sha256 = sha256_bytes_from_session_token(&session_token)?;
     let session_id = Uuid::now_v7();
@@ -161,12 +174,14 @@ pub async fn login(
 
     sqlx::query(
         "INSERT INTO sessions \
-         (id, organization_id, user_id, token_sha256, created_at, expires_at, last_used_at) \
-         VALUES ($1, $2, $3, $4, $5, $6, $5)",
+         (id, organization_id, user_id, device_id, token_sha256, created_at, expires_at, \
+          last_used_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $6)",
     )
     .bind(session_id)
     .bind(organization.id)
     .bind(user.id)
+    .bind(req.device_id)
     .bind(token_sha256.as_slice())
     .bind(now)
     .bind(expires_at)
@@ -183,16 +198,12 @@ pub async fn login(
         user: UserInfo {
             id: user.id,
             email: user.email,
-            role: user.role,
+            role,
         },
     }))
 }
 
-pub async fn me(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-) -> Result<Json<MeResponse>, ApiError> {
-    let ctx = authenticate(&headers, &state.pool).await?;
+pub async fn me(ctx: AuthContext) -> Result<Json<MeResponse>, ApiError> {
     Ok(Json(MeResponse {
         organization: OrganizationInfo {
             id: ctx.organization_id,
@@ -209,9 +220,8 @@ pub async fn me(
 
 pub async fn logout(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    ctx: AuthContext,
 ) -> Result<Json<LogoutResponse>, ApiError> {
-    let ctx = authenticate(&headers, &state.pool).await?;
     sqlx::query("UPDATE sessions SET revoked_at = now() WHERE id = $1")
         .bind(ctx.session_id)
         .execute(&state.pool)
@@ -227,6 +237,11 @@ fn normalize_email(input: &str) -> Result<String, ApiError> {
     Ok(trimmed.to_ascii_lowercase())
 }
 
+fn parse_role(role: &str) -> Result<Role, ApiError> {
+    role.parse()
+        .map_err(|e| ApiError::forbidden(format!("user cannot sign in: {e}")))
+}
+
 fn generate_session_token() -> String {
     let mut bytes = [0u8; 32];
     rand::thread_rng().fill_bytes(&mut bytes);
@@ -240,15 +255,50 @@ fn sha256_bytes_from_session_token(token: &str) -> Result<Vec<u8>, ApiError> {
     Ok(Sha256::digest(decoded).to_vec())
 }
 
+/// The authenticated session behind a request's Bearer token.
+///
+/// Used as an extractor by every authenticated route; handlers then call
+/// [`AuthContext::require`] for the permission the route needs.
 #[derive(Debug)]
-struct AuthContext {
-    session_id: Uuid,
-    organization_id: Uuid,
-    organization_code: String,
-    organization_name: String,
-    user_id: Uuid,
-    user_email: String,
-    user_role: String,
+pub struct AuthContext {
+    pub(crate) session_id: Uuid,
+    pub(crate) organization_id: Uuid,
+    pub(crate) organization_code: String,
+    pub(crate) organization_name: String,
+    pub(crate) user_id: Uuid,
+    pub(crate) user_email: String,
+    pub(crate) user_role: Role,
+    pub(crate) device_id: Option<Uuid>,
+}
+
+impl AuthContext {
+    /// Fails with 403 unless the session's role grants `permission`.
+    pub(crate) fn require(&self, permission: Permission) -> Result<(), ApiError> {
+        if self.user_role.allows(permission) {
+            return Ok(());
+        }
+        Err(ApiError::forbidden(format!(
+            "role {} does not have the {} permission",
+            self.user_role,
+            permission.as_str()
+        )))
+    }
+
+    /// The session's device. Fails with 403 for sessions not bound to one,
+    /// which have no sync scope to read through.
+    pub(crate) fn require_device(&self) -> Result<Uuid, ApiError> {
+        self.device_id
+            .ok_or_else(|| ApiError::forbidden("this endpoint needs a device-bound session"))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthContext {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        authenticate(&parts.headers, &state.pool).await
+    }
 }
 
 async fn authenticate(headers: &HeaderMap, pool: &PgPool) -> Result<AuthContext, ApiError> {
@@ -275,6 +325,7 @@ async fn authenticate(headers: &HeaderMap, pool: &PgPool) -> Result<AuthContext,
             s.id AS session_id, \
             s.organization_id AS organization_id, \
             s.user_id AS user_id, \
+            s.device_id AS device_id, \
             u.email AS user_email, \
             u.role AS user_role, \
             o.code AS organization_code, \
@@ -282,9 +333,11 @@ async fn authenticate(headers: &HeaderMap, pool: &PgPool) -> Result<AuthContext,
          FROM sessions s \
          JOIN users u ON u.id = s.user_id \
          JOIN organizations o ON o.id = s.organization_id \
+         LEFT JOIN devices d ON d.id = s.device_id \
          WHERE s.token_sha256 = $1 \
            AND s.revoked_at IS NULL \
-           AND s.expires_at > now()",
+           AND s.expires_at > now() \
+           AND (s.device_id IS NULL OR (d.id IS NOT NULL AND d.revoked_at IS NULL))",
     )
     .bind(token_sha256.as_slice())
     .fetch_one(pool)
@@ -306,7 +359,8 @@ async fn authenticate(headers: &HeaderMap, pool: &PgPool) -> Result<AuthContext,
         organization_name: row.organization_name,
         user_id: row.user_id,
         user_email: row.user_email,
-        user_role: row.user_role,
+        user_role: parse_role(&row.user_role)?,
+        device_id: row.device_id,
     })
 }
 
@@ -331,6 +385,7 @@ struct SessionRow {
     session_id: Uuid,
     organization_id: Uuid,
     user_id: Uuid,
+    device_id: Option<Uuid>,
     user_email: String,
     user_role: String,
     organization_code: String,
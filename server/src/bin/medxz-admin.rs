@@ -2,6 +2,7 @@
 
 use std::collections::HashMap;
 
+use medxz_server::rbac::{Role, UnknownRole};
 use sqlx::PgPool;
 use thiserror::Error;
 use uuid::Uuid;
@@ -43,6 +44,15 @@ enum CliError {
     #[error("user already exists: {0}")]
     UserAlreadyExists(String),
 
+    #[error("invalid device id: {0}")]
+    InvalidDeviceId(String),
+
+    #[error(transparent)]
+    UnknownRole(#[from] UnknownRole),
+
+    #[error("unknown device: {0}")]
+    UnknownDevice(Uuid),
+
     #[error(transparent)]
     Db(#[from] medxz_server::db::DbError),
 
@@ -60,9 +70,12 @@ impl CliError {
             | CliError::UnknownCommand(_)
             | CliError::UnexpectedArgument(_)
             | CliError::MissingFlagValue(_)
-            | CliError::MissingRequiredFlag(_) => 2,
+            | CliError::MissingRequiredFlag(_)
+            | CliError::InvalidDeviceId(_)
+            | CliError::UnknownRole(_) => 2,
             CliError::UnknownOrganizationCode(_)
             | CliError::UserAlreadyExists(_)
+            | CliError::UnknownDevice(_)
             | CliError::Db(_)
             | CliError::Sqlx(_)
             | CliError::PasswordHash(_) => 1,
@@ -77,6 +90,8 @@ impl CliError {
                 | CliError::UnexpectedArgument(_)
                 | CliError::MissingFlagValue(_)
                 | CliError::MissingRequiredFlag(_)
+                | CliError::InvalidDeviceId(_)
+                | CliError::UnknownRole(_)
         )
     }
 }
@@ -104,11 +119,16 @@ async fn run() -> Result<(), CliError> {
         create_user(opts).await?;
         return Ok(());
     }
+    if command == "revoke-device" {
+        revoke_device(opts).await?;
+        return Ok(());
+    }
 
     Err(CliError::UnknownCommand(command))
 }
 
 async fn bootstrap(opts: HashMap<String, String>) -> Result<(), CliError> {
+    let role = role_flag(&opts)?;
     let pool = connect().await?;
     let org_code = required(&opts, "org-code")?;
     let org_name = required(&opts, "org-name")?;
@@ -117,7 +137,6 @@ async fn bootstrap(opts: HashMap<String, String>) -> Result<(), CliError> {
 
     let email = required(&opts, "email")?;
     let password = required(&opts, "password")?;
-    let role = opts.get("role").map(String::as_str).unwrap_or("front_desk");
 
     let user_id = ensure_user(&pool, org_id, email, password, role).await?;
 
@@ -137,11 +156,11 @@ async fn create_organization(opts: HashMap<String, String>) -> Result<(), CliErr
 }
 
 async fn create_user(opts: HashMap<String, String>) -> Result<(), CliError> {
+    let role = role_flag(&opts)?;
     let pool = connect().await?;
     let org_code = required(&opts, "org-code")?;
     let email = required(&opts, "email")?;
     let password = required(&opts, "password")?;
-    let role = opts.get("role").map(String::as_str).unwrap_or("front_desk");
 
     let org_id: Option<Uuid> = sqlx::query_scalar("SELECT id FROM organizations WHERE code = $1")
         .bind(org_code)
@@ -155,6 +174,29 @@ async fn create_user(opts: HashMap<String, String>) -> Result<(), CliError> {
     Ok(())
 }
 
+async fn revoke_device(opts: HashMap<String, String>) -> Result<(), CliError> {
+    let pool = connect().await?;
+    let org_code = required(&opts, "org-code")?;
+    let device_id_raw = required(&opts, "device-id")?;
+    let device_id = Uuid::parse_str(device_id_raw)
+        .map_err(|_| CliError::InvalidDeviceId(device_id_raw.to_string()))?;
+
+    let org_id: Option<Uuid> = sqlx::query_scalar("SELECT id FROM organizations WHERE code = $1")
+        .bind(org_code)
+        .fetch_optional(&pool)
+        .await?;
+    let org_id = org_id.ok_or_else(|| CliError::UnknownOrganizationCode(org_code.to_string()))?;
+
+    let mut tx = pool.begin().await?;
+    let device = medxz_server::devices::revoke_device(&mut tx, org_id, device_id)
+        .await?
+        .ok_or(CliError::UnknownDevice(device_id))?;
+    tx.commit().await?;
+
+    println!("device_id={} name={} revoked", device.id, device.name);
+    Ok(())
+}
+
 async fn connect() -> Result<PgPool, CliError> {
     Ok(medxz_server::db::connect_from_env_and_migrate().await?)
 }
@@ -184,7 +226,7 @@ async fn ensure_user(
     organization_id: Uuid,
     email: &str,
     password: &str,
-    role: &str,
+    role: Role,
 ) -> Result<Uuid, CliError> {
     let email = email.trim().to_ascii_lowercase();
     let existing: Option<Uuid> =
@@ -209,7 +251,7 @@ async fn ensure_user(
     .bind(organization_id)
     .bind(&email)
     .bind(password_hash)
-    .bind(role)
+    .bind(role.as_str())
     .execute(pool)
     .await?;
     Ok(id)
@@ -238,6 +280,13 @@ fn required<'a>(opts: &'a HashMap<String, String>, key: &'static str) -> Result<
         .ok_or(CliError::MissingRequiredFlag(key))
 }
 
+fn role_flag(opts: &HashMap<String, String>) -> Result<Role, CliError> {
+    match opts.get("role") {
+        Some(role) => Ok(role.parse()?),
+        None => Ok(Role::FrontDesk),
+    }
+}
+
 fn usage() -> &'static str {
-    "Usage:\n  medxz-admin bootstrap --org-code <code> --org-name <name> --email <email> --password <password> [--role <role>]\n  medxz-admin create-organization --org-code <code> --org-name <name>\n  medxz-admin create-user --org-code <code> --email <email> --password <password> [--role <role>]"
+    "Usage:\n  medxz-admin bootstrap --org-code <code> --org-name <name> --email <email> --password <password> [--role admin|clinician|front_desk]\n  medxz-admin create-organization --org-code <code> --org-name <name>\n  medxz-admin create-user --org-code <code> --email <email> --password <password> [--role admin|clinician|front_desk]\n  medxz-admin revoke-device --org-code <code> --device-id <uuid>"
 }
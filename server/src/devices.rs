@@ -0,0 +1,351 @@
+use axum::extract::rejection::{JsonRejection, PathRejection};
+use axum::extract::{Path, State};
+use axum::Json;
+use medxz_protocol::signing::VerifyingKey;
+use medxz_protocol::{payload, DevicePublicKey, DeviceScope, SyncScope};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::AuthContext;
+use crate::error::ApiError;
+use crate::rbac::Permission;
+use crate::state::AppState;
+
+const MAX_DEVICE_NAME_LEN: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterDeviceRequest {
+    /// Generated by the device on first run so it can author ops offline.
+    pub device_id: Uuid,
+    pub name: String,
+    /// Key the device signs its ops with. Fixed at first registration.
+    pub public_key: DevicePublicKey,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub registered_by: Uuid,
+    pub public_key: Option<DevicePublicKey>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListDevicesResponse {
+    pub devices: Vec<DeviceInfo>,
+}
+
+/// Registers the calling device and binds the current session to it if the
+/// session is not yet bound to a device.
+///
+/// Registering an already-registered device is idempotent, so a device can
+/// safely retry after losing the response.
+pub async fn register(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    payload: Result<Json<RegisterDeviceRequest>, JsonRejection>,
+) -> Result<Json<DeviceInfo>, ApiError> {
+    ctx.require(Permission::RegisterDevice)?;
+    let Json(req) = payload?;
+
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("name is required"));
+    }
+    if name.chars().count() > MAX_DEVICE_NAME_LEN {
+        return Err(ApiError::bad_request(format!(
+            "name must be at most {MAX_DEVICE_NAME_LEN} characters"
+        )));
+    }
+    if req.public_key.verifying_key().is_err() {
+        return Err(ApiError::bad_request(
+            "public_key is not a valid Ed25519 public key",
+        ));
+    }
+
+    let mut tx = state.pool.begin().await?;
+
+    sqlx::query(
+        "INSERT INTO devices (id, organization_id, registered_by, name, public_key) \
+         VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (id) DO NOTHING",
+    )
+    .bind(req.device_id)
+    .bind(ctx.organization_id)
+    .bind(ctx.user_id)
+    .bind(name)
+    .bind(req.public_key.0.as_slice())
+    .execute(&mut *tx)
+    .await?;
+
+    let mut device = sqlx::query_as::<_, DeviceRow>(
+        "SELECT id, organization_id, name, registered_by, public_key, created_at, revoked_at \
+         FROM devices WHERE id = $1",
+    )
+    .bind(req.device_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if device.organization_id != ctx.organization_id {
+        return Err(ApiError::conflict(format!(
+            "device {} is registered to another organization",
+            req.device_id
+        )));
+    }
+    if device.revoked_at.is_some() {
+        return Err(ApiError::forbidden(format!(
+            "device {} has been revoked",
+            req.device_id
+        )));
+    }
+    match &device.public_key {
+        Some(key) if key.as_slice() != req.public_key.0.as_slice() => {
+            return Err(ApiError::conflict(format!(
+                "device {} is registered with a different public key",
+                req.device_id
+            )));
+        }
+        Some(_) => {}
+        None => {
+            sqlx::query("UPDATE devices SET public_key = $1 WHERE id = $2")
+                .bind(req.public_key.0.as_slice())
+                .bind(device.id)
+                .execute(&mut *tx)
+                .await?;
+            device.public_key = Some(req.public_key.0.to_vec());
+        }
+    }
+
+    sqlx::query("UPDATE sessions SET device_id = $1 WHERE id = $2 AND device_id IS NULL")
+        .bind(device.id)
+        .bind(ctx.session_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(device.into_info()))
+}
+
+pub async fn list(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+) -> Result<Json<ListDevicesResponse>, ApiError> {
+    ctx.require(Permission::ManageDevices)?;
+
+    let devices = sqlx::query_as::<_, DeviceRow>(
+        "SELECT id, organization_id, name, registered_by, public_key, created_at, revoked_at \
+         FROM devices WHERE organization_id = $1 \
+         ORDER BY created_at, id",
+    )
+    .bind(ctx.organization_id)
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(ListDevicesResponse {
+        devices: devices.into_iter().map(DeviceRow::into_info).collect(),
+    }))
+}
+
+/// Revokes a device and every session bound to it.
+pub async fn revoke(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    device_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<DeviceInfo>, ApiError> {
+    ctx.require(Permission::ManageDevices)?;
+    let Path(device_id) = device_id?;
+
+    let mut tx = state.pool.begin().await?;
+    let device = revoke_device(&mut tx, ctx.organization_id, device_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("unknown device {device_id}")))?;
+    tx.commit().await?;
+
+    Ok(Json(device.into_info()))
+}
+
+/// Sets the part of the log a device replicates. The device notices the new
+/// revision on its next pull and backfills.
+pub async fn set_scope(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    device_id: Result<Path<Uuid>, PathRejection>,
+    payload: Result<Json<SyncScope>, JsonRejection>,
+) -> Result<Json<DeviceScope>, ApiError> {
+    ctx.require(Permission::ManageDevices)?;
+    let Path(device_id) = device_id?;
+    let Json(scope) = payload?;
+
+    let known = payload::registry().operations();
+    for entity_type in scope.entity_types.iter().flatten() {
+        if !known.iter().any(|(known, _, _)| known == entity_type) {
+            return Err(ApiError::bad_request(format!(
+                "unknown entity type {entity_type}"
+            )));
+        }
+    }
+
+    let revision: Option<i64> = sqlx::query_scalar(
+        "UPDATE devices \
+         SET scope_patients = $1, scope_entity_types = $2, scope_revision = scope_revision + 1 \
+         WHERE id = $3 AND organization_id = $4 \
+         RETURNING scope_revision",
+    )
+    .bind(&scope.patients)
+    .bind(&scope.entity_types)
+    .bind(device_id)
+    .bind(ctx.organization_id)
+    .fetch_optional(&state.pool)
+    .await?;
+    let revision =
+        revision.ok_or_else(|| ApiError::not_found(format!("unknown device {device_id}")))?;
+
+    Ok(Json(DeviceScope {
+        scope,
+        revision: revision as u64,
+    }))
+}
+
+/// The scope of the calling device.
+pub async fn own_scope(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+) -> Result<Json<DeviceScope>, ApiError> {
+    ctx.require(Permission::SyncPull)?;
+    Ok(Json(scope_of(&state.pool, ctx.require_device()?).await?))
+}
+
+/// The scope `device_id` replicates.
+pub(crate) async fn scope_of(pool: &PgPool, device_id: Uuid) -> Result<DeviceScope, ApiError> {
+    let row = sqlx::query_as::<_, ScopeRow>(
+        "SELECT scope_patients, scope_entity_types, scope_revision FROM devices WHERE id = $1",
+    )
+    .bind(device_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row
+        .map(|row| DeviceScope {
+            scope: SyncScope {
+                patients: row.scope_patients,
+                entity_types: row.scope_entity_types,
+            },
+            revision: row.scope_revision as u64,
+        })
+        .unwrap_or_default())
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ScopeRow {
+    scope_patients: Option<Vec<Uuid>>,
+    scope_entity_types: Option<Vec<String>>,
+    scope_revision: i64,
+}
+
+/// Marks the device revoked (keeping the original timestamp if it already
+/// was) and revokes its sessions. Returns `None` if the organization has no
+/// such device.
+pub async fn revoke_device(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    organization_id: Uuid,
+    device_id: Uuid,
+) -> Result<Option<DeviceRow>, sqlx::Error> {
+    let device = sqlx::query_as::<_, DeviceRow>(
+        "UPDATE devices SET revoked_at = COALESCE(revoked_at, now()) \
+         WHERE id = $1 AND organization_id = $2 \
+         RETURNING id, organization_id, name, registered_by, public_key, created_at, revoked_at",
+    )
+    .bind(device_id)
+    .bind(organization_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    if device.is_some() {
+        sqlx::query(
+            "UPDATE sessions SET revoked_at = now() \
+             WHERE device_id = $1 AND revoked_at IS NULL",
+        )
+        .bind(device_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok(device)
+}
+
+/// The key ops from `device_id` must be signed with, if the device has one.
+pub(crate) async fn verifying_key(
+    pool: &PgPool,
+    device_id: Uuid,
+) -> Result<Option<VerifyingKey>, ApiError> {
+    let key: Option<Option<Vec<u8>>> =
+        sqlx::query_scalar("SELECT public_key FROM devices WHERE id = $1")
+            .bind(device_id)
+            .fetch_optional(pool)
+            .await?;
+    key.flatten()
+        .map(|bytes| {
+            DevicePublicKey::try_from(bytes.as_slice())
+                .and_then(|key| key.verifying_key())
+                .map_err(|_| {
+                    ApiError::internal(format!("invalid stored key for device {device_id}"))
+                })
+        })
+        .transpose()
+}
+
+/// Checks that a device a user is logging in from may be used.
+pub(crate) async fn ensure_device_usable(
+    pool: &PgPool,
+    organization_id: Uuid,
+    device_id: Uuid,
+) -> Result<(), ApiError> {
+    let revoked_at: Option<Option<OffsetDateTime>> =
+        sqlx::query_scalar("SELECT revoked_at FROM devices WHERE id = $1 AND organization_id = $2")
+            .bind(device_id)
+            .bind(organization_id)
+            .fetch_optional(pool)
+            .await?;
+
+    match revoked_at {
+        None => Err(ApiError::not_found(format!(
+            "unknown device {device_id}; register it before binding a session"
+        ))),
+        Some(Some(_)) => Err(ApiError::forbidden(format!(
+            "device {device_id} has been revoked"
+        ))),
+        Some(None) => Ok(()),
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DeviceRow {
+    pub id: Uuid,
+    pub organization_id: Uuid,
+    pub name: String,
+    pub registered_by: Uuid,
+    pub public_key: Option<Vec<u8>>,
+    pub created_at: OffsetDateTime,
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+impl DeviceRow {
+    fn into_info(self) -> DeviceInfo {
+        DeviceInfo {
+            id: self.id,
+            name: self.name,
+            registered_by: self.registered_by,
+            public_key: self
+                .public_key
+                .and_then(|key| DevicePublicKey::try_from(key.as_slice()).ok()),
+            created_at: self.created_at,
+            revoked_at: self.revoked_at,
+        }
+    }
+}
@@ -3,5 +3,11 @@
 pub mod app;
 pub mod auth;
 pub mod db;
+pub mod devices;
 pub mod error;
+pub mod merkle;
+pub mod rbac;
+pub mod snapshot;
 pub mod state;
+pub mod sync;
+pub mod wire;
@@ -44,6 +44,7 @@ async fn run() -> Result<(), ServerError> {
         })?;
 
     let pool = medxz_server::db::connect_from_env_and_migrate().await?;
+    medxz_server::snapshot::spawn_refresher(pool.clone(), medxz_server::snapshot::REFRESH_INTERVAL);
     let app_state = medxz_server::state::AppState { pool };
     let app = medxz_server::app::router(app_state);
 
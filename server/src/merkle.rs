@@ -0,0 +1,103 @@
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use medxz_protocol::merkle::{bucket_of, MerkleNode, MerkleTree, NodeId};
+use medxz_protocol::{FetchRequest, FetchResponse, Hlc};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::AuthContext;
+use crate::devices;
+use crate::error::ApiError;
+use crate::rbac::{self, Permission};
+use crate::state::AppState;
+use crate::sync::{check_client_protocol, ops_by_id, MAX_PULL_LIMIT, VISIBLE_OPS};
+use crate::wire::{Accept, Wire};
+
+/// One node of the Merkle tree over the op ids the caller's device
+/// replicates. A device walks down from [`NodeId::ROOT`] into the children
+/// whose digests differ from its own, then fetches the ops it lacks from
+/// `/sync/fetch` and pushes the ones the server lacks.
+pub async fn node(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    headers: HeaderMap,
+    Accept(accept): Accept,
+    query: Result<Query<NodeId>, QueryRejection>,
+) -> Result<Wire<MerkleNode>, ApiError> {
+    ctx.require(Permission::SyncPull)?;
+    check_client_protocol(&headers)?;
+    let device_id = ctx.require_device()?;
+    let Query(node) = query?;
+    if !node.is_valid() {
+        return Err(ApiError::bad_request("no such merkle node"));
+    }
+
+    let (from_ms, to_ms) = node.wall_ms();
+    let to_ms = to_ms.map(i64::try_from).transpose();
+    let (Ok(from_ms), Ok(to_ms)) = (i64::try_from(from_ms), to_ms) else {
+        return Err(ApiError::bad_request("no such merkle node"));
+    };
+    let scope = devices::scope_of(&state.pool, device_id).await?;
+    // Ops stored before HLCs are placed by device time, as `bucket_of` does.
+    let rows: Vec<(Uuid, Option<String>, OffsetDateTime)> = sqlx::query_as(&format!(
+        "SELECT op_id, hlc, device_time FROM ( \
+           SELECT op_id, hlc, device_time, \
+                  COALESCE(left(hlc, 15)::bigint, \
+                           GREATEST(floor(extract(epoch FROM device_time) * 1000), 0)::bigint) \
+                    AS wall_ms \
+           FROM ops \
+           WHERE {VISIBLE_OPS} \
+         ) ops \
+         WHERE wall_ms >= $5 AND ($6::bigint IS NULL OR wall_ms < $6)"
+    ))
+    .bind(ctx.organization_id)
+    .bind(rbac::hidden_entity_types(ctx.user_role))
+    .bind(&scope.scope.entity_types)
+    .bind(&scope.scope.patients)
+    .bind(from_ms)
+    .bind(to_ms)
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut tree = MerkleTree::new();
+    for (op_id, hlc, device_time) in rows {
+        let hlc: Option<Hlc> = hlc
+            .map(|hlc| hlc.parse())
+            .transpose()
+            .map_err(|_| ApiError::internal("invalid stored op hlc"))?;
+        tree.insert(op_id, bucket_of(hlc.as_ref(), device_time));
+    }
+    Ok(Wire(accept.unwrap_or_default(), tree.node(node)))
+}
+
+/// The requested ops the caller may see, for repairing the gaps a Merkle
+/// comparison found.
+pub async fn fetch(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    headers: HeaderMap,
+    Accept(accept): Accept,
+    payload: Result<Wire<FetchRequest>, ApiError>,
+) -> Result<Wire<FetchResponse>, ApiError> {
+    ctx.require(Permission::SyncPull)?;
+    check_client_protocol(&headers)?;
+    let device_id = ctx.require_device()?;
+    let Wire(format, req) = payload?;
+    if req.op_ids.len() > MAX_PULL_LIMIT as usize {
+        return Err(ApiError::bad_request(format!(
+            "at most {MAX_PULL_LIMIT} ops can be fetched at once"
+        )));
+    }
+
+    let scope = devices::scope_of(&state.pool, device_id).await?;
+    let ops = ops_by_id(
+        &state.pool,
+        ctx.organization_id,
+        &req.op_ids,
+        &rbac::hidden_entity_types(ctx.user_role),
+        &scope.scope,
+    )
+    .await?;
+    Ok(Wire(accept.unwrap_or(format), FetchResponse { ops }))
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Clinician,
+    FrontDesk,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unknown role: {0} (expected one of admin, clinician, front_desk)")]
+pub struct UnknownRole(pub String);
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Admin, Role::Clinician, Role::FrontDesk];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Clinician => "clinician",
+            Role::FrontDesk => "front_desk",
+        }
+    }
+
+    /// The permission matrix. Every route and every restricted entity type
+    /// is checked against this.
+    pub fn allows(self, permission: Permission) -> bool {
+        use Permission::*;
+
+        match self {
+            Role::Admin => matches!(
+                permission,
+                SyncPush
+                    | SyncPull
+                    | RegisterDevice
+                    | ManageDevices
+                    | ReadClinicalNotes
+                    | ReadClinicalRecords
+            ),
+            Role::Clinician => matches!(
+                permission,
+                SyncPush
+                    | SyncPull
+                    | RegisterDevice
+                    | ReadClinicalNotes
+                    | WriteClinicalNotes
+                    | ReadClinicalRecords
+                    | WriteClinicalRecords
+            ),
+            Role::FrontDesk => matches!(permission, SyncPush | SyncPull | RegisterDevice),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = UnknownRole;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| UnknownRole(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    SyncPush,
+    SyncPull,
+    RegisterDevice,
+    ManageDevices,
+    ReadClinicalNotes,
+    WriteClinicalNotes,
+    /// Observations, conditions and medication orders.
+    ReadClinicalRecords,
+    WriteClinicalRecords,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::SyncPush => "sync_push",
+            Permission::SyncPull => "sync_pull",
+            Permission::RegisterDevice => "register_device",
+            Permission::ManageDevices => "manage_devices",
+            Permission::ReadClinicalNotes => "read_clinical_notes",
+            Permission::WriteClinicalNotes => "write_clinical_notes",
+            Permission::ReadClinicalRecords => "read_clinical_records",
+            Permission::WriteClinicalRecords => "write_clinical_records",
+        }
+    }
+}
+
+/// Entity types whose ops need a permission beyond sync access, as
+/// `(entity_type, read, write)`.
+/// Patients and encounters are deliberately absent: the front desk registers
+/// patients and checks them in.
+const RESTRICTED_ENTITY_TYPES: &[(&str, Permission, Permission)] = &[
+    (
+        "ClinicalNote",
+        Permission::ReadClinicalNotes,
+        Permission::WriteClinicalNotes,
+    ),
+    (
+        "Observation",
+        Permission::ReadClinicalRecords,
+        Permission::WriteClinicalRecords,
+    ),
+    (
+        "Condition",
+        Permission::ReadClinicalRecords,
+        Permission::WriteClinicalRecords,
+    ),
+    (
+        "MedicationRequest",
+        Permission::ReadClinicalRecords,
+        Permission::WriteClinicalRecords,
+    ),
+];
+
+/// The permission needed to push ops for `entity_type`, if any.
+pub fn write_permission(entity_type: &str) -> Option<Permission> {
+    RESTRICTED_ENTITY_TYPES
+        .iter()
+        .find(|(restricted, _, _)| *restricted == entity_type)
+        .map(|(_, _, write)| *write)
+}
+
+/// Entity types whose ops must be filtered out of anything sent to `role`.
+pub fn hidden_entity_types(role: Role) -> Vec<String> {
+    RESTRICTED_ENTITY_TYPES
+        .iter()
+        .filter(|(_, read, _)| !role.allows(*read))
+        .map(|(entity_type, _, _)| entity_type.to_string())
+        .collect()
+}
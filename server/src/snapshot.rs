@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use medxz_protocol::projection::Projection;
+use medxz_protocol::{Cursor, Snapshot, SyncScope};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::AuthContext;
+use crate::devices;
+use crate::error::ApiError;
+use crate::rbac::{self, Permission};
+use crate::state::AppState;
+use crate::sync::{check_client_protocol, cursor_from_db, ops_after};
+use crate::wire::{Accept, Wire};
+
+/// A new snapshot is built once this many ops arrived after the latest one.
+pub const SNAPSHOT_EVERY_OPS: u64 = 1000;
+
+/// How often [`spawn_refresher`] looks for organizations due a snapshot.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Ops folded per query while building a snapshot.
+const FOLD_PAGE: i64 = 1000;
+
+/// The latest snapshot of the caller's organization, narrowed to what the
+/// caller may read and the device replicates. A device loads it, then pulls
+/// from its `cursor`.
+pub async fn latest(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    headers: HeaderMap,
+    Accept(accept): Accept,
+) -> Result<Wire<Snapshot>, ApiError> {
+    ctx.require(Permission::SyncPull)?;
+    check_client_protocol(&headers)?;
+    let device_id = ctx.require_device()?;
+
+    let mut snapshot = load_latest(&state.pool, ctx.organization_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("no snapshot yet; pull from the start of the log"))?;
+    for entity_type in rbac::hidden_entity_types(ctx.user_role) {
+        snapshot.projection.remove_entity_type(&entity_type);
+    }
+    let scope = devices::scope_of(&state.pool, device_id).await?;
+    snapshot.projection.restrict(&scope.scope);
+    Ok(Wire(accept.unwrap_or_default(), snapshot))
+}
+
+/// Folds the ops stored after the organization's latest snapshot into a new
+/// one, if at least `min_new_ops` of them arrived. Returns the new snapshot's
+/// cursor.
+///
+/// Reads the log like `/sync/pull` does, so the snapshot covers exactly the
+/// ops up to its cursor even while devices push.
+pub async fn refresh(
+    pool: &PgPool,
+    organization_id: Uuid,
+    min_new_ops: u64,
+) -> Result<Option<Cursor>, ApiError> {
+    let latest = load_latest(pool, organization_id).await?;
+    let (mut after, mut op_count, mut projection) = match latest {
+        Some(snapshot) => (
+            i64::try_from(snapshot.cursor.0)
+                .map_err(|_| ApiError::internal("invalid snapshot cursor"))?,
+            snapshot.op_count,
+            snapshot.projection,
+        ),
+        None => (0, 0, Projection::new()),
+    };
+
+    let pending: i64 = sqlx::query_scalar(
+        "SELECT count(*) FROM ops WHERE organization_id = $1 AND server_seq > $2",
+    )
+    .bind(organization_id)
+    .bind(after)
+    .fetch_one(pool)
+    .await?;
+    if pending == 0 || (pending as u64) < min_new_ops {
+        return Ok(None);
+    }
+
+    loop {
+        let page = ops_after(
+            pool,
+            organization_id,
+            after,
+            &[],
+            &SyncScope::default(),
+            FOLD_PAGE,
+        )
+        .await?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = *last;
+        for (_, op) in &page {
+            // Push rejects undecodable ops, so only ops stored before payloads
+            // were checked can fail here; devices cannot apply them either.
+            if let Err(err) = projection.apply(op) {
+                tracing::warn!(op_id = %op.op_id, error = %err, "op left out of snapshot");
+            }
+            op_count += 1;
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO snapshots (organization_id, server_seq, op_count, projection) \
+         VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (organization_id, server_seq) DO NOTHING",
+    )
+    .bind(organization_id)
+    .bind(after)
+    .bind(op_count as i64)
+    .bind(sqlx::types::Json(&projection))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM snapshots WHERE organization_id = $1 AND server_seq < $2")
+        .bind(organization_id)
+        .bind(after)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    cursor_from_db(after).map(Some)
+}
+
+/// Refreshes the snapshot of every organization due one.
+pub async fn refresh_all(pool: &PgPool, min_new_ops: u64) -> Result<(), ApiError> {
+    let organizations: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM organizations")
+        .fetch_all(pool)
+        .await?;
+    for organization_id in organizations {
+        if let Some(cursor) = refresh(pool, organization_id, min_new_ops).await? {
+            tracing::info!(%organization_id, cursor = cursor.0, "snapshot refreshed");
+        }
+    }
+    Ok(())
+}
+
+/// Refreshes snapshots every `every` for as long as the server runs.
+pub fn spawn_refresher(pool: PgPool, every: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(err) = refresh_all(&pool, SNAPSHOT_EVERY_OPS).await {
+                tracing::warn!(error = ?err, "snapshot refresh failed");
+            }
+        }
+    })
+}
+
+async fn load_latest(pool: &PgPool, organization_id: Uuid) -> Result<Option<Snapshot>, ApiError> {
+    let row: Option<(i64, i64, sqlx::types::Json<Projection>, OffsetDateTime)> = sqlx::query_as(
+        "SELECT server_seq, op_count, projection, created_at FROM snapshots \
+         WHERE organization_id = $1 \
+         ORDER BY server_seq DESC \
+         LIMIT 1",
+    )
+    .bind(organization_id)
+    .fetch_optional(pool)
+    .await?;
+    row.map(|(server_seq, op_count, projection, created_at)| {
+        Ok(Snapshot {
+            cursor: cursor_from_db(server_seq)?,
+            op_count: op_count as u64,
+            created_at,
+            projection: projection.0,
+        })
+    })
+    .transpose()
+}
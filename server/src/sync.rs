@@ -0,0 +1,581 @@
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
+use medxz_protocol::encoding::InvalidEncoding;
+use medxz_protocol::payload::Payload;
+use medxz_protocol::signing::VerifyingKey;
+use medxz_protocol::wire::WireFormat;
+use medxz_protocol::{
+    payload, Capabilities, Cursor, EntityRef, Hlc, OpHash, Operation, ProvenanceField, PullRequest,
+    PullResponse, PushOutcome, PushRequest, PushResponse, PushResult, RejectReason, SyncLimits,
+    SyncScope, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+};
+use sqlx::{PgPool, Postgres, Transaction};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::AuthContext;
+use crate::devices;
+use crate::error::ApiError;
+use crate::rbac::{self, Permission};
+use crate::state::AppState;
+use crate::wire::{Accept, Wire};
+
+/// Upper bound on the number of ops accepted in a single push.
+pub const MAX_PUSH_OPS: usize = 500;
+
+/// Page size used when `/sync/pull` is called without a `limit`.
+pub const DEFAULT_PULL_LIMIT: u32 = 100;
+
+/// Upper bound on the page size accepted by `/sync/pull`.
+pub const MAX_PULL_LIMIT: u32 = 1000;
+
+/// Oldest client protocol version the sync endpoints still serve.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// `Content-Encoding`s the sync routes decompress and compress with; see
+/// [`crate::app`].
+pub const CONTENT_ENCODINGS: [&str; 2] = ["gzip", "zstd"];
+
+/// What a client needs to know before it syncs. Public so that a build too
+/// old to sync can find out before signing in.
+pub async fn capabilities() -> Json<Capabilities> {
+    Json(Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        operations: payload::registry().supported_operations(),
+        limits: SyncLimits {
+            max_push_ops: MAX_PUSH_OPS as u32,
+            default_pull_limit: DEFAULT_PULL_LIMIT,
+            max_pull_limit: MAX_PULL_LIMIT,
+        },
+        formats: WireFormat::ALL
+            .iter()
+            .map(|format| format.content_type().to_owned())
+            .collect(),
+        content_encodings: CONTENT_ENCODINGS.map(str::to_owned).to_vec(),
+    })
+}
+
+/// Refuses clients that announce a protocol version below
+/// [`MIN_PROTOCOL_VERSION`]. Clients that predate the header are served.
+pub(crate) fn check_client_protocol(headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(value) = headers.get(PROTOCOL_VERSION_HEADER) else {
+        return Ok(());
+    };
+    let version: u32 = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| ApiError::bad_request(format!("invalid {PROTOCOL_VERSION_HEADER}")))?;
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(ApiError::upgrade_required(format!(
+            "protocol version {version} is no longer supported; \
+             update to a build speaking version {MIN_PROTOCOL_VERSION} or later"
+        )));
+    }
+    Ok(())
+}
+
+pub async fn push(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    headers: HeaderMap,
+    Accept(accept): Accept,
+    payload: Result<Wire<PushRequest>, ApiError>,
+) -> Result<Wire<PushResponse>, ApiError> {
+    ctx.require(Permission::SyncPush)?;
+    check_client_protocol(&headers)?;
+    let Wire(format, req) = payload?;
+
+    if req.ops.len() > MAX_PUSH_OPS {
+        return Err(ApiError::bad_request(format!(
+            "push batch of {} ops exceeds the limit of {MAX_PUSH_OPS}",
+            req.ops.len()
+        )));
+    }
+
+    let device_key = match ctx.device_id {
+        Some(device_id) => devices::verifying_key(&state.pool, device_id).await?,
+        None => None,
+    };
+
+    let mut tx = state.pool.begin().await?;
+    lock_organization_log(&mut tx, ctx.organization_id).await?;
+
+    let mut results = Vec::with_capacity(req.ops.len());
+    for op in &req.ops {
+        let outcome = match check_op(&ctx, device_key.as_ref(), op) {
+            Err(rejected) => rejected,
+            Ok(payload) => store_op(&mut tx, ctx.organization_id, op, &payload).await?,
+        };
+        results.push(PushResult {
+            op_id: op.op_id,
+            outcome,
+        });
+    }
+
+    tx.commit().await?;
+
+    Ok(Wire(accept.unwrap_or(format), PushResponse { results }))
+}
+
+pub async fn pull(
+    State(state): State<AppState>,
+    ctx: AuthContext,
+    headers: HeaderMap,
+    Accept(accept): Accept,
+    query: Result<Query<PullRequest>, QueryRejection>,
+) -> Result<Wire<PullResponse>, ApiError> {
+    ctx.require(Permission::SyncPull)?;
+    check_client_protocol(&headers)?;
+    let device_id = ctx.require_device()?;
+    let Query(req) = query?;
+
+    let limit = req.limit.unwrap_or(DEFAULT_PULL_LIMIT);
+    if limit == 0 || limit > MAX_PULL_LIMIT {
+        return Err(ApiError::bad_request(format!(
+            "limit must be between 1 and {MAX_PULL_LIMIT}"
+        )));
+    }
+
+    let after = match req.cursor {
+        Some(Cursor(n)) => {
+            i64::try_from(n).map_err(|_| ApiError::bad_request("cursor is out of range"))?
+        }
+        None => 0,
+    };
+
+    let scope = devices::scope_of(&state.pool, device_id).await?;
+    let mut rows = ops_after(
+        &state.pool,
+        ctx.organization_id,
+        after,
+        &rbac::hidden_entity_types(ctx.user_role),
+        &scope.scope,
+        i64::from(limit) + 1,
+    )
+    .await?;
+
+    let has_more = rows.len() > limit as usize;
+    rows.truncate(limit as usize);
+
+    let next_cursor = match rows.last() {
+        Some((server_seq, _)) => Some(cursor_from_db(*server_seq)?),
+        None => req.cursor,
+    };
+    let ops = rows.into_iter().map(|(_, op)| op).collect();
+
+    Ok(Wire(
+        accept.unwrap_or_default(),
+        PullResponse {
+            ops,
+            next_cursor,
+            has_more,
+            scope_revision: scope.revision,
+        },
+    ))
+}
+
+/// Checks everything about `op` that can be decided without the log and
+/// returns its decoded payload. `device_key` is the registered key of the
+/// session's device.
+fn check_op(
+    ctx: &AuthContext,
+    device_key: Option<&VerifyingKey>,
+    op: &Operation,
+) -> Result<Payload, PushOutcome> {
+    op.validate().map_err(|error| PushOutcome::Rejected {
+        reason: RejectReason::InvalidOperation { error },
+        message: error.to_string(),
+    })?;
+
+    let mismatch = if op.clinic_id != ctx.organization_id {
+        Some((
+            ProvenanceField::ClinicId,
+            "clinic_id does not match the session",
+        ))
+    } else if op.user_id != ctx.user_id {
+        Some((
+            ProvenanceField::UserId,
+            "user_id does not match the session",
+        ))
+    } else if ctx.device_id != Some(op.device_id) {
+        Some((
+            ProvenanceField::DeviceId,
+            "device_id does not match the session",
+        ))
+    } else {
+        None
+    };
+    if let Some((field, message)) = mismatch {
+        return Err(PushOutcome::Rejected {
+            reason: RejectReason::ProvenanceMismatch { field },
+            message: message.into(),
+        });
+    }
+
+    if let Some(permission) = rbac::write_permission(&op.entity.entity_type) {
+        if !ctx.user_role.allows(permission) {
+            return Err(PushOutcome::Rejected {
+                reason: RejectReason::PermissionDenied,
+                message: format!(
+                    "role {} may not write {} ops",
+                    ctx.user_role, op.entity.entity_type
+                ),
+            });
+        }
+    }
+
+    let payload = op.decode_payload().map_err(|err| {
+        let reason = if err.is_unsupported() {
+            RejectReason::UnsupportedOperation
+        } else {
+            RejectReason::InvalidPayload
+        };
+        PushOutcome::Rejected {
+            reason,
+            message: err.to_string(),
+        }
+    })?;
+
+    if !op.verify_hash() {
+        return Err(PushOutcome::Rejected {
+            reason: RejectReason::HashMismatch,
+            message: "hash is missing or does not match the op contents".into(),
+        });
+    }
+
+    if !device_key.is_some_and(|key| op.verify_signature(key)) {
+        return Err(PushOutcome::Rejected {
+            reason: RejectReason::InvalidSignature,
+            message: "signature is missing or was not made with the device's registered key".into(),
+        });
+    }
+
+    Ok(payload)
+}
+
+/// Appends a checked `op` to the log unless it was already stored or does not
+/// extend its device's hash chain. `op_id`s are unique across organizations,
+/// so an `op_id` another organization stored is rejected rather than
+/// reported as a duplicate the device would drop.
+async fn store_op(
+    tx: &mut Transaction<'_, Postgres>,
+    organization_id: Uuid,
+    op: &Operation,
+    payload: &Payload,
+) -> Result<PushOutcome, ApiError> {
+    let stored_by: Option<Uuid> =
+        sqlx::query_scalar("SELECT organization_id FROM ops WHERE op_id = $1")
+            .bind(op.op_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+    match stored_by {
+        Some(stored_by) if stored_by == organization_id => return Ok(PushOutcome::Duplicate),
+        Some(_) => return Ok(op_id_taken()),
+        None => {}
+    }
+
+    if let Some(message) = chain_problem(tx, op).await? {
+        return Ok(PushOutcome::Rejected {
+            reason: RejectReason::ChainBroken,
+            message,
+        });
+    }
+
+    // The organization lock rules out a concurrent push of the same op from
+    // this organization, so a conflict here is another organization's op.
+    let Some(server_seq) = insert_op(tx, organization_id, op).await? else {
+        return Ok(op_id_taken());
+    };
+    if let Some(patient_id) = payload.patient(op.entity.entity_id) {
+        sqlx::query(
+            "INSERT INTO entity_patients (organization_id, entity_type, entity_id, patient_id) \
+             VALUES ($1, $2, $3, $4) \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(organization_id)
+        .bind(&op.entity.entity_type)
+        .bind(op.entity.entity_id)
+        .bind(patient_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(PushOutcome::Accepted {
+        cursor: cursor_from_db(server_seq)?,
+    })
+}
+
+fn op_id_taken() -> PushOutcome {
+    PushOutcome::Rejected {
+        reason: RejectReason::OpIdTaken,
+        message: "op_id is already used by another organization".into(),
+    }
+}
+
+/// Checks that `op` directly follows the stored op with the previous
+/// `device_seq` from its device, and that its HLC moved past that op's. The
+/// organization lock held by the caller keeps the answer valid until commit.
+async fn chain_problem(
+    tx: &mut Transaction<'_, Postgres>,
+    op: &Operation,
+) -> Result<Option<String>, ApiError> {
+    let device_seq = op.device_seq as i64;
+    let neighbours: Vec<(i64, Option<Vec<u8>>, Option<String>)> = sqlx::query_as(
+        "SELECT device_seq, hash, hlc FROM ops \
+         WHERE device_id = $1 AND device_seq IN ($2 - 1, $2)",
+    )
+    .bind(op.device_id)
+    .bind(device_seq)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    if neighbours.iter().any(|(seq, _, _)| *seq == device_seq) {
+        return Ok(Some(format!(
+            "device_seq {device_seq} was already used by another op from this device"
+        )));
+    }
+    if device_seq == 1 {
+        return Ok(op
+            .prev_hash
+            .is_some()
+            .then(|| "a device's first op must not have a prev_hash".into()));
+    }
+
+    let Some((_, previous, previous_hlc)) = neighbours.into_iter().next() else {
+        return Ok(Some(format!(
+            "op {} from this device has not been received",
+            device_seq - 1
+        )));
+    };
+    let previous = previous
+        .map(|bytes| {
+            OpHash::try_from(bytes.as_slice())
+                .map_err(|_| ApiError::internal("invalid stored op hash"))
+        })
+        .transpose()?;
+    if op.prev_hash != previous {
+        return Ok(Some(format!(
+            "prev_hash does not match the hash of op {} from this device",
+            device_seq - 1
+        )));
+    }
+
+    let previous_hlc: Option<Hlc> = previous_hlc
+        .map(|hlc| hlc.parse())
+        .transpose()
+        .map_err(|_| ApiError::internal("invalid stored op hlc"))?;
+    if previous_hlc.is_some_and(|previous| op.hlc <= Some(previous)) {
+        return Ok(Some(format!(
+            "hlc does not advance past the hlc of op {} from this device",
+            device_seq - 1
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Serializes writers to one organization's log for the rest of the transaction.
+///
+/// `server_seq` values are handed out at insert time but only become visible at
+/// commit. Holding this lock until commit guarantees that sequences become
+/// visible in order, so a reader paging by cursor can never observe seq N+1
+/// before seq N and skip it.
+async fn lock_organization_log(
+    tx: &mut Transaction<'_, Postgres>,
+    organization_id: Uuid,
+) -> Result<(), ApiError> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1::text, 0))")
+        .bind(organization_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Appends a validated `op` to the log and returns its `server_seq`, or `None`
+/// if an op with the same `op_id` was already stored.
+async fn insert_op(
+    tx: &mut Transaction<'_, Postgres>,
+    organization_id: Uuid,
+    op: &Operation,
+) -> Result<Option<i64>, ApiError> {
+    let server_seq: Option<i64> = sqlx::query_scalar(
+        "INSERT INTO ops \
+         (op_id, organization_id, clinic_id, device_id, user_id, entity_type, entity_id, \
+          op_type, device_time, device_seq, hlc, schema_version, payload, prev_hash, hash, \
+          signature) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) \
+         ON CONFLICT (op_id) DO NOTHING \
+         RETURNING server_seq",
+    )
+    .bind(op.op_id)
+    .bind(organization_id)
+    .bind(op.clinic_id)
+    .bind(op.device_id)
+    .bind(op.user_id)
+    .bind(&op.entity.entity_type)
+    .bind(op.entity.entity_id)
+    .bind(&op.op_type)
+    .bind(op.device_time)
+    .bind(op.device_seq as i64)
+    .bind(op.hlc.map(|hlc| hlc.to_string()))
+    .bind(op.schema_version as i32)
+    .bind(sqlx::types::Json(&op.payload))
+    .bind(op.prev_hash.map(|hash| hash.0.to_vec()))
+    .bind(op.hash.map(|hash| hash.0.to_vec()))
+    .bind(op.signature.map(|signature| signature.0.to_vec()))
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(server_seq)
+}
+
+/// The columns [`OpRow`] reads.
+const OP_COLUMNS: &str = "server_seq, op_id, clinic_id, device_id, user_id, entity_type, \
+                          entity_id, op_type, device_time, device_seq, hlc, schema_version, \
+                          payload, prev_hash, hash, signature";
+
+/// Narrows `ops` to what a caller may see: `$1` is the organization, `$2`
+/// the entity types hidden from its role, `$3` and `$4` the entity types and
+/// patients of its device's scope.
+pub(crate) const VISIBLE_OPS: &str = "ops.organization_id = $1 \
+     AND NOT (ops.entity_type = ANY($2)) \
+     AND ($3::text[] IS NULL OR ops.entity_type = ANY($3)) \
+     AND ($4::uuid[] IS NULL OR EXISTS ( \
+           SELECT 1 FROM entity_patients p \
+           WHERE p.organization_id = ops.organization_id \
+             AND p.entity_type = ops.entity_type \
+             AND p.entity_id = ops.entity_id \
+             AND p.patient_id = ANY($4)))";
+
+/// Up to `limit` of the organization's ops after `after` that fall in `scope`,
+/// in server order and with their `server_seq`, leaving out the `hidden`
+/// entity types.
+pub(crate) async fn ops_after(
+    pool: &PgPool,
+    organization_id: Uuid,
+    after: i64,
+    hidden: &[String],
+    scope: &SyncScope,
+    limit: i64,
+) -> Result<Vec<(i64, Operation)>, ApiError> {
+    let rows = sqlx::query_as::<_, OpRow>(&format!(
+        "SELECT {OP_COLUMNS} FROM ops \
+         WHERE {VISIBLE_OPS} AND server_seq > $5 \
+         ORDER BY server_seq \
+         LIMIT $6"
+    ))
+    .bind(organization_id)
+    .bind(hidden)
+    .bind(&scope.entity_types)
+    .bind(&scope.patients)
+    .bind(after)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| Ok((row.server_seq, row.into_operation()?)))
+        .collect()
+}
+
+/// The ops among `op_ids` that fall in `scope`, in server order, leaving out
+/// the `hidden` entity types.
+pub(crate) async fn ops_by_id(
+    pool: &PgPool,
+    organization_id: Uuid,
+    op_ids: &[Uuid],
+    hidden: &[String],
+    scope: &SyncScope,
+) -> Result<Vec<Operation>, ApiError> {
+    let rows = sqlx::query_as::<_, OpRow>(&format!(
+        "SELECT {OP_COLUMNS} FROM ops \
+         WHERE {VISIBLE_OPS} AND op_id = ANY($5) \
+         ORDER BY server_seq"
+    ))
+    .bind(organization_id)
+    .bind(hidden)
+    .bind(&scope.entity_types)
+    .bind(&scope.patients)
+    .bind(op_ids)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter().map(OpRow::into_operation).collect()
+}
+
+pub(crate) fn cursor_from_db(server_seq: i64) -> Result<Cursor, ApiError> {
+    u64::try_from(server_seq)
+        .map(Cursor)
+        .map_err(|_| ApiError::internal(format!("invalid server_seq {server_seq}")))
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct OpRow {
+    server_seq: i64,
+    op_id: Uuid,
+    clinic_id: Uuid,
+    device_id: Uuid,
+    user_id: Uuid,
+    entity_type: String,
+    entity_id: Uuid,
+    op_type: String,
+    device_time: OffsetDateTime,
+    device_seq: i64,
+    hlc: Option<String>,
+    schema_version: i32,
+    payload: sqlx::types::Json<serde_json::Value>,
+    prev_hash: Option<Vec<u8>>,
+    hash: Option<Vec<u8>>,
+    signature: Option<Vec<u8>>,
+}
+
+impl OpRow {
+    fn into_operation(self) -> Result<Operation, ApiError> {
+        let device_seq = u64::try_from(self.device_seq).map_err(|_| {
+            ApiError::internal(format!("invalid stored device_seq for op {}", self.op_id))
+        })?;
+        let schema_version = u32::try_from(self.schema_version).map_err(|_| {
+            ApiError::internal(format!(
+                "invalid stored schema_version for op {}",
+                self.op_id
+            ))
+        })?;
+        let invalid = |column: &str| {
+            ApiError::internal(format!("invalid stored {column} for op {}", self.op_id))
+        };
+        let hlc = self
+            .hlc
+            .map(|hlc| hlc.parse())
+            .transpose()
+            .map_err(|_| invalid("hlc"))?;
+        let prev_hash = stored_bytes(self.prev_hash).map_err(|_| invalid("prev_hash"))?;
+        let hash = stored_bytes(self.hash).map_err(|_| invalid("hash"))?;
+        let signature = stored_bytes(self.signature).map_err(|_| invalid("signature"))?;
+        Ok(Operation {
+            op_id: self.op_id,
+            clinic_id: self.clinic_id,
+            device_id: self.device_id,
+            user_id: self.user_id,
+            entity: EntityRef {
+                entity_type: self.entity_type,
+                entity_id: self.entity_id,
+            },
+            op_type: self.op_type,
+            device_time: self.device_time,
+            device_seq,
+            hlc,
+            schema_version,
+            payload: self.payload.0,
+            prev_hash,
+            hash,
+            signature,
+        })
+    }
+}
+
+fn stored_bytes<T>(bytes: Option<Vec<u8>>) -> Result<Option<T>, InvalidEncoding>
+where
+    T: for<'a> TryFrom<&'a [u8], Error = InvalidEncoding>,
+{
+    bytes.map(|bytes| T::try_from(bytes.as_slice())).transpose()
+}
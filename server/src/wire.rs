@@ -0,0 +1,95 @@
+//! Content negotiation for sync bodies.
+//!
+//! Requests are decoded by their `Content-Type`; responses are encoded in the
+//! format the client `Accept`s. Compression is not handled here but by the
+//! `Content-Encoding` layers in [`crate::app`].
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
+use medxz_protocol::wire::WireFormat;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// A body in one of the [`WireFormat`]s: the format it arrived in when
+/// extracted, the format to send it in when returned.
+#[derive(Debug)]
+pub struct Wire<T>(pub WireFormat, pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Wire<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, ApiError> {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let format = WireFormat::from_content_type(content_type).ok_or_else(|| {
+            ApiError::unsupported_media_type(format!(
+                "expected a Content-Type of {} or {}",
+                WireFormat::Json.content_type(),
+                WireFormat::Cbor.content_type()
+            ))
+        })?;
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| ApiError::bad_request(format!("invalid body: {e}")))?;
+        let value = format
+            .decode(&bytes)
+            .map_err(|e| ApiError::bad_request(e.to_string()))?;
+        Ok(Wire(format, value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Wire<T> {
+    fn into_response(self) -> Response {
+        let Wire(format, value) = self;
+        match format.encode(&value) {
+            Ok(bytes) => (
+                [(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(format.content_type()),
+                )],
+                bytes,
+            )
+                .into_response(),
+            Err(e) => ApiError::internal(e.to_string()).into_response(),
+        }
+    }
+}
+
+/// The response format named by the `Accept` header, if it names one of
+/// ours. Anything else falls back to the caller's default rather than a 406,
+/// so errors and old clients keep getting JSON.
+#[derive(Debug, Clone, Copy)]
+pub struct Accept(pub Option<WireFormat>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Accept
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Accept(
+            parts
+                .headers
+                .get(ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .and_then(WireFormat::from_accept),
+        ))
+    }
+}
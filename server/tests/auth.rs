@@ -1,10 +1,10 @@
+mod common;
+
 use axum::body::Body;
 use axum::http::{header, Request, StatusCode};
+use common::{body_json, TestDb};
 use serde_json::json;
-use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
 use tower::ServiceExt;
-use uuid::Uuid;
 
 #[tokio::test]
 async fn login_me_logout_happy_path() {
@@ -219,99 +219,3 @@ async fn me_requires_bearer_token() {
 
     assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 }
-
-struct TestDb {
-    pool: PgPool,
-}
-
-impl TestDb {
-    async fn new() -> Option<Self> {
-        let database_url = match std::env::var("DATABASE_URL") {
-            Ok(v) => v,
-            Err(_) => {
-                eprintln!("DATABASE_URL is not set; skipping Postgres-backed auth tests");
-                return None;
-            }
-        };
-        let schema = format!("test_{}", Uuid::now_v7().as_simple());
-
-        let admin_pool = PgPoolOptions::new()
-            .max_connections(1)
-            .connect(&database_url)
-            .await
-            .expect("failed to connect to DATABASE_URL");
-
-        sqlx::query(&format!("CREATE SCHEMA \"{schema}\""))
-            .execute(&admin_pool)
-            .await
-            .expect("failed to create test schema");
-
-        let schema_clone = schema.clone();
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .after_connect(move |conn, _meta| {
-                let schema = schema_clone.clone();
-                Box::pin(async move {
-                    sqlx::query(&format!("SET search_path TO \"{schema}\""))
-                        .execute(conn)
-                        .await?;
-                    Ok(())
-                })
-            })
-            .connect(&database_url)
-            .await
-            .expect("failed to connect to DATABASE_URL");
-
-        medxz_server::db::migrate(&pool)
-            .await
-            .expect("failed to run migrations");
-
-        Some(Self { pool })
-    }
-
-    fn router(&self) -> axum::Router {
-        medxz_server::app::router(medxz_server::state::AppState {
-            pool: self.pool.clone(),
-        })
-    }
-
-    async fn seed_org_and_user(
-        &self,
-        org_code: &str,
-        org_name: &str,
-        email: &str,
-        password: &str,
-        role: &str,
-    ) {
-        let org_id = Uuid::now_v7();
-        sqlx::query("INSERT INTO organizations (id, code, name) VALUES ($1, $2, $3)")
-            .bind(org_id)
-            .bind(org_code)
-            .bind(org_name)
-            .execute(&self.pool)
-            .await
-            .expect("failed to seed organization");
-
-        let password_hash =
-            medxz_server::auth::hash_password(password).expect("hash_password failed");
-        let user_id = Uuid::now_v7();
-        sqlx::query(
-            "INSERT INTO users (id, organization_id, email, password_hash, role) VALUES ($1, $2, $3, $4, $5)",
-        )
-        .bind(user_id)
-        .bind(org_id)
-        .bind(email.trim().to_ascii_lowercase())
-        .bind(password_hash)
-        .bind(role)
-        .execute(&self.pool)
-        .await
-        .expect("failed to seed user");
-    }
-}
-
-async fn body_json(response: axum::response::Response) -> serde_json::Value {
-    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
-        .await
-        .unwrap();
-    serde_json::from_slice(bytes.as_ref()).unwrap()
-}
@@ -0,0 +1,366 @@
+#![allow(dead_code)]
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use medxz_protocol::payload::OpPayload;
+use medxz_protocol::signing::SigningKey;
+use medxz_protocol::{
+    Cursor, DevicePublicKey, EntityRef, Hlc, Operation, PullResponse, PushRequest, PushResponse,
+    PushResult,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+pub struct TestDb {
+    pub pool: PgPool,
+}
+
+impl TestDb {
+    pub async fn new() -> Option<Self> {
+        let database_url = match std::env::var("DATABASE_URL") {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("DATABASE_URL is not set; skipping Postgres-backed tests");
+                return None;
+            }
+        };
+        let schema = format!("test_{}", Uuid::now_v7().as_simple());
+
+        let admin_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&database_url)
+            .await
+            .expect("failed to connect to DATABASE_URL");
+
+        sqlx::query(&format!("CREATE SCHEMA \"{schema}\""))
+            .execute(&admin_pool)
+            .await
+            .expect("failed to create test schema");
+
+        let schema_clone = schema.clone();
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .after_connect(move |conn, _meta| {
+                let schema = schema_clone.clone();
+                Box::pin(async move {
+                    sqlx::query(&format!("SET search_path TO \"{schema}\""))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                })
+            })
+            .connect(&database_url)
+            .await
+            .expect("failed to connect to DATABASE_URL");
+
+        medxz_server::db::migrate(&pool)
+            .await
+            .expect("failed to run migrations");
+
+        Some(Self { pool })
+    }
+
+    pub fn router(&self) -> axum::Router {
+        medxz_server::app::router(medxz_server::state::AppState {
+            pool: self.pool.clone(),
+        })
+    }
+
+    pub async fn seed_org_and_user(
+        &self,
+        org_code: &str,
+        org_name: &str,
+        email: &str,
+        password: &str,
+        role: &str,
+    ) -> SeededUser {
+        let org_id = Uuid::now_v7();
+        sqlx::query("INSERT INTO organizations (id, code, name) VALUES ($1, $2, $3)")
+            .bind(org_id)
+            .bind(org_code)
+            .bind(org_name)
+            .execute(&self.pool)
+            .await
+            .expect("failed to seed organization");
+
+        self.seed_user(org_id, email, password, role).await
+    }
+
+    pub async fn seed_user(
+        &self,
+        org_id: Uuid,
+        email: &str,
+        password: &str,
+        role: &str,
+    ) -> SeededUser {
+        let password_hash =
+            medxz_server::auth::hash_password(password).expect("hash_password failed");
+        let user_id = Uuid::now_v7();
+        sqlx::query(
+            "INSERT INTO users (id, organization_id, email, password_hash, role) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(user_id)
+        .bind(org_id)
+        .bind(email.trim().to_ascii_lowercase())
+        .bind(password_hash)
+        .bind(role)
+        .execute(&self.pool)
+        .await
+        .expect("failed to seed user");
+
+        SeededUser { org_id, user_id }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SeededUser {
+    pub org_id: Uuid,
+    pub user_id: Uuid,
+}
+
+pub async fn login(app: &axum::Router, org_code: &str, email: &str, password: &str) -> String {
+    let response = login_request(app, org_code, email, password, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    session_token(response).await
+}
+
+/// Logs in and registers `device_id`, returning a session bound to it.
+pub async fn login_device(
+    app: &axum::Router,
+    org_code: &str,
+    email: &str,
+    password: &str,
+    device_id: Uuid,
+) -> String {
+    let token = login(app, org_code, email, password).await;
+    let response = register_device(app, &token, device_id).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    token
+}
+
+pub async fn login_request(
+    app: &axum::Router,
+    org_code: &str,
+    email: &str,
+    password: &str,
+    device_id: Option<Uuid>,
+) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/auth/login")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "organization_code": org_code,
+                        "email": email,
+                        "password": password,
+                        "device_id": device_id
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn session_token(response: axum::response::Response) -> String {
+    body_json(response).await["session_token"]
+        .as_str()
+        .expect("session_token must be present")
+        .to_string()
+}
+
+pub async fn register_device(
+    app: &axum::Router,
+    token: &str,
+    device_id: Uuid,
+) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/devices")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "device_id": device_id,
+                        "name": "Front desk laptop",
+                        "public_key": DevicePublicKey::from(&device_key(device_id)),
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn body_json(response: axum::response::Response) -> serde_json::Value {
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(bytes.as_ref()).unwrap()
+}
+
+/// The signing key [`register_device`] registers for `device_id`.
+pub fn device_key(device_id: Uuid) -> SigningKey {
+    SigningKey::from_bytes(&Sha256::digest(device_id.as_bytes()).into())
+}
+
+/// A signed op with no `prev_hash`, so on its own it is only accepted as a
+/// device's first op; see [`chain`].
+/// Ops from one device get increasing HLCs in the order they were built.
+pub fn test_op(user: &SeededUser, device_id: Uuid, device_seq: u64) -> Operation {
+    let device_time = now_micros();
+    let mut op = Operation {
+        op_id: Uuid::now_v7(),
+        clinic_id: user.org_id,
+        device_id,
+        user_id: user.user_id,
+        entity: EntityRef {
+            entity_type: "Patient".into(),
+            entity_id: Uuid::now_v7(),
+        },
+        op_type: "patient.registered".into(),
+        device_time,
+        device_seq,
+        hlc: Some(Hlc {
+            wall_ms: (device_time.unix_timestamp_nanos() / 1_000_000) as u64,
+            counter: device_seq as u32,
+            node: device_id,
+        }),
+        schema_version: 1,
+        payload: serde_json::json!({ "family_name": format!("Patient {device_seq}") }),
+        prev_hash: None,
+        hash: None,
+        signature: None,
+    };
+    op.sign(&device_key(device_id));
+    op
+}
+
+/// A signed first revision of a note about `patient`.
+pub fn test_note_op(
+    user: &SeededUser,
+    device_id: Uuid,
+    device_seq: u64,
+    patient: Uuid,
+) -> Operation {
+    let mut op = Operation {
+        entity: EntityRef {
+            entity_type: "ClinicalNote".into(),
+            entity_id: Uuid::now_v7(),
+        },
+        op_type: "clinical_note.revision_created".into(),
+        payload: serde_json::json!({
+            "patient": { "type": "Patient", "id": patient },
+            "body": "Presents with a cough.",
+        }),
+        ..test_op(user, device_id, device_seq)
+    };
+    op.sign(&device_key(device_id));
+    op
+}
+
+/// A signed op carrying a typed domain payload for `entity_id`.
+pub fn domain_op<P: OpPayload>(
+    user: &SeededUser,
+    device_id: Uuid,
+    device_seq: u64,
+    entity_id: Uuid,
+    payload: &P,
+) -> Operation {
+    let mut op = Operation {
+        entity: EntityRef {
+            entity_type: P::ENTITY_TYPE.into(),
+            entity_id,
+        },
+        op_type: P::OP_TYPE.into(),
+        schema_version: P::SCHEMA_VERSION,
+        payload: serde_json::to_value(payload).unwrap(),
+        ..test_op(user, device_id, device_seq)
+    };
+    op.sign(&device_key(device_id));
+    op
+}
+
+/// Links `ops` into a hash chain continuing from `after`, re-signing each op.
+pub fn chain(mut ops: Vec<Operation>, after: Option<&Operation>) -> Vec<Operation> {
+    let mut prev_hash = after.and_then(|op| op.hash);
+    for op in &mut ops {
+        op.prev_hash = prev_hash;
+        op.sign(&device_key(op.device_id));
+        prev_hash = op.hash;
+    }
+    ops
+}
+
+pub async fn push(
+    app: &axum::Router,
+    token: &str,
+    ops: Vec<Operation>,
+) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/push")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&PushRequest { ops }).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn push_results(app: &axum::Router, token: &str, ops: Vec<Operation>) -> Vec<PushResult> {
+    let response = push(app, token, ops).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: PushResponse = serde_json::from_value(body_json(response).await).unwrap();
+    body.results
+}
+
+pub async fn pull(app: &axum::Router, token: &str, query: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/v1/sync/pull?{query}"))
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn pull_page(
+    app: &axum::Router,
+    token: &str,
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> PullResponse {
+    let query = match cursor {
+        Some(Cursor(n)) => format!("cursor={n}&limit={limit}"),
+        None => format!("limit={limit}"),
+    };
+    let response = pull(app, token, &query).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_value(body_json(response).await).unwrap()
+}
+
+/// Postgres stores timestamps with microsecond precision.
+pub fn now_micros() -> OffsetDateTime {
+    medxz_protocol::hash::truncate_device_time(OffsetDateTime::now_utc())
+}
@@ -0,0 +1,270 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::{
+    body_json, device_key, login, login_device, login_request, register_device, session_token,
+    TestDb,
+};
+use medxz_protocol::DevicePublicKey;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn registering_a_device_binds_the_session_and_allows_device_login() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+    let token = login(&app, "acme", "front@acme.com", "pw123").await;
+    let device_id = Uuid::now_v7();
+
+    let response = register_device(&app, &token, device_id).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["id"], device_id.to_string());
+    assert_eq!(body["name"], "Front desk laptop");
+    assert_eq!(
+        body["public_key"],
+        DevicePublicKey::from(&device_key(device_id)).to_string()
+    );
+    assert!(body["revoked_at"].is_null());
+
+    let bound: Option<Uuid> = sqlx::query_scalar("SELECT device_id FROM sessions")
+        .fetch_one(&test_db.pool)
+        .await
+        .unwrap();
+    assert_eq!(bound, Some(device_id));
+
+    // Retrying the registration is harmless.
+    let retry = register_device(&app, &token, device_id).await;
+    assert_eq!(retry.status(), StatusCode::OK);
+
+    let response = login_request(&app, "acme", "front@acme.com", "pw123", Some(device_id)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn login_rejects_unregistered_devices() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+
+    let response = login_request(
+        &app,
+        "acme",
+        "front@acme.com",
+        "pw123",
+        Some(Uuid::now_v7()),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn register_validates_name_and_organization() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    test_db
+        .seed_org_and_user("other", "Other", "front@other.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    login_device(&app, "acme", "front@acme.com", "pw123", device_id).await;
+
+    let other_token = login(&app, "other", "front@other.com", "pw123").await;
+    let response = register_device(&app, &other_token, device_id).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/devices")
+                .header(header::AUTHORIZATION, format!("Bearer {other_token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({
+                        "device_id": Uuid::now_v7(),
+                        "name": "  ",
+                        "public_key": DevicePublicKey::from(&device_key(Uuid::now_v7())),
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn admin_revocation_invalidates_sessions_and_future_logins() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let front = test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    test_db
+        .seed_user(front.org_id, "admin@acme.com", "pw123", "admin")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let device_token = login_device(&app, "acme", "front@acme.com", "pw123", device_id).await;
+    let admin_token = login(&app, "acme", "admin@acme.com", "pw123").await;
+
+    let response = revoke(&app, &device_token, device_id).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = revoke(&app, &admin_token, device_id).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_json(response).await["revoked_at"].is_string());
+
+    let response = me(&app, &device_token).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = login_request(&app, "acme", "front@acme.com", "pw123", Some(device_id)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let fresh = login(&app, "acme", "front@acme.com", "pw123").await;
+    let response = register_device(&app, &fresh, device_id).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = list(&app, &admin_token).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["devices"][0]["id"], device_id.to_string());
+    assert!(body["devices"][0]["revoked_at"].is_string());
+
+    let response = revoke(&app, &admin_token, Uuid::now_v7()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn sessions_bound_to_unknown_devices_do_not_authenticate() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+    let response = login_request(&app, "acme", "front@acme.com", "pw123", None).await;
+    let token = session_token(response).await;
+
+    // Simulates a session bound before devices had to be registered.
+    sqlx::query("ALTER TABLE sessions DROP CONSTRAINT sessions_device_id_fkey")
+        .execute(&test_db.pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE sessions SET device_id = $1")
+        .bind(Uuid::now_v7())
+        .execute(&test_db.pool)
+        .await
+        .unwrap();
+
+    let response = me(&app, &token).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn a_device_keeps_the_key_it_first_registered() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "front@acme.com", "pw123", device_id).await;
+
+    let other_key = DevicePublicKey::from(&device_key(Uuid::now_v7()));
+    let response = register_with_key(&app, &token, device_id, &other_key.to_string()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // Not a point on the curve.
+    let invalid = "02".repeat(32);
+    let response = register_with_key(&app, &token, Uuid::now_v7(), &invalid).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+async fn register_with_key(
+    app: &axum::Router,
+    token: &str,
+    device_id: Uuid,
+    public_key: &str,
+) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/devices")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({
+                        "device_id": device_id,
+                        "name": "Exam room laptop",
+                        "public_key": public_key,
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn revoke(app: &axum::Router, token: &str, device_id: Uuid) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/v1/devices/{device_id}/revoke"))
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn list(app: &axum::Router, token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/devices")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn me(app: &axum::Router, token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/auth/me")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::{body_json, chain, login, login_device, push_results, test_note_op, test_op, TestDb};
+use medxz_protocol::merkle::{MerkleNode, MerkleTree, NodeId};
+use medxz_protocol::{FetchRequest, FetchResponse};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+async fn get_node(app: &axum::Router, token: &str, node: NodeId) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/v1/sync/merkle?level={}&index={}",
+                    node.level, node.index
+                ))
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn merkle_node(app: &axum::Router, token: &str, node: NodeId) -> MerkleNode {
+    let response = get_node(app, token, node).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_value(body_json(response).await).unwrap()
+}
+
+async fn fetch(app: &axum::Router, token: &str, op_ids: Vec<Uuid>) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/fetch")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&FetchRequest { op_ids }).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn devices_find_and_repair_gaps_through_the_merkle_tree() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let doctor = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    test_db
+        .seed_user(doctor.org_id, "desk@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let patient = test_op(&doctor, device_id, 1);
+    let other = test_op(&doctor, device_id, 2);
+    let note = test_note_op(&doctor, device_id, 3, patient.entity.entity_id);
+    let ops = chain(vec![patient, other, note], None);
+    assert!(push_results(&app, &token, ops.clone())
+        .await
+        .iter()
+        .all(|result| result.outcome.is_acked()));
+    // Ops stored before HLCs are placed by device time. The log is
+    // append-only, so stand one in with triggers off.
+    let mut tx = test_db.pool.begin().await.unwrap();
+    sqlx::query("SET LOCAL session_replication_role = replica")
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE ops SET hlc = NULL WHERE op_id = $1")
+        .bind(ops[0].op_id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    let root = merkle_node(&app, &token, NodeId::ROOT).await;
+    assert_eq!(root.digest, MerkleTree::from_ops(&ops).digest(NodeId::ROOT));
+
+    // A device restored from a backup lost the last op and holds one the
+    // server never received.
+    let unpushed = test_op(&doctor, device_id, 4);
+    let local = MerkleTree::from_ops([&ops[0], &ops[1], &unpushed]);
+    let (mut missing, mut extra) = (Vec::new(), Vec::new());
+    let mut queue = vec![NodeId::ROOT];
+    while let Some(node) = queue.pop() {
+        let divergence = local.compare(&merkle_node(&app, &token, node).await);
+        queue.extend(divergence.descend);
+        missing.extend(divergence.missing);
+        extra.extend(divergence.extra);
+    }
+    assert_eq!(missing, [ops[2].op_id]);
+    assert_eq!(extra, [unpushed.op_id]);
+
+    let response = fetch(&app, &token, vec![Uuid::now_v7(), ops[2].op_id]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let fetched: FetchResponse = serde_json::from_value(body_json(response).await).unwrap();
+    assert_eq!(fetched.ops, [ops[2].clone()]);
+
+    // Front desk neither sees nor can fetch the note.
+    let desk = login_device(&app, "acme", "desk@acme.com", "pw123", Uuid::now_v7()).await;
+    assert_eq!(
+        merkle_node(&app, &desk, NodeId::ROOT).await.digest,
+        MerkleTree::from_ops(&ops[..2]).digest(NodeId::ROOT)
+    );
+    let response = fetch(&app, &desk, vec![ops[2].op_id]).await;
+    let fetched: FetchResponse = serde_json::from_value(body_json(response).await).unwrap();
+    assert!(fetched.ops.is_empty());
+}
+
+#[tokio::test]
+async fn merkle_requests_are_checked() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", Uuid::now_v7()).await;
+
+    let response = get_node(&app, &token, NodeId { level: 7, index: 0 }).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = get_node(
+        &app,
+        &token,
+        NodeId {
+            level: 0,
+            index: 1 << 24,
+        },
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let empty = merkle_node(&app, &token, NodeId { level: 2, index: 5 }).await;
+    assert_eq!(empty.digest.count, 0);
+
+    let too_many = (0..1001).map(|_| Uuid::now_v7()).collect();
+    let response = fetch(&app, &token, too_many).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let unbound = login(&app, "acme", "doc@acme.com", "pw123").await;
+    let response = get_node(&app, &unbound, NodeId::ROOT).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = fetch(&app, &unbound, vec![Uuid::now_v7()]).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/merkle?level=6&index=0")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
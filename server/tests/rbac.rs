@@ -0,0 +1,174 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::{
+    body_json, chain, login_device, login_request, pull_page, push_results, test_note_op, test_op,
+    TestDb,
+};
+use medxz_protocol::{PushOutcome, RejectReason};
+use medxz_server::rbac::{hidden_entity_types, write_permission, Permission, Role, UnknownRole};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+#[test]
+fn roles_parse_from_their_wire_names_only() {
+    for role in Role::ALL {
+        assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+    }
+    assert_eq!("doctor".parse::<Role>(), Err(UnknownRole("doctor".into())));
+    assert!("Admin".parse::<Role>().is_err());
+}
+
+#[test]
+fn permission_matrix_restricts_clinical_data() {
+    assert!(Role::Clinician.allows(Permission::WriteClinicalNotes));
+    assert!(Role::Admin.allows(Permission::ReadClinicalNotes));
+    assert!(!Role::Admin.allows(Permission::WriteClinicalNotes));
+    assert!(!Role::FrontDesk.allows(Permission::ReadClinicalNotes));
+    assert!(!Role::FrontDesk.allows(Permission::ManageDevices));
+    assert!(Role::ALL
+        .iter()
+        .all(|role| role.allows(Permission::SyncPush) && role.allows(Permission::SyncPull)));
+
+    assert_eq!(
+        write_permission("ClinicalNote"),
+        Some(Permission::WriteClinicalNotes)
+    );
+    assert_eq!(
+        write_permission("Observation"),
+        Some(Permission::WriteClinicalRecords)
+    );
+    assert!(Role::Admin.allows(Permission::ReadClinicalRecords));
+    assert!(!Role::Admin.allows(Permission::WriteClinicalRecords));
+    assert_eq!(write_permission("Patient"), None);
+    assert_eq!(write_permission("Encounter"), None);
+    assert_eq!(
+        hidden_entity_types(Role::FrontDesk),
+        vec![
+            "ClinicalNote",
+            "Observation",
+            "Condition",
+            "MedicationRequest"
+        ]
+    );
+    assert!(hidden_entity_types(Role::Clinician).is_empty());
+}
+
+#[tokio::test]
+async fn database_rejects_unknown_roles() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+
+    let result = sqlx::query(
+        "INSERT INTO users (id, organization_id, email, password_hash, role) \
+         VALUES ($1, $2, 'x@acme.com', 'x', 'janitor')",
+    )
+    .bind(Uuid::now_v7())
+    .bind(user.org_id)
+    .execute(&test_db.pool)
+    .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn users_with_legacy_roles_cannot_sign_in() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "front@acme.com", "pw123", "front_desk")
+        .await;
+    sqlx::query("ALTER TABLE users DROP CONSTRAINT users_role_check")
+        .execute(&test_db.pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE users SET role = 'receptionist'")
+        .execute(&test_db.pool)
+        .await
+        .unwrap();
+
+    let app = test_db.router();
+    let response = login_request(&app, "acme", "front@acme.com", "pw123", None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn login_reports_the_typed_role() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+
+    let response = login_request(&app, "acme", "doc@acme.com", "pw123", None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["user"]["role"], "clinician");
+}
+
+#[tokio::test]
+async fn front_desk_cannot_read_or_write_clinical_notes() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let clinician = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let front = test_db
+        .seed_user(clinician.org_id, "front@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+
+    let clinician_device = Uuid::now_v7();
+    let clinician_token =
+        login_device(&app, "acme", "doc@acme.com", "pw123", clinician_device).await;
+    let front_device = Uuid::now_v7();
+    let front_token = login_device(&app, "acme", "front@acme.com", "pw123", front_device).await;
+
+    let patient = test_op(&clinician, clinician_device, 1);
+    let note = test_note_op(&clinician, clinician_device, 2, patient.entity.entity_id);
+    let ops = chain(vec![patient, note], None);
+    let (patient, note) = (ops[0].clone(), ops[1].clone());
+    let results = push_results(&app, &clinician_token, vec![patient.clone(), note.clone()]).await;
+    assert!(results.iter().all(|r| r.outcome.is_acked()));
+
+    let page = pull_page(&app, &front_token, None, 10).await;
+    assert_eq!(page.ops, vec![patient.clone()]);
+
+    let page = pull_page(&app, &clinician_token, None, 10).await;
+    assert_eq!(page.ops, vec![patient, note]);
+
+    let front_note = test_note_op(&front, front_device, 1, ops[0].entity.entity_id);
+    let mut front_observation = test_op(&front, front_device, 1);
+    front_observation.entity.entity_type = "Observation".into();
+    let results = push_results(&app, &front_token, vec![front_note, front_observation]).await;
+    assert!(results.iter().all(|r| matches!(
+        r.outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::PermissionDenied,
+            ..
+        }
+    )));
+
+    let response = list_devices(&app, &front_token).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+async fn list_devices(app: &axum::Router, token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/devices")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
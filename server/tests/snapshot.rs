@@ -0,0 +1,185 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::{body_json, chain, domain_op, login, login_device, pull_page, push_results, TestDb};
+use medxz_protocol::domain::{
+    ObservationRecorded, ObservationValue, PatientDemographicsChanged, PatientRegistered,
+};
+use medxz_protocol::fhir::{system, Coding, Quantity, Reference};
+use medxz_protocol::projection::Projection;
+use medxz_protocol::{Cursor, Snapshot};
+use medxz_server::snapshot;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+async fn get_snapshot(app: &axum::Router, token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/snapshot")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn new_devices_start_from_a_snapshot_and_pull_the_tail() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let doctor = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    test_db
+        .seed_user(doctor.org_id, "desk@acme.com", "pw123", "front_desk")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let response = get_snapshot(&app, &token).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let patient = Reference {
+        resource_type: "Patient".into(),
+        id: Uuid::now_v7(),
+    };
+    let registration = domain_op(
+        &doctor,
+        device_id,
+        1,
+        patient.id,
+        &PatientRegistered {
+            family_name: "Okafor".into(),
+            given_names: vec!["Ada".into()],
+            birth_date: None,
+            gender: None,
+            identifiers: vec![],
+        },
+    );
+    let heart_rate = domain_op(
+        &doctor,
+        device_id,
+        2,
+        Uuid::now_v7(),
+        &ObservationRecorded {
+            patient: patient.clone(),
+            encounter: None,
+            code: Coding::new(system::LOINC, "8867-4").into(),
+            value: ObservationValue::Quantity(Quantity::ucum(72.0, "/min")),
+            effective_at: time::OffsetDateTime::now_utc(),
+        },
+    );
+    let head = chain(vec![registration, heart_rate], None);
+    assert!(push_results(&app, &token, head.clone())
+        .await
+        .iter()
+        .all(|result| result.outcome.is_acked()));
+
+    assert_eq!(
+        snapshot::refresh(&test_db.pool, doctor.org_id, 3)
+            .await
+            .unwrap(),
+        None,
+        "fewer new ops than the threshold"
+    );
+    let cursor = snapshot::refresh(&test_db.pool, doctor.org_id, 2)
+        .await
+        .unwrap()
+        .expect("a snapshot was built");
+    assert_eq!(
+        snapshot::refresh(&test_db.pool, doctor.org_id, 1)
+            .await
+            .unwrap(),
+        None,
+        "nothing arrived since"
+    );
+
+    let rename = domain_op(
+        &doctor,
+        device_id,
+        3,
+        patient.id,
+        &PatientDemographicsChanged {
+            based_on: vec![head[0].op_id],
+            family_name: Some("Okafor-Smith".into()),
+            ..Default::default()
+        },
+    );
+    let tail = chain(vec![rename], head.last());
+    push_results(&app, &token, tail.clone()).await;
+
+    let response = get_snapshot(&app, &token).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let snapshot: Snapshot = serde_json::from_value(body_json(response).await).unwrap();
+    assert_eq!(snapshot.cursor, cursor);
+    assert_eq!(snapshot.op_count, 2);
+    assert_eq!(snapshot.projection, Projection::from_ops(&head).unwrap());
+
+    let pulled = pull_page(&app, &token, Some(snapshot.cursor), 100).await;
+    assert_eq!(pulled.ops, tail);
+    let mut bootstrapped = snapshot.projection;
+    for op in &pulled.ops {
+        bootstrapped.apply(op).unwrap();
+    }
+    let everything = [head, tail].concat();
+    assert_eq!(bootstrapped, Projection::from_ops(&everything).unwrap());
+
+    // The next snapshot builds on this one.
+    snapshot::refresh_all(&test_db.pool, 1).await.unwrap();
+    let snapshot: Snapshot =
+        serde_json::from_value(body_json(get_snapshot(&app, &token).await).await).unwrap();
+    let next = snapshot.cursor;
+    assert!(next > cursor);
+    assert_eq!(snapshot.op_count, 3);
+    assert_eq!(snapshot.projection, bootstrapped);
+    let stored: i64 = sqlx::query_scalar("SELECT count(*) FROM snapshots")
+        .fetch_one(&test_db.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 1);
+
+    // Front desk sees the patient but not the observation.
+    let desk = login_device(&app, "acme", "desk@acme.com", "pw123", Uuid::now_v7()).await;
+    let snapshot: Snapshot =
+        serde_json::from_value(body_json(get_snapshot(&app, &desk).await).await).unwrap();
+    assert_eq!(snapshot.cursor, next);
+    assert_eq!(snapshot.projection.patients.len(), 1);
+    assert!(snapshot.projection.observations.is_empty());
+    assert_eq!(
+        pull_page(&app, &desk, Some(Cursor(0)), 100).await.ops.len(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn snapshots_require_a_device_bound_session() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+
+    let unbound = login(&app, "acme", "doc@acme.com", "pw123").await;
+    assert_eq!(
+        get_snapshot(&app, &unbound).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/snapshot")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
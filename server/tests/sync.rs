@@ -0,0 +1,1374 @@
+mod common;
+
+use std::collections::HashSet;
+use std::io::Write;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::{
+    body_json, chain, device_key, domain_op, login, login_device, pull, pull_page, push,
+    push_results, test_op, TestDb,
+};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use medxz_protocol::domain::{
+    AdministrativeGender, ConditionAdded, EncounterClosed, EncounterOpened, MedicationOrdered,
+    ObservationEnteredInError, ObservationRecorded, ObservationValue, PatientRegistered,
+};
+use medxz_protocol::fhir::{system, Coding, Identifier, Quantity, Reference};
+use medxz_protocol::testing;
+use medxz_protocol::wire::{WireFormat, CBOR_CONTENT_TYPE};
+use medxz_protocol::{
+    Capabilities, Cursor, Hlc, Operation, OperationValidationError, ProvenanceField, PullResponse,
+    PushOutcome, PushRequest, PushResponse, PushResult, RejectReason,
+};
+use medxz_protocol::{DeviceScope, SyncScope};
+use proptest::strategy::{Strategy, ValueTree};
+use proptest::test_runner::TestRunner;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn push_stores_ops_and_dedupes_by_op_id() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(
+        vec![test_op(&user, device_id, 1), test_op(&user, device_id, 2)],
+        None,
+    );
+    let (first, second) = (ops[0].clone(), ops[1].clone());
+
+    let results = push_results(&app, &token, vec![first.clone(), second.clone()]).await;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].op_id, first.op_id);
+    assert_eq!(results[1].op_id, second.op_id);
+    assert!(matches!(results[0].outcome, PushOutcome::Accepted { .. }));
+    assert!(matches!(results[1].outcome, PushOutcome::Accepted { .. }));
+
+    let results = push_results(
+        &app,
+        &token,
+        vec![second.clone(), first.clone(), first.clone()],
+    )
+    .await;
+    let outcomes: Vec<_> = results.into_iter().map(|r| r.outcome).collect();
+    assert_eq!(outcomes, vec![PushOutcome::Duplicate; 3]);
+
+    let stored: Vec<(Uuid, i64)> =
+        sqlx::query_as("SELECT op_id, device_seq FROM ops ORDER BY server_seq")
+            .fetch_all(&test_db.pool)
+            .await
+            .unwrap();
+    assert_eq!(stored.len(), 2);
+    assert_eq!(stored[1], (second.op_id, 2));
+}
+
+#[tokio::test]
+async fn push_rejects_an_op_id_stored_by_another_organization() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let acme = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let other = test_db
+        .seed_org_and_user("other", "Other", "doc@other.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let acme_device = Uuid::now_v7();
+    let other_device = Uuid::now_v7();
+    let acme_token = login_device(&app, "acme", "doc@acme.com", "pw123", acme_device).await;
+    let other_token = login_device(&app, "other", "doc@other.com", "pw123", other_device).await;
+
+    let acme_op = test_op(&acme, acme_device, 1);
+    let results = push_results(&app, &acme_token, vec![acme_op.clone()]).await;
+    assert!(matches!(results[0].outcome, PushOutcome::Accepted { .. }));
+
+    let mut colliding = test_op(&other, other_device, 1);
+    colliding.op_id = acme_op.op_id;
+    colliding.sign(&device_key(other_device));
+    let results = push_results(&app, &other_token, vec![colliding]).await;
+    assert!(matches!(
+        results[0].outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::OpIdTaken,
+            ..
+        }
+    ));
+
+    let page = pull_page(&app, &other_token, None, 10).await;
+    assert!(page.ops.is_empty());
+}
+
+#[tokio::test]
+async fn push_assigns_increasing_server_seq() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(
+        (1..=5).map(|seq| test_op(&user, device_id, seq)).collect(),
+        None,
+    );
+    let results = push_results(&app, &token, ops.clone()).await;
+
+    let cursors: Vec<Cursor> = results
+        .iter()
+        .map(|r| match r.outcome {
+            PushOutcome::Accepted { cursor } => cursor,
+            ref other => panic!("unexpected outcome {other:?}"),
+        })
+        .collect();
+    assert!(cursors.windows(2).all(|w| w[0] < w[1]));
+
+    let stored: Vec<Uuid> = sqlx::query_scalar("SELECT op_id FROM ops ORDER BY server_seq")
+        .fetch_all(&test_db.pool)
+        .await
+        .unwrap();
+    let pushed: Vec<Uuid> = ops.iter().map(|op| op.op_id).collect();
+    assert_eq!(stored, pushed);
+}
+
+#[tokio::test]
+async fn ops_table_is_append_only() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let response = push(&app, &token, vec![test_op(&user, device_id, 1)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let update = sqlx::query("UPDATE ops SET op_type = 'tampered'")
+        .execute(&test_db.pool)
+        .await;
+    assert!(update.is_err());
+
+    let delete = sqlx::query("DELETE FROM ops").execute(&test_db.pool).await;
+    assert!(delete.is_err());
+}
+
+#[tokio::test]
+async fn push_reports_rejected_ops_individually() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let valid = test_op(&user, device_id, 1);
+    let mut empty_op_type = test_op(&user, device_id, 2);
+    empty_op_type.op_type = " ".into();
+    let mut out_of_range = test_op(&user, device_id, 3);
+    out_of_range.device_seq = u64::MAX;
+
+    let results = push_results(
+        &app,
+        &token,
+        vec![valid.clone(), empty_op_type.clone(), out_of_range.clone()],
+    )
+    .await;
+    assert!(matches!(results[0].outcome, PushOutcome::Accepted { .. }));
+    assert_eq!(
+        results[1],
+        PushResult {
+            op_id: empty_op_type.op_id,
+            outcome: PushOutcome::Rejected {
+                reason: RejectReason::InvalidOperation {
+                    error: OperationValidationError::EmptyOpType
+                },
+                message: "op_type must not be empty".into(),
+            },
+        }
+    );
+    assert!(matches!(
+        results[2].outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::InvalidOperation {
+                error: OperationValidationError::DeviceSeqOutOfRange
+            },
+            ..
+        }
+    ));
+
+    let stored: Vec<Uuid> = sqlx::query_scalar("SELECT op_id FROM ops")
+        .fetch_all(&test_db.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, vec![valid.op_id]);
+}
+
+#[tokio::test]
+async fn push_decodes_payloads_through_the_registry() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let mut unknown_type = test_op(&user, device_id, 1);
+    unknown_type.op_type = "patient.teleported".into();
+    let mut future_version = test_op(&user, device_id, 2);
+    future_version.schema_version = 99;
+    let mut malformed = test_op(&user, device_id, 3);
+    malformed.payload = serde_json::json!({ "family_name": 7 });
+    let mut nameless = test_op(&user, device_id, 4);
+    nameless.payload = serde_json::json!({ "family_name": " " });
+
+    let results = push_results(
+        &app,
+        &token,
+        vec![unknown_type, future_version, malformed, nameless],
+    )
+    .await;
+    let reasons: Vec<_> = results
+        .into_iter()
+        .map(|result| match result.outcome {
+            PushOutcome::Rejected { reason, .. } => reason,
+            outcome => panic!("unexpected outcome {outcome:?}"),
+        })
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            RejectReason::UnsupportedOperation,
+            RejectReason::UnsupportedOperation,
+            RejectReason::InvalidPayload,
+            RejectReason::InvalidPayload,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn push_accepts_the_clinical_op_set() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let patient = Reference {
+        resource_type: "Patient".into(),
+        id: Uuid::now_v7(),
+    };
+    let encounter = Reference {
+        resource_type: "Encounter".into(),
+        id: Uuid::now_v7(),
+    };
+    let (observation_id, condition_id, order_id) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+    let now = time::OffsetDateTime::now_utc();
+    let heart_rate = ObservationRecorded {
+        patient: patient.clone(),
+        encounter: Some(encounter.clone()),
+        code: Coding::new(system::LOINC, "8867-4").into(),
+        value: ObservationValue::Quantity(Quantity::ucum(72.0, "/min")),
+        effective_at: now,
+    };
+    let ops = chain(
+        vec![
+            domain_op(
+                &user,
+                device_id,
+                1,
+                patient.id,
+                &PatientRegistered {
+                    family_name: "Okafor".into(),
+                    given_names: vec!["Ada".into()],
+                    birth_date: None,
+                    gender: Some(AdministrativeGender::Female),
+                    identifiers: vec![Identifier {
+                        system: "urn:oid:1.2.36.146.595.217.0.1".into(),
+                        value: "MRN-1".into(),
+                    }],
+                },
+            ),
+            domain_op(
+                &user,
+                device_id,
+                2,
+                encounter.id,
+                &EncounterOpened {
+                    patient: patient.clone(),
+                    started_at: now,
+                    reason: None,
+                },
+            ),
+            domain_op(&user, device_id, 3, observation_id, &heart_rate),
+            domain_op(
+                &user,
+                device_id,
+                4,
+                condition_id,
+                &ConditionAdded {
+                    patient: patient.clone(),
+                    encounter: Some(encounter.clone()),
+                    code: Coding::new(system::ICD_10, "I10").into(),
+                    onset_date: None,
+                },
+            ),
+            domain_op(
+                &user,
+                device_id,
+                5,
+                order_id,
+                &MedicationOrdered {
+                    patient: patient.clone(),
+                    encounter: Some(encounter.clone()),
+                    medication: Coding::new(system::RXNORM, "197361").into(),
+                    dose: Some(Quantity::ucum(5.0, "mg")),
+                    route: None,
+                    instructions: "Once daily".into(),
+                },
+            ),
+            domain_op(
+                &user,
+                device_id,
+                6,
+                observation_id,
+                &ObservationEnteredInError {
+                    reason: "Wrong patient".into(),
+                },
+            ),
+            domain_op(
+                &user,
+                device_id,
+                7,
+                encounter.id,
+                &EncounterClosed { ended_at: now },
+            ),
+        ],
+        None,
+    );
+    let results = push_results(&app, &token, ops).await;
+    assert!(results
+        .iter()
+        .all(|r| matches!(r.outcome, PushOutcome::Accepted { .. })));
+
+    let misfiled = ObservationRecorded {
+        patient: encounter,
+        ..heart_rate
+    };
+    let op = chain(
+        vec![domain_op(&user, device_id, 8, Uuid::now_v7(), &misfiled)],
+        None,
+    );
+    let results = push_results(&app, &token, op).await;
+    assert!(matches!(
+        &results[0].outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::InvalidPayload,
+            message,
+        } if message.contains("patient must reference a Patient")
+    ));
+}
+
+#[tokio::test]
+async fn push_verifies_hashes_and_the_device_chain() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(
+        (1..=4).map(|seq| test_op(&user, device_id, seq)).collect(),
+        None,
+    );
+
+    let mut tampered = ops[0].clone();
+    tampered.payload["family_name"] = "Someone else".into();
+    let mut unsealed = ops[0].clone();
+    unsealed.hash = None;
+    let results = push_results(&app, &token, vec![tampered, unsealed]).await;
+    assert!(results.iter().all(|r| matches!(
+        r.outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::HashMismatch,
+            ..
+        }
+    )));
+
+    let mut linked_first = test_op(&user, device_id, 1);
+    linked_first.prev_hash = ops[1].hash;
+    linked_first.sign(&device_key(device_id));
+    let results = push_results(
+        &app,
+        &token,
+        vec![linked_first, ops[1].clone(), ops[0].clone(), ops[1].clone()],
+    )
+    .await;
+    let outcomes: Vec<_> = results.into_iter().map(|r| r.outcome).collect();
+    assert!(matches!(
+        outcomes[0],
+        PushOutcome::Rejected {
+            reason: RejectReason::ChainBroken,
+            ..
+        }
+    ));
+    // Op 2 arrived before op 1, then succeeds once its predecessor is stored.
+    assert!(matches!(
+        outcomes[1],
+        PushOutcome::Rejected {
+            reason: RejectReason::ChainBroken,
+            ..
+        }
+    ));
+    assert!(matches!(outcomes[2], PushOutcome::Accepted { .. }));
+    assert!(matches!(outcomes[3], PushOutcome::Accepted { .. }));
+
+    // A different op claiming an already used device_seq forks the chain.
+    let fork = chain(vec![test_op(&user, device_id, 2)], Some(&ops[0]));
+    // An op that skips over its predecessor leaves a gap.
+    let gap = ops[3].clone();
+    // An op whose prev_hash names the wrong predecessor.
+    let wrong_link = chain(vec![test_op(&user, device_id, 3)], Some(&ops[0]));
+    let results = push_results(
+        &app,
+        &token,
+        vec![fork[0].clone(), gap, wrong_link[0].clone()],
+    )
+    .await;
+    assert!(results.iter().all(|r| matches!(
+        r.outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::ChainBroken,
+            ..
+        }
+    )));
+
+    let results = push_results(&app, &token, ops.clone()).await;
+    let outcomes: Vec<_> = results.into_iter().map(|r| r.outcome).collect();
+    assert_eq!(
+        outcomes[0..2],
+        [PushOutcome::Duplicate, PushOutcome::Duplicate]
+    );
+    assert!(outcomes[2..]
+        .iter()
+        .all(|o| matches!(o, PushOutcome::Accepted { .. })));
+}
+
+#[tokio::test]
+async fn push_requires_an_hlc_that_advances_along_the_device_chain() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let key = device_key(device_id);
+
+    let mut unstamped = test_op(&user, device_id, 1);
+    unstamped.hlc = None;
+    unstamped.sign(&key);
+    let mut borrowed = test_op(&user, device_id, 1);
+    borrowed.hlc = Some(Hlc::zero(Uuid::now_v7()));
+    borrowed.sign(&key);
+    let results = push_results(&app, &token, vec![unstamped, borrowed]).await;
+    let errors: Vec<_> = results
+        .into_iter()
+        .map(|r| match r.outcome {
+            PushOutcome::Rejected {
+                reason: RejectReason::InvalidOperation { error },
+                ..
+            } => error,
+            other => panic!("unexpected outcome {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            OperationValidationError::MissingHlc,
+            OperationValidationError::HlcNodeMismatch
+        ]
+    );
+
+    let first = test_op(&user, device_id, 1);
+    let mut stale = test_op(&user, device_id, 2);
+    stale.hlc = first.hlc;
+    let stale = chain(vec![stale], Some(&first));
+    let next = chain(vec![test_op(&user, device_id, 2)], Some(&first));
+    let results = push_results(&app, &token, vec![first, stale[0].clone(), next[0].clone()]).await;
+    let outcomes: Vec<_> = results.into_iter().map(|r| r.outcome).collect();
+    assert!(matches!(outcomes[0], PushOutcome::Accepted { .. }));
+    assert!(matches!(
+        outcomes[1],
+        PushOutcome::Rejected {
+            reason: RejectReason::ChainBroken,
+            ..
+        }
+    ));
+    assert!(matches!(outcomes[2], PushOutcome::Accepted { .. }));
+
+    let page = pull_page(&app, &token, None, 10).await;
+    assert!(page.ops[0].hlc < page.ops[1].hlc);
+}
+
+#[tokio::test]
+async fn push_requires_a_signature_from_the_registered_device_key() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let mut forged = test_op(&user, device_id, 1);
+    forged.sign(&device_key(Uuid::now_v7()));
+    let mut unsigned = test_op(&user, device_id, 1);
+    unsigned.signature = None;
+    let results = push_results(&app, &token, vec![forged, unsigned]).await;
+    assert!(results.iter().all(|r| matches!(
+        r.outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::InvalidSignature,
+            ..
+        }
+    )));
+
+    let signed = test_op(&user, device_id, 1);
+    let results = push_results(&app, &token, vec![signed.clone()]).await;
+    assert!(matches!(results[0].outcome, PushOutcome::Accepted { .. }));
+    let page = pull_page(&app, &token, None, 10).await;
+    assert_eq!(page.ops, vec![signed]);
+    assert!(page.ops[0].verify_signature(&device_key(device_id).verifying_key()));
+}
+
+#[tokio::test]
+async fn pulled_ops_can_be_rehashed() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    // Sub-microsecond precision and a non-UTC offset are lost in storage but
+    // are not part of the canonical encoding.
+    let mut first = test_op(&user, device_id, 1);
+    first.device_time = time::OffsetDateTime::now_utc()
+        .to_offset(time::UtcOffset::from_hms(5, 45, 0).unwrap())
+        .replace_nanosecond(123_456_789)
+        .unwrap();
+    first.payload =
+        serde_json::json!({ "given_names": ["Ada", "Grace"], "family_name": "Lovelace" });
+    let ops = chain(vec![first, test_op(&user, device_id, 2)], None);
+    let results = push_results(&app, &token, ops.clone()).await;
+    assert!(results.iter().all(|r| r.outcome.is_acked()));
+
+    let page = pull_page(&app, &token, None, 10).await;
+    assert_eq!(page.ops.len(), 2);
+    assert!(page.ops.iter().all(Operation::verify_hash));
+    assert_eq!(page.ops[1].prev_hash, page.ops[0].hash);
+    assert_eq!(page.ops[0].hash, ops[0].hash);
+}
+
+#[tokio::test]
+async fn push_rejects_ops_not_authored_by_the_session() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let other = test_db
+        .seed_org_and_user("other", "Other", "doc@other.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let mut foreign_clinic = test_op(&user, device_id, 1);
+    foreign_clinic.clinic_id = other.org_id;
+    let mut foreign_user = test_op(&user, device_id, 2);
+    foreign_user.user_id = other.user_id;
+    let foreign_device = test_op(&user, Uuid::now_v7(), 3);
+
+    let results = push_results(
+        &app,
+        &token,
+        vec![foreign_clinic, foreign_user, foreign_device],
+    )
+    .await;
+    let fields: Vec<_> = results
+        .into_iter()
+        .map(|r| match r.outcome {
+            PushOutcome::Rejected {
+                reason: RejectReason::ProvenanceMismatch { field },
+                ..
+            } => field,
+            other => panic!("unexpected outcome {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            ProvenanceField::ClinicId,
+            ProvenanceField::UserId,
+            ProvenanceField::DeviceId
+        ]
+    );
+
+    // A session that never declared a device cannot push at all.
+    let unbound = login(&app, "acme", "doc@acme.com", "pw123").await;
+    let results = push_results(&app, &unbound, vec![test_op(&user, device_id, 4)]).await;
+    assert!(matches!(
+        results[0].outcome,
+        PushOutcome::Rejected {
+            reason: RejectReason::ProvenanceMismatch {
+                field: ProvenanceField::DeviceId
+            },
+            ..
+        }
+    ));
+
+    let stored: i64 = sqlx::query_scalar("SELECT count(*) FROM ops")
+        .fetch_one(&test_db.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn push_rejects_oversized_batches() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+
+    let too_many = (0..=medxz_server::sync::MAX_PUSH_OPS as u64)
+        .map(|seq| test_op(&user, device_id, seq + 1))
+        .collect();
+    let response = push(&app, &token, too_many).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let stored: i64 = sqlx::query_scalar("SELECT count(*) FROM ops")
+        .fetch_one(&test_db.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn push_requires_bearer_token() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/push")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&PushRequest {
+                        ops: vec![test_op(&user, Uuid::now_v7(), 1)],
+                    })
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn pull_pages_through_the_log_in_server_order() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(
+        (1..=7).map(|seq| test_op(&user, device_id, seq)).collect(),
+        None,
+    );
+    assert_eq!(
+        push(&app, &token, ops.clone()).await.status(),
+        StatusCode::OK
+    );
+
+    let first = pull_page(&app, &token, None, 3).await;
+    assert_eq!(first.ops, ops[0..3]);
+    assert!(first.has_more);
+
+    let second = pull_page(&app, &token, first.next_cursor, 3).await;
+    assert_eq!(second.ops, ops[3..6]);
+    assert!(second.has_more);
+
+    let third = pull_page(&app, &token, second.next_cursor, 3).await;
+    assert_eq!(third.ops, ops[6..7]);
+    assert!(!third.has_more);
+
+    let caught_up = pull_page(&app, &token, third.next_cursor, 3).await;
+    assert!(caught_up.ops.is_empty());
+    assert!(!caught_up.has_more);
+    assert_eq!(caught_up.next_cursor, third.next_cursor);
+
+    // A device that crashed before persisting the last cursor resumes from the
+    // previous one and sees exactly the same page again.
+    let replayed = pull_page(&app, &token, second.next_cursor, 3).await;
+    assert_eq!(replayed, third);
+}
+
+#[tokio::test]
+async fn pull_on_empty_log_returns_no_cursor() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", Uuid::now_v7()).await;
+
+    let page = pull_page(&app, &token, None, 10).await;
+    assert!(page.ops.is_empty());
+    assert_eq!(page.next_cursor, None);
+    assert!(!page.has_more);
+}
+
+#[tokio::test]
+async fn pull_only_returns_ops_from_the_callers_organization() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let acme = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let other = test_db
+        .seed_org_and_user("other", "Other", "doc@other.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let acme_device = Uuid::now_v7();
+    let other_device = Uuid::now_v7();
+    let acme_token = login_device(&app, "acme", "doc@acme.com", "pw123", acme_device).await;
+    let other_token = login_device(&app, "other", "doc@other.com", "pw123", other_device).await;
+
+    let acme_op = test_op(&acme, acme_device, 1);
+    let other_op = test_op(&other, other_device, 1);
+    assert_eq!(
+        push(&app, &acme_token, vec![acme_op.clone()])
+            .await
+            .status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        push(&app, &other_token, vec![other_op.clone()])
+            .await
+            .status(),
+        StatusCode::OK
+    );
+
+    let page = pull_page(&app, &acme_token, None, 10).await;
+    assert_eq!(page.ops, vec![acme_op]);
+    let page = pull_page(&app, &other_token, None, 10).await;
+    assert_eq!(page.ops, vec![other_op]);
+}
+
+#[tokio::test]
+async fn pull_rejects_invalid_query_parameters() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", Uuid::now_v7()).await;
+
+    for query in [
+        "limit=0",
+        "limit=1001",
+        "cursor=abc",
+        "cursor=-1",
+        "cursor=18446744073709551615",
+    ] {
+        let response = pull(&app, &token, query).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{query}");
+    }
+
+    // Without a device there is no scope to pull through.
+    let unbound = login(&app, "acme", "doc@acme.com", "pw123").await;
+    let response = pull(&app, &unbound, "limit=10").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/pull")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn pull_never_skips_or_repeats_ops_during_concurrent_pushes() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", Uuid::now_v7()).await;
+
+    let writers: Vec<_> = (0..4)
+        .map(|_| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let device_id = Uuid::now_v7();
+                let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+                let mut pushed = Vec::new();
+                let mut last: Option<Operation> = None;
+                for batch in 0..10 {
+                    let ops = chain(
+                        (1..=5)
+                            .map(|i| test_op(&user, device_id, batch * 5 + i))
+                            .collect(),
+                        last.as_ref(),
+                    );
+                    let results = push_results(&app, &token, ops.clone()).await;
+                    assert!(results.iter().all(|r| r.outcome.is_acked()));
+                    last = ops.last().cloned();
+                    pushed.extend(ops.into_iter().map(|op| op.op_id));
+                }
+                pushed
+            })
+        })
+        .collect();
+
+    let mut cursor = None;
+    let mut seen = Vec::new();
+    while !writers.iter().all(|w| w.is_finished()) {
+        let page = pull_page(&app, &token, cursor, 7).await;
+        seen.extend(page.ops.iter().map(|op| op.op_id));
+        cursor = page.next_cursor;
+    }
+
+    let mut expected = HashSet::new();
+    for writer in writers {
+        expected.extend(writer.await.unwrap());
+    }
+
+    loop {
+        let page = pull_page(&app, &token, cursor, 7).await;
+        seen.extend(page.ops.iter().map(|op| op.op_id));
+        cursor = page.next_cursor;
+        if !page.has_more {
+            break;
+        }
+    }
+
+    let unique: HashSet<Uuid> = seen.iter().copied().collect();
+    assert_eq!(unique.len(), seen.len(), "an op was pulled twice");
+    assert_eq!(unique, expected, "an op was skipped");
+}
+
+#[tokio::test]
+async fn capabilities_are_public_and_describe_the_server() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let app = test_db.router();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/capabilities")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let capabilities: Capabilities = serde_json::from_value(body_json(response).await).unwrap();
+    assert_eq!(
+        capabilities.protocol_version,
+        medxz_protocol::PROTOCOL_VERSION
+    );
+    assert_eq!(
+        capabilities.min_protocol_version,
+        medxz_server::sync::MIN_PROTOCOL_VERSION
+    );
+    assert_eq!(
+        capabilities.limits.max_push_ops as usize,
+        medxz_server::sync::MAX_PUSH_OPS
+    );
+    assert_eq!(
+        capabilities.limits.max_pull_limit,
+        medxz_server::sync::MAX_PULL_LIMIT
+    );
+    assert!(capabilities.operations.iter().any(|op| {
+        op.entity_type == "Patient" && op.op_type == "patient.registered" && op.schema_version == 2
+    }));
+    assert_eq!(
+        capabilities.formats,
+        ["application/json", "application/cbor"]
+    );
+    assert_eq!(capabilities.content_encodings, ["gzip", "zstd"]);
+}
+
+#[tokio::test]
+async fn sync_refuses_clients_below_the_minimum_protocol_version() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(vec![test_op(&user, device_id, 1)], None);
+
+    let request = |method: &str, uri: &str, version: &str| {
+        let body = if method == "POST" {
+            Body::from(serde_json::to_vec(&PushRequest { ops: ops.clone() }).unwrap())
+        } else {
+            Body::empty()
+        };
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(medxz_protocol::PROTOCOL_VERSION_HEADER, version)
+            .body(body)
+            .unwrap()
+    };
+
+    for (method, uri) in [("POST", "/v1/sync/push"), ("GET", "/v1/sync/pull")] {
+        let response = app
+            .clone()
+            .oneshot(request(method, uri, "0"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED, "{uri}");
+        assert_eq!(body_json(response).await["code"], "upgrade_required");
+
+        let response = app
+            .clone()
+            .oneshot(request(method, uri, "one"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+    let stored: i64 = sqlx::query_scalar("SELECT count(*) FROM ops")
+        .fetch_one(&test_db.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+
+    let current = medxz_protocol::PROTOCOL_VERSION.to_string();
+    let response = app
+        .clone()
+        .oneshot(request("POST", "/v1/sync/push", &current))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .oneshot(request("GET", "/v1/sync/pull", &current))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn body_bytes(response: axum::response::Response) -> Vec<u8> {
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec()
+}
+
+#[tokio::test]
+async fn sync_bodies_can_travel_as_cbor() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(
+        (1..=3).map(|seq| test_op(&user, device_id, seq)).collect(),
+        None,
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/push")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, CBOR_CONTENT_TYPE)
+                .body(Body::from(
+                    WireFormat::Cbor
+                        .encode(&PushRequest { ops: ops.clone() })
+                        .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], CBOR_CONTENT_TYPE);
+    let pushed: PushResponse = WireFormat::Cbor
+        .decode(&body_bytes(response).await)
+        .unwrap();
+    assert!(pushed.results.iter().all(|r| r.outcome.is_acked()));
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/pull")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::ACCEPT, "application/json;q=0.5, application/cbor")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], CBOR_CONTENT_TYPE);
+    let pulled: PullResponse = WireFormat::Cbor
+        .decode(&body_bytes(response).await)
+        .unwrap();
+    assert_eq!(pulled, pull_page(&app, &token, None, 100).await);
+    assert_eq!(pulled.ops, ops);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/push")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("ops"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn sync_bodies_can_be_compressed() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let user = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+    let device_id = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+    let ops = chain(
+        (1..=3).map(|seq| test_op(&user, device_id, seq)).collect(),
+        None,
+    );
+
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(&serde_json::to_vec(&PushRequest { ops: ops.clone() }).unwrap())
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/push")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(Body::from(gzip.finish().unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/pull")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::ACCEPT_ENCODING, "zstd")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "zstd");
+    let body = zstd::decode_all(body_bytes(response).await.as_slice()).unwrap();
+    let pulled: PullResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(pulled.ops, ops);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/sync/push")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_ENCODING, "br")
+                .body(Body::from(vec![0u8; 8]))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+async fn put_scope(
+    app: &axum::Router,
+    token: &str,
+    device_id: Uuid,
+    scope: &SyncScope,
+) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("PUT")
+                .uri(format!("/v1/devices/{device_id}/scope"))
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(scope).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn pull_follows_the_device_scope() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let doctor = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    test_db
+        .seed_user(doctor.org_id, "admin@acme.com", "pw123", "admin")
+        .await;
+    let app = test_db.router();
+    let exam_room = Uuid::now_v7();
+    let token = login_device(&app, "acme", "doc@acme.com", "pw123", exam_room).await;
+    let front_desk = Uuid::now_v7();
+    let desk_token = login_device(&app, "acme", "doc@acme.com", "pw123", front_desk).await;
+    let admin = login(&app, "acme", "admin@acme.com", "pw123").await;
+
+    let (ada, bo) = (Uuid::now_v7(), Uuid::now_v7());
+    let registered = |family_name: &str| PatientRegistered {
+        family_name: family_name.into(),
+        given_names: vec![],
+        birth_date: None,
+        gender: None,
+        identifiers: vec![],
+    };
+    let encounter = Uuid::now_v7();
+    let now = time::OffsetDateTime::now_utc();
+    let ops = chain(
+        vec![
+            domain_op(&doctor, exam_room, 1, ada, &registered("Okafor")),
+            domain_op(&doctor, exam_room, 2, bo, &registered("Bello")),
+            domain_op(
+                &doctor,
+                exam_room,
+                3,
+                encounter,
+                &EncounterOpened {
+                    patient: Reference {
+                        resource_type: "Patient".into(),
+                        id: ada,
+                    },
+                    started_at: now,
+                    reason: None,
+                },
+            ),
+            domain_op(
+                &doctor,
+                exam_room,
+                4,
+                encounter,
+                &EncounterClosed { ended_at: now },
+            ),
+            common::test_note_op(&doctor, exam_room, 5, ada),
+        ],
+        None,
+    );
+    assert!(push_results(&app, &token, ops.clone())
+        .await
+        .iter()
+        .all(|result| result.outcome.is_acked()));
+    let op_ids = |page: &medxz_protocol::PullResponse| -> Vec<Uuid> {
+        page.ops.iter().map(|op| op.op_id).collect()
+    };
+
+    let everything = pull_page(&app, &desk_token, None, 100).await;
+    assert_eq!(everything.ops, ops);
+    assert_eq!(everything.scope_revision, 0);
+
+    let no_notes = SyncScope {
+        patients: None,
+        entity_types: Some(vec!["Patient".into(), "Encounter".into()]),
+    };
+    let response = put_scope(&app, &admin, front_desk, &no_notes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let scope: DeviceScope = serde_json::from_value(body_json(response).await).unwrap();
+    assert_eq!(scope.revision, 1);
+    let page = pull_page(&app, &desk_token, None, 100).await;
+    assert_eq!(page.scope_revision, 1);
+    assert_eq!(
+        op_ids(&page),
+        ops[..4].iter().map(|op| op.op_id).collect::<Vec<_>>()
+    );
+
+    let panel = SyncScope {
+        patients: Some(vec![ada]),
+        entity_types: None,
+    };
+    put_scope(&app, &admin, front_desk, &panel).await;
+    let page = pull_page(&app, &desk_token, None, 100).await;
+    assert_eq!(page.scope_revision, 2);
+    // The encounter's close op names no patient but follows its opening.
+    assert_eq!(
+        op_ids(&page),
+        [ops[0].op_id, ops[2].op_id, ops[3].op_id, ops[4].op_id]
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/sync/scope")
+                .header(header::AUTHORIZATION, format!("Bearer {desk_token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let scope: DeviceScope = serde_json::from_value(body_json(response).await).unwrap();
+    assert_eq!(
+        scope,
+        DeviceScope {
+            scope: panel.clone(),
+            revision: 2
+        }
+    );
+
+    // Other devices are unaffected.
+    let page = pull_page(&app, &token, None, 100).await;
+    assert_eq!((page.ops.len(), page.scope_revision), (5, 0));
+
+    let unknown = SyncScope {
+        patients: None,
+        entity_types: Some(vec!["Widget".into()]),
+    };
+    let response = put_scope(&app, &admin, front_desk, &unknown).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = put_scope(&app, &token, front_desk, &panel).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = put_scope(&app, &admin, Uuid::now_v7(), &panel).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn generated_device_logs_are_accepted_and_pulled_back_unchanged() {
+    let Some(test_db) = TestDb::new().await else {
+        return;
+    };
+    let doctor = test_db
+        .seed_org_and_user("acme", "Acme", "doc@acme.com", "pw123", "clinician")
+        .await;
+    let app = test_db.router();
+
+    let mut runner = TestRunner::deterministic();
+    let mut pushed = Vec::new();
+    let mut token = String::new();
+    for _ in 0..4 {
+        let device_id = Uuid::now_v7();
+        token = login_device(&app, "acme", "doc@acme.com", "pw123", device_id).await;
+        let log = testing::device_log(
+            doctor.org_id,
+            doctor.user_id,
+            device_id,
+            device_key(device_id),
+            1..20,
+        )
+        .new_tree(&mut runner)
+        .unwrap()
+        .current();
+        for result in push_results(&app, &token, log.clone()).await {
+            assert!(result.outcome.is_acked(), "{result:?}");
+        }
+        pushed.extend(log);
+    }
+
+    let pulled = pull_page(&app, &token, None, 1000).await;
+    assert_eq!(pulled.ops, pushed);
+}
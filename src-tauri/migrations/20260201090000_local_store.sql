@@ -0,0 +1,7 @@
+-- The device identity this database was created for. Ops in the local log
+-- are numbered per device, so a database is never carried over to another.
+CREATE TABLE store_info (
+  id INTEGER PRIMARY KEY CHECK (id = 1),
+  device_id TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
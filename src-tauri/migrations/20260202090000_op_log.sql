@@ -0,0 +1,68 @@
+-- The last HLC this device issued or observed, so its clock resumes past
+-- every op it has seen after a restart.
+ALTER TABLE store_info ADD COLUMN hlc TEXT;
+
+-- Every op this device holds, authored here or pulled from the server, as
+-- the JSON it travels in. Append-only, like the server's log.
+CREATE TABLE ops (
+  op_id TEXT PRIMARY KEY,
+  device_id TEXT NOT NULL,
+  device_seq INTEGER NOT NULL,
+  entity_type TEXT NOT NULL,
+  entity_id TEXT NOT NULL,
+  op_type TEXT NOT NULL,
+  hlc TEXT,
+  body TEXT NOT NULL,
+  received_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+  UNIQUE (device_id, device_seq)
+);
+
+CREATE INDEX ops_entity_idx ON ops(entity_type, entity_id);
+
+CREATE TRIGGER ops_no_update BEFORE UPDATE ON ops
+BEGIN
+  SELECT RAISE(ABORT, 'ops is append-only');
+END;
+
+CREATE TRIGGER ops_no_delete BEFORE DELETE ON ops
+BEGIN
+  SELECT RAISE(ABORT, 'ops is append-only');
+END;
+
+-- Ops authored here that the server has not acknowledged yet.
+CREATE TABLE outbox (
+  op_id TEXT PRIMARY KEY REFERENCES ops(op_id)
+);
+
+-- Who made each local write, and when. Written with the op it records and
+-- never changed.
+CREATE TABLE audit_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  op_id TEXT NOT NULL UNIQUE REFERENCES ops(op_id),
+  user_id TEXT NOT NULL,
+  action TEXT NOT NULL,
+  entity_type TEXT NOT NULL,
+  entity_id TEXT NOT NULL,
+  recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE INDEX audit_log_entity_idx ON audit_log(entity_type, entity_id);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+-- The read model: each entity's merged state, as a projection of that
+-- entity alone.
+CREATE TABLE entities (
+  entity_type TEXT NOT NULL,
+  entity_id TEXT NOT NULL,
+  state TEXT NOT NULL,
+  PRIMARY KEY (entity_type, entity_id)
+);
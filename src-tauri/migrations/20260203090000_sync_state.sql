@@ -0,0 +1,11 @@
+-- Where this device syncs and how far it has pulled: `cursor` is the
+-- `next_cursor` of the last pulled page applied, `scope_revision` the
+-- revision of the device's sync scope it was pulled under.
+CREATE TABLE sync_state (
+  id INTEGER PRIMARY KEY CHECK (id = 1),
+  server_url TEXT,
+  cursor INTEGER,
+  scope_revision INTEGER
+);
+
+INSERT INTO sync_state (id) VALUES (1);
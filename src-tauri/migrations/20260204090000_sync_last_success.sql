@@ -0,0 +1,2 @@
+-- When a sync run last completed, for the UI's sync indicator.
+ALTER TABLE sync_state ADD COLUMN last_success_at TEXT;
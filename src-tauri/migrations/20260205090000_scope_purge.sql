@@ -0,0 +1,10 @@
+-- Ops pulled from the server are deleted again once the device's sync scope
+-- stops covering them. Ops written here are never deleted: the audit log
+-- refers to them.
+DROP TRIGGER ops_no_delete;
+
+CREATE TRIGGER ops_no_delete BEFORE DELETE ON ops
+WHEN EXISTS (SELECT 1 FROM audit_log WHERE op_id = OLD.op_id)
+BEGIN
+  SELECT RAISE(ABORT, 'ops written on this device are never deleted');
+END;
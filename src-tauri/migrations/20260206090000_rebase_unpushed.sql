@@ -0,0 +1,11 @@
+-- An op still in the outbox has not reached the server, so it may be
+-- renumbered when the server turns out to hold other ops under its
+-- `device_seq`, as after this device was restored from a backup. Ops the
+-- server has stored, and pulled ops, stay as they are.
+DROP TRIGGER ops_no_update;
+
+CREATE TRIGGER ops_no_update BEFORE UPDATE ON ops
+WHEN NOT EXISTS (SELECT 1 FROM outbox WHERE op_id = OLD.op_id)
+BEGIN
+  SELECT RAISE(ABORT, 'ops is append-only');
+END;
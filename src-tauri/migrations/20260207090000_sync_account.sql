@@ -0,0 +1,5 @@
+-- Who this device last signed in as. The cursor and scope revision belong to
+-- one server's log for one organization, so signing in to another starts
+-- the pull over.
+ALTER TABLE sync_state ADD COLUMN organization_id TEXT;
+ALTER TABLE sync_state ADD COLUMN user_id TEXT;
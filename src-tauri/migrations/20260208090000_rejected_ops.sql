@@ -0,0 +1,10 @@
+-- Ops written here that the server refused for good, kept for the UI to
+-- show rather than pushed again. A rejected op leaves the outbox and the
+-- device's chain: its `device_seq` becomes the negative of its rowid, which
+-- no op in the chain holds, and the ops queued after it are renumbered.
+CREATE TABLE rejected_ops (
+  op_id TEXT PRIMARY KEY REFERENCES ops(op_id),
+  reason TEXT NOT NULL,
+  message TEXT NOT NULL,
+  rejected_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
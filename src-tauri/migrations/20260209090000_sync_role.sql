@@ -0,0 +1,4 @@
+-- The role the device last signed in with. What a device holds is filtered
+-- by the signed-in user's role, so signing in as another user, or with
+-- another role, drops what was pulled and starts the pull over.
+ALTER TABLE sync_state ADD COLUMN role TEXT;
@@ -1,8 +1,13 @@
+use crate::core::device::DeviceIdentity;
 use crate::core::error::{AppError, AppResult};
+use crate::core::keychain;
+use crate::sync::log::{Account, LocalStore};
+use crate::sync::worker::{self, SyncHandle};
 use keyring::Entry;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use tauri::{AppHandle, Manager, State};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 pub struct OrganizationInfo {
@@ -46,6 +51,8 @@ struct ServerErrorBody {
 #[tauri::command(rename_all = "camelCase")]
 #[specta::specta]
 pub(crate) async fn login(
+    app: AppHandle,
+    sync: State<'_, SyncHandle>,
     server_url: String,
     organization_code: String,
     email: String,
@@ -75,7 +82,21 @@ pub(crate) async fn login(
         message: format!("failed to decode server response: {e}"),
     })?;
 
+    // Registering is idempotent and binds the new session to this device, so
+    // it runs on every login rather than only the first.
+    let store = app.state::<LocalStore>();
+    let identity = store.log()?.identity();
+    register_device(&client, &server_url, &data.session_token, identity).await?;
+
     store_session_token(&data.session_token)?;
+    let account = Account {
+        server_url: server_url.clone(),
+        organization_id: data.organization.id.clone(),
+        user_id: data.user.id.clone(),
+        role: data.user.role.clone(),
+    };
+    worker::with_log(&app, move |log| log.sign_in(&account)).await?;
+    sync.wake();
 
     Ok(SessionInfo {
         organization: data.organization,
@@ -148,7 +169,34 @@ pub(crate) async fn logout(server_url: String) -> AppResult<()> {
     Ok(())
 }
 
-fn join_url(base: &str, path: &str) -> AppResult<String> {
+async fn register_device(
+    client: &reqwest::Client,
+    server_url: &str,
+    token: &str,
+    identity: &DeviceIdentity,
+) -> AppResult<()> {
+    let url = join_url(server_url, "/v1/devices")?;
+    let response = client
+        .post(url)
+        .bearer_auth(token)
+        .json(&serde_json::json!({
+            "device_id": identity.device_id,
+            "name": format!("medxz desktop ({})", std::env::consts::OS),
+            "public_key": identity.public_key(),
+        }))
+        .send()
+        .await
+        .map_err(|e| AppError::Network {
+            message: e.to_string(),
+        })?;
+
+    if !response.status().is_success() {
+        return Err(parse_server_error(response).await);
+    }
+    Ok(())
+}
+
+pub(crate) fn join_url(base: &str, path: &str) -> AppResult<String> {
     let base = base.trim();
     if base.is_empty() {
         return Err(AppError::InvalidServerUrl {
@@ -161,29 +209,15 @@ fn join_url(base: &str, path: &str) -> AppResult<String> {
 }
 
 fn session_entry() -> AppResult<Entry> {
-    Entry::new("com.medxz.app", "session_token").map_err(|e| AppError::Keychain {
-        message: e.to_string(),
-    })
+    keychain::entry("session_token")
 }
 
 fn store_session_token(token: &str) -> AppResult<()> {
-    session_entry()?
-        .set_password(token)
-        .map_err(|e| AppError::Keychain {
-            message: e.to_string(),
-        })?;
-    Ok(())
+    keychain::write(&session_entry()?, token)
 }
 
-fn load_session_token() -> AppResult<Option<String>> {
-    let entry = session_entry()?;
-    match entry.get_password() {
-        Ok(value) => Ok(Some(value)),
-        Err(keyring::Error::NoEntry) => Ok(None),
-        Err(e) => Err(AppError::Keychain {
-            message: e.to_string(),
-        }),
-    }
+pub(crate) fn load_session_token() -> AppResult<Option<String>> {
+    keychain::read(&session_entry()?)
 }
 
 fn delete_session_token() -> AppResult<()> {
@@ -197,7 +231,7 @@ fn delete_session_token() -> AppResult<()> {
     }
 }
 
-async fn parse_server_error(response: reqwest::Response) -> AppError {
+pub(crate) async fn parse_server_error(response: reqwest::Response) -> AppError {
     let status = response.status().as_u16();
     let body = response.json::<ServerErrorBody>().await;
     match body {
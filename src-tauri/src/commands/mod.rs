@@ -1,2 +1,4 @@
 pub(crate) mod auth;
 pub(crate) mod greet;
+pub(crate) mod store;
+pub(crate) mod sync;
@@ -0,0 +1,12 @@
+use tauri::State;
+
+use crate::core::error::AppResult;
+use crate::sync::log::LocalStore;
+
+/// Whether the local database opened at startup; the error explains why it
+/// did not, such as a database key missing from the keychain.
+#[tauri::command]
+#[specta::specta]
+pub(crate) async fn local_store_status(store: State<'_, LocalStore>) -> AppResult<()> {
+    store.log().map(|_| ())
+}
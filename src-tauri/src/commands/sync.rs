@@ -0,0 +1,93 @@
+use serde::Serialize;
+use specta::Type;
+use tauri::{AppHandle, State};
+
+use crate::core::error::{AppError, AppResult};
+use crate::sync::worker::{self, SyncHandle};
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct SyncStatus {
+    /// RFC 3339 time the last sync run completed, if any has.
+    pub last_success_at: Option<String>,
+    /// Local changes not yet pushed to the server.
+    pub backlog: u32,
+    /// Local changes the server refused; see `rejected_ops`.
+    pub rejected: u32,
+    /// Why the latest run failed; cleared by the next one that completes.
+    pub last_error: Option<AppError>,
+    /// How far this device has pulled the server's log.
+    pub cursor: Option<String>,
+}
+
+/// A local change the server refused for good.
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct RejectedOp {
+    pub op_id: String,
+    pub entity_type: String,
+    pub entity_id: String,
+    pub op_type: String,
+    /// The server's reason code, such as `permission_denied`.
+    pub reason: String,
+    pub message: String,
+    /// RFC 3339.
+    pub rejected_at: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStage {
+    Pushing,
+    Pulling,
+    Done,
+}
+
+/// Emitted after each batch a sync run pushes or pulls, and once it is done.
+/// Counts are for the run so far.
+#[derive(Debug, Clone, Serialize, Type, tauri_specta::Event)]
+pub struct SyncProgress {
+    pub stage: SyncStage,
+    pub pushed: u32,
+    pub pulled: u32,
+}
+
+/// Emitted when a sync run fails.
+#[derive(Debug, Clone, Serialize, Type, tauri_specta::Event)]
+pub struct SyncFailed {
+    pub error: AppError,
+    /// When the run is retried, unless syncing is paused until the user
+    /// signs in again.
+    pub retry_in_secs: Option<u32>,
+}
+
+/// Starts a sync run now rather than at the next scheduled one.
+#[tauri::command]
+#[specta::specta]
+pub(crate) async fn sync_now(sync: State<'_, SyncHandle>) -> AppResult<()> {
+    sync.wake();
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub(crate) async fn sync_status(
+    app: AppHandle,
+    sync: State<'_, SyncHandle>,
+) -> AppResult<SyncStatus> {
+    let (state, (backlog, rejected)) =
+        worker::with_log(&app, |log| Ok((log.sync_state()?, log.backlog()?))).await?;
+    Ok(SyncStatus {
+        last_success_at: state.last_success_at,
+        backlog: u32::try_from(backlog).unwrap_or(u32::MAX),
+        rejected: u32::try_from(rejected).unwrap_or(u32::MAX),
+        last_error: sync.last_error(),
+        cursor: state.cursor.map(|cursor| cursor.0.to_string()),
+    })
+}
+
+/// Local changes the server refused, newest first. They stay on this device
+/// but are never pushed again.
+#[tauri::command]
+#[specta::specta]
+pub(crate) async fn rejected_ops(app: AppHandle) -> AppResult<Vec<RejectedOp>> {
+    worker::with_log(&app, |log| log.rejected()).await
+}
@@ -0,0 +1,231 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use rusqlite::{Connection, ErrorCode, OptionalExtension, Transaction, TransactionBehavior};
+use tauri::Manager;
+use uuid::Uuid;
+
+use crate::core::error::{AppError, AppResult};
+use crate::core::keychain;
+
+const FILE_NAME: &str = "medxz.db";
+
+/// Embedded schema migrations, applied in order. `PRAGMA user_version`
+/// records how many have run, so never edit or reorder an applied one.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "20260201090000_local_store",
+        include_str!("../../migrations/20260201090000_local_store.sql"),
+    ),
+    (
+        "20260202090000_op_log",
+        include_str!("../../migrations/20260202090000_op_log.sql"),
+    ),
+    (
+        "20260203090000_sync_state",
+        include_str!("../../migrations/20260203090000_sync_state.sql"),
+    ),
+    (
+        "20260204090000_sync_last_success",
+        include_str!("../../migrations/20260204090000_sync_last_success.sql"),
+    ),
+    (
+        "20260205090000_scope_purge",
+        include_str!("../../migrations/20260205090000_scope_purge.sql"),
+    ),
+    (
+        "20260206090000_rebase_unpushed",
+        include_str!("../../migrations/20260206090000_rebase_unpushed.sql"),
+    ),
+    (
+        "20260207090000_sync_account",
+        include_str!("../../migrations/20260207090000_sync_account.sql"),
+    ),
+    (
+        "20260208090000_rejected_ops",
+        include_str!("../../migrations/20260208090000_rejected_ops.sql"),
+    ),
+    (
+        "20260209090000_sync_role",
+        include_str!("../../migrations/20260209090000_sync_role.sql"),
+    ),
+];
+
+/// How long a statement waits for a lock held by another connection, such as
+/// a second instance of the app, before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keychain entry holding the hex-encoded [`DatabaseKey`].
+const KEY_ENTRY: &str = "database_key";
+
+/// The key the local database is encrypted with: random, generated with the
+/// database and kept only in the OS credential store.
+pub(crate) struct DatabaseKey([u8; 32]);
+
+impl DatabaseKey {
+    /// Loads the key of the database at `path`, generating one if neither
+    /// exists yet. A database without its key stays unreadable: the key is
+    /// not recreated over it, so nothing overwrites the records it holds.
+    pub(crate) fn load_or_create(path: &Path) -> AppResult<Self> {
+        let entry = keychain::entry(KEY_ENTRY)?;
+        if let Some(hex_key) = keychain::read(&entry)? {
+            let mut key = [0u8; 32];
+            hex::decode_to_slice(&hex_key, &mut key).map_err(|e| AppError::Keychain {
+                message: format!("stored database key is invalid: {e}"),
+            })?;
+            return Ok(DatabaseKey(key));
+        }
+        if path.exists() {
+            return Err(AppError::DatabaseKeyMissing);
+        }
+
+        let key = DatabaseKey::generate();
+        // Stored before the database is created, so a database never exists
+        // without its key having been saved.
+        keychain::write(&entry, &hex::encode(key.0))?;
+        tracing::info!("created local database key");
+        Ok(key)
+    }
+
+    /// A new random key, not yet stored anywhere.
+    pub(crate) fn generate() -> Self {
+        DatabaseKey(rand::random())
+    }
+}
+
+/// The local SQLCipher database: this device's copy of the log and the read
+/// model built from it. Held as managed Tauri state.
+///
+/// There is one connection; writers queue on it, which keeps every write a
+/// single serialized transaction.
+pub(crate) struct Db {
+    conn: Mutex<Connection>,
+}
+
+impl Db {
+    /// Opens, or creates, the database at `path` and brings its schema up to
+    /// date. The database belongs to `device_id`; a file written by another
+    /// device identity is refused rather than appended to.
+    pub(crate) fn open(path: &Path, key: &DatabaseKey, device_id: Uuid) -> AppResult<Self> {
+        let conn = Connection::open(path)?;
+        // Must be the first statement on the connection.
+        conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", hex::encode(key.0)))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Reading the schema is the first point at which a wrong key shows.
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|e| match e.sqlite_error_code() {
+                Some(ErrorCode::NotADatabase) => AppError::DatabaseDecryptFailed {
+                    message: format!("{}: {e}", path.display()),
+                },
+                _ => e.into(),
+            })?;
+        // WAL keeps readers off the writer's back; with `synchronous = FULL`
+        // a committed transaction survives a power cut, not only a crash.
+        let journal_mode: String =
+            conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Err(AppError::Database {
+                message: format!("could not enable WAL, journal_mode is {journal_mode}"),
+            });
+        }
+        conn.execute_batch("PRAGMA synchronous = FULL; PRAGMA foreign_keys = ON;")?;
+
+        let db = Db {
+            conn: Mutex::new(conn),
+        };
+        db.migrate()?;
+        db.claim(device_id)?;
+        Ok(db)
+    }
+
+    /// Runs `f` in a write transaction, committed if `f` succeeds and rolled
+    /// back otherwise. The transaction takes the write lock up front, so it
+    /// cannot fail halfway for want of it.
+    pub(crate) fn write<T>(&self, f: impl FnOnce(&Transaction) -> AppResult<T>) -> AppResult<T> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// Runs `f` against a consistent view of the database.
+    pub(crate) fn read<T>(&self, f: impl FnOnce(&Transaction) -> AppResult<T>) -> AppResult<T> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+        f(&tx)
+    }
+
+    fn lock(&self) -> AppResult<MutexGuard<'_, Connection>> {
+        self.conn.lock().map_err(|_| AppError::Database {
+            message: "a previous write panicked".into(),
+        })
+    }
+
+    fn migrate(&self) -> AppResult<()> {
+        self.write(|tx| {
+            let applied: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            if applied > MIGRATIONS.len() {
+                return Err(AppError::Database {
+                    message: format!(
+                        "database schema version {applied} is newer than this build ({})",
+                        MIGRATIONS.len()
+                    ),
+                });
+            }
+            for (name, sql) in &MIGRATIONS[applied..] {
+                tx.execute_batch(sql).map_err(|e| AppError::Database {
+                    message: format!("migration {name} failed: {e}"),
+                })?;
+                tracing::info!(migration = %name, "applied local migration");
+            }
+            tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+            Ok(())
+        })
+    }
+
+    fn claim(&self, device_id: Uuid) -> AppResult<()> {
+        self.write(|tx| {
+            let owner: Option<String> = tx
+                .query_row("SELECT device_id FROM store_info", [], |row| row.get(0))
+                .optional()?;
+            match owner {
+                None => {
+                    tx.execute(
+                        "INSERT INTO store_info (id, device_id) VALUES (1, ?1)",
+                        [device_id.to_string()],
+                    )?;
+                }
+                Some(owner) if owner == device_id.to_string() => {}
+                Some(owner) => {
+                    return Err(AppError::Database {
+                        message: format!(
+                            "local database belongs to device {owner}, not {device_id}"
+                        ),
+                    })
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Where the database lives: the app's data directory, created if missing.
+pub(crate) fn path(app: &tauri::AppHandle) -> AppResult<PathBuf> {
+    let dir = app.path().app_data_dir().map_err(|e| AppError::Database {
+        message: format!("no app data directory: {e}"),
+    })?;
+    std::fs::create_dir_all(&dir).map_err(|e| AppError::Database {
+        message: format!("cannot create {}: {e}", dir.display()),
+    })?;
+    Ok(dir.join(FILE_NAME))
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Database {
+            message: e.to_string(),
+        }
+    }
+}
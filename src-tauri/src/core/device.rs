@@ -0,0 +1,60 @@
+use medxz_protocol::signing::SigningKey;
+use medxz_protocol::{DevicePublicKey, Operation};
+use uuid::Uuid;
+
+use crate::core::error::{AppError, AppResult};
+use crate::core::keychain;
+
+/// This installation's identity: the id it authors ops under and the key it
+/// signs them with. Both live in the OS credential store.
+pub(crate) struct DeviceIdentity {
+    pub device_id: Uuid,
+    signing_key: SigningKey,
+}
+
+impl DeviceIdentity {
+    /// Loads the identity, creating one on first run. A device whose key was
+    /// lost gets a new id too, since the server pins each id to one key.
+    pub(crate) fn load_or_create() -> AppResult<Self> {
+        let id_entry = keychain::entry("device_id")?;
+        let key_entry = keychain::entry("device_signing_key")?;
+
+        if let (Some(id), Some(key)) = (keychain::read(&id_entry)?, keychain::read(&key_entry)?) {
+            let device_id = Uuid::parse_str(&id).map_err(|e| AppError::Keychain {
+                message: format!("stored device id is invalid: {e}"),
+            })?;
+            let mut secret = [0u8; 32];
+            hex::decode_to_slice(&key, &mut secret).map_err(|e| AppError::Keychain {
+                message: format!("stored device signing key is invalid: {e}"),
+            })?;
+            return Ok(Self {
+                device_id,
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+
+        let identity = Self::generate();
+        // The id is written last, so its presence implies the key was stored.
+        keychain::write(&key_entry, &hex::encode(identity.signing_key.to_bytes()))?;
+        keychain::write(&id_entry, &identity.device_id.to_string())?;
+        tracing::info!(device_id = %identity.device_id, "created device identity");
+        Ok(identity)
+    }
+
+    /// A new identity, not yet stored anywhere.
+    pub(crate) fn generate() -> Self {
+        Self {
+            device_id: Uuid::now_v7(),
+            signing_key: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> DevicePublicKey {
+        DevicePublicKey::from(&self.signing_key)
+    }
+
+    /// Seals and signs an op this device authored.
+    pub(crate) fn sign(&self, op: &mut Operation) {
+        op.sign(&self.signing_key);
+    }
+}
@@ -2,7 +2,7 @@ use serde::Serialize;
 use specta::Type;
 use thiserror::Error;
 
-#[derive(Debug, Error, Serialize, Type)]
+#[derive(Debug, Clone, Error, Serialize, Type)]
 #[serde(tag = "type", content = "details")]
 pub enum AppError {
     #[error("name cannot be empty")]
@@ -23,6 +23,18 @@ pub enum AppError {
         code: String,
         message: String,
     },
+
+    #[error("local database error: {message}")]
+    Database { message: String },
+
+    #[error("the local database key is missing from the keychain")]
+    DatabaseKeyMissing,
+
+    #[error("the local database cannot be decrypted with the stored key: {message}")]
+    DatabaseDecryptFailed { message: String },
+
+    #[error("invalid operation {op_id}: {message}")]
+    InvalidOperation { op_id: String, message: String },
 }
 
 pub type AppResult<T> = Result<T, AppError>;
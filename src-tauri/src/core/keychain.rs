@@ -0,0 +1,30 @@
+//! Secrets kept in the OS credential store: the device identity, the local
+//! database key and the session token.
+
+use keyring::Entry;
+
+use crate::core::error::{AppError, AppResult};
+
+const SERVICE: &str = "com.medxz.app";
+
+pub(crate) fn entry(name: &str) -> AppResult<Entry> {
+    Entry::new(SERVICE, name).map_err(|e| AppError::Keychain {
+        message: e.to_string(),
+    })
+}
+
+pub(crate) fn read(entry: &Entry) -> AppResult<Option<String>> {
+    match entry.get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(AppError::Keychain {
+            message: e.to_string(),
+        }),
+    }
+}
+
+pub(crate) fn write(entry: &Entry, value: &str) -> AppResult<()> {
+    entry.set_password(value).map_err(|e| AppError::Keychain {
+        message: e.to_string(),
+    })
+}
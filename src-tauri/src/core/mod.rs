@@ -1,2 +1,5 @@
+pub mod db;
+pub mod device;
 pub mod error;
+pub mod keychain;
 pub mod logging;
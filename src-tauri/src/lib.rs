@@ -4,21 +4,56 @@
 mod commands;
 mod core;
 mod specta_gen;
+mod sync;
+
+use tauri::Manager;
+
+use crate::core::db::{self, DatabaseKey, Db};
+use crate::core::device::DeviceIdentity;
+use crate::core::error::AppResult;
+use crate::sync::log::{LocalStore, OpLog};
+use crate::sync::worker::{self, SyncHandle};
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let builder = specta_gen::builder();
     let result = tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .setup(|_app| {
+        .invoke_handler(builder.invoke_handler())
+        .setup(move |app| {
             core::logging::init();
             tracing::info!("tauri app starting");
+            builder.mount_events(app);
+
+            // The UI asks for `local_store_status` and explains a failure;
+            // until the store opens, nothing can sync.
+            let store = LocalStore::new(open_log(app.handle()));
+            let available = match store.log() {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::error!("local database unavailable: {e}");
+                    false
+                }
+            };
+            app.manage(store);
+            app.manage(SyncHandle::default());
+            if available {
+                worker::spawn(app.handle().clone());
+            }
             Ok(())
         })
-        .invoke_handler(builder.invoke_handler())
         .run(tauri::generate_context!());
 
     if let Err(err) = result {
         eprintln!("error while running tauri application: {err}");
     }
 }
+
+fn open_log(app: &tauri::AppHandle) -> AppResult<OpLog> {
+    let identity = DeviceIdentity::load_or_create()?;
+    let path = db::path(app)?;
+    let key = DatabaseKey::load_or_create(&path)?;
+    let db = Db::open(&path, &key, identity.device_id)?;
+    tracing::info!(path = %path.display(), "local database open");
+    Ok(OpLog::new(db, identity))
+}
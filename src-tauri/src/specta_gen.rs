@@ -1,16 +1,25 @@
 #[cfg(debug_assertions)]
 use specta_typescript::Typescript;
-use tauri_specta::{collect_commands, Builder};
+use tauri_specta::{collect_commands, collect_events, Builder};
 
 use crate::commands;
 
 pub fn builder() -> Builder<tauri::Wry> {
-    let builder = Builder::<tauri::Wry>::new().commands(collect_commands![
-        commands::greet::greet,
-        commands::auth::login,
-        commands::auth::me,
-        commands::auth::logout
-    ]);
+    let builder = Builder::<tauri::Wry>::new()
+        .commands(collect_commands![
+            commands::greet::greet,
+            commands::auth::login,
+            commands::auth::me,
+            commands::auth::logout,
+            commands::store::local_store_status,
+            commands::sync::sync_now,
+            commands::sync::sync_status,
+            commands::sync::rejected_ops
+        ])
+        .events(collect_events![
+            commands::sync::SyncProgress,
+            commands::sync::SyncFailed
+        ]);
 
     #[cfg(debug_assertions)]
     if let Err(err) = builder.export(
@@ -0,0 +1,172 @@
+use std::io::Write;
+
+use medxz_protocol::wire::WireFormat;
+use medxz_protocol::{
+    Capabilities, DeviceScope, Operation, PullRequest, PullResponse, PushRequest, PushResponse,
+    PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+};
+use reqwest::header::{ACCEPT, CONTENT_ENCODING, CONTENT_TYPE};
+use serde::de::DeserializeOwned;
+
+use crate::commands::auth::{join_url, parse_server_error};
+use crate::core::error::{AppError, AppResult};
+
+/// The server's sync endpoints, called with the signed-in session.
+pub(crate) struct SyncClient {
+    http: reqwest::Client,
+    server_url: String,
+    token: String,
+    encoding: Encoding,
+}
+
+/// How sync bodies travel, as settled by [`SyncClient::negotiate`].
+/// Responses are decompressed by `reqwest`, which offers every encoding it
+/// was built with.
+#[derive(Debug, Clone, Copy, Default)]
+struct Encoding {
+    format: WireFormat,
+    compression: Option<Compression>,
+}
+
+/// A `Content-Encoding` request bodies can be compressed with.
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Most preferred first.
+    const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::encode_all(body, 0),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl SyncClient {
+    pub(crate) fn new(http: reqwest::Client, server_url: String, token: String) -> Self {
+        SyncClient {
+            http,
+            server_url,
+            token,
+            encoding: Encoding::default(),
+        }
+    }
+
+    /// Fetches the server's capabilities and settles on the most compact
+    /// body format and request compression both sides support. Until then,
+    /// bodies are uncompressed JSON, which every server speaks.
+    pub(crate) async fn negotiate(&mut self) -> AppResult<()> {
+        let request = self
+            .http
+            .get(join_url(&self.server_url, "/v1/sync/capabilities")?);
+        let capabilities: Capabilities = self.send(request).await?;
+        let speaks = |format: WireFormat| {
+            capabilities
+                .formats
+                .iter()
+                .any(|content_type| WireFormat::from_content_type(content_type) == Some(format))
+        };
+        self.encoding = Encoding {
+            format: if speaks(WireFormat::Cbor) {
+                WireFormat::Cbor
+            } else {
+                WireFormat::Json
+            },
+            compression: Compression::ALL.into_iter().find(|compression| {
+                capabilities
+                    .content_encodings
+                    .iter()
+                    .any(|encoding| encoding.eq_ignore_ascii_case(compression.name()))
+            }),
+        };
+        tracing::debug!(encoding = ?self.encoding, "negotiated sync encoding");
+        Ok(())
+    }
+
+    pub(crate) async fn push(&self, ops: Vec<Operation>) -> AppResult<PushResponse> {
+        let Encoding {
+            format,
+            compression,
+        } = self.encoding;
+        let body = format
+            .encode(&PushRequest { ops })
+            .map_err(|e| AppError::Network {
+                message: e.to_string(),
+            })?;
+        let mut request = self
+            .http
+            .post(join_url(&self.server_url, "/v1/sync/push")?)
+            .header(CONTENT_TYPE, format.content_type());
+        let body = match compression {
+            Some(compression) => {
+                request = request.header(CONTENT_ENCODING, compression.name());
+                compression.compress(&body).map_err(|e| AppError::Network {
+                    message: format!("failed to compress request: {e}"),
+                })?
+            }
+            None => body,
+        };
+        self.send(request.body(body)).await
+    }
+
+    pub(crate) async fn pull(&self, req: &PullRequest) -> AppResult<PullResponse> {
+        let request = self
+            .http
+            .get(join_url(&self.server_url, "/v1/sync/pull")?)
+            .query(req);
+        self.send(request).await
+    }
+
+    pub(crate) async fn scope(&self) -> AppResult<DeviceScope> {
+        let request = self.http.get(join_url(&self.server_url, "/v1/sync/scope")?);
+        self.send(request).await
+    }
+
+    /// Sends `request` asking for the negotiated format, and decodes the
+    /// response in whichever format the server answered in.
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> AppResult<T> {
+        let response = request
+            .bearer_auth(&self.token)
+            .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION.to_string())
+            .header(ACCEPT, self.encoding.format.content_type())
+            .send()
+            .await
+            .map_err(|e| AppError::Network {
+                message: e.to_string(),
+            })?;
+
+        if !response.status().is_success() {
+            return Err(parse_server_error(response).await);
+        }
+
+        let format = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(WireFormat::from_content_type)
+            .unwrap_or_default();
+        let body = response.bytes().await.map_err(|e| AppError::Network {
+            message: e.to_string(),
+        })?;
+        format.decode(&body).map_err(|e| AppError::Network {
+            message: format!("failed to decode server response: {e}"),
+        })
+    }
+}
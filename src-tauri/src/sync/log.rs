@@ -0,0 +1,996 @@
+//! The local op log.
+//!
+//! Every change to the local database is an op in `ops`. A write made here
+//! becomes a signed op with this device's next `device_seq`, an outbox entry
+//! and an audit entry, all committed in one transaction with the read model
+//! update, so the read model never shows a change the outbox does not hold.
+//! Ops pulled from the server go through the same read model and are
+//! deduplicated by `op_id`, so a batch can be applied again after a crash.
+//!
+//! A device restored from a backup numbers its new ops from where the backup
+//! stopped, reusing `device_seq`s the server already holds. When those ops
+//! are pulled back, the ops written since the restore, none of which the
+//! server accepted, are renumbered to follow them and signed again.
+
+use medxz_protocol::hash::truncate_device_time;
+use medxz_protocol::hlc::HlcClock;
+use medxz_protocol::payload::Payload;
+use medxz_protocol::projection::Projection;
+use medxz_protocol::{
+    ClinicId, Cursor, DeviceScope, EntityId, EntityRef, OpHash, Operation, OperationId,
+    PullResponse, RejectReason, SyncScope, UserId,
+};
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::commands::sync::RejectedOp;
+use crate::core::db::Db;
+use crate::core::device::DeviceIdentity;
+use crate::core::error::{AppError, AppResult};
+use crate::sync::reducer;
+
+/// Who a local write is made by: the signed-in user and their organization.
+// Built by the front desk's patient registration, still on localStorage.
+#[allow(dead_code)]
+pub(crate) struct Author {
+    pub clinic_id: ClinicId,
+    pub user_id: UserId,
+}
+
+/// The local database together with the identity this device authors ops
+/// under. Held as managed Tauri state.
+pub(crate) struct OpLog {
+    db: Db,
+    identity: DeviceIdentity,
+}
+
+impl OpLog {
+    pub(crate) fn new(db: Db, identity: DeviceIdentity) -> Self {
+        OpLog { db, identity }
+    }
+
+    /// Records a write made on this device to `entity_id`: stamps, signs and
+    /// stores the op, queues it for push, audits it and applies it to the
+    /// read model. Nothing is stored if the payload does not decode.
+    // Called by the front desk's patient registration, still on localStorage.
+    #[allow(dead_code)]
+    pub(crate) fn record(
+        &self,
+        author: &Author,
+        entity_id: EntityId,
+        payload: Payload,
+    ) -> AppResult<Operation> {
+        let device_id = self.identity.device_id;
+        let now = OffsetDateTime::now_utc();
+        self.db.write(|tx| {
+            let (device_seq, prev_hash) = match last_local_op(tx, device_id)? {
+                Some(last) => (last.device_seq + 1, last.hash),
+                None => (1, None),
+            };
+            let mut clock = clock(tx, device_id)?;
+            let hlc = clock.tick(now);
+            save_clock(tx, &clock)?;
+
+            let mut op = Operation {
+                op_id: Uuid::now_v7(),
+                clinic_id: author.clinic_id,
+                device_id,
+                user_id: author.user_id,
+                entity: EntityRef {
+                    entity_type: payload.entity_type().into(),
+                    entity_id,
+                },
+                op_type: payload.op_type().into(),
+                device_time: truncate_device_time(now),
+                device_seq,
+                hlc: Some(hlc),
+                schema_version: payload.schema_version(),
+                payload: payload.to_json(),
+                prev_hash,
+                hash: None,
+                signature: None,
+            };
+            self.identity.sign(&mut op);
+
+            insert(tx, &op)?;
+            tx.execute(
+                "INSERT INTO outbox (op_id) VALUES (?1)",
+                [op.op_id.to_string()],
+            )?;
+            tx.execute(
+                "INSERT INTO audit_log (op_id, user_id, action, entity_type, entity_id) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                (
+                    op.op_id.to_string(),
+                    op.user_id.to_string(),
+                    &op.op_type,
+                    &op.entity.entity_type,
+                    op.entity.entity_id.to_string(),
+                ),
+            )?;
+            reducer::apply(tx, &op)?;
+            Ok(op)
+        })
+    }
+
+    /// Applies ops received from the server, in one transaction, and returns
+    /// how many were new. Ops already in the log, including this device's
+    /// own, are skipped, so applying a batch twice changes nothing.
+    ///
+    /// An op whose payload this build cannot decode is kept in the log but
+    /// left out of the read model.
+    // Called by the Merkle repair with `/v1/sync/fetch`'s ops, not written yet.
+    #[allow(dead_code)]
+    pub(crate) fn apply_remote(&self, ops: &[Operation]) -> AppResult<usize> {
+        self.db.write(|tx| apply_ops(tx, &self.identity, ops))
+    }
+
+    /// Applies a page from `/sync/pull` as [`OpLog::apply_remote`] does and
+    /// moves the persisted cursor past it in the same transaction, so a page
+    /// is never skipped and, after a crash, at most pulled again.
+    pub(crate) fn apply_pulled(&self, page: &PullResponse) -> AppResult<usize> {
+        self.db.write(|tx| {
+            let applied = apply_ops(tx, &self.identity, &page.ops)?;
+            tx.execute(
+                "UPDATE sync_state SET cursor = ?1, scope_revision = ?2",
+                (
+                    page.next_cursor.map(|cursor| cursor.0 as i64),
+                    page.scope_revision as i64,
+                ),
+            )?;
+            Ok(applied)
+        })
+    }
+
+    /// Local ops the server has not acknowledged, oldest first, so that each
+    /// is pushed after the op it chains to. Only ops `user_id` wrote before
+    /// the first op by another user are returned: the server takes an op only
+    /// from its author's session, and a device's ops only in order, so the
+    /// rest wait for their author to sign in.
+    pub(crate) fn unacked(&self, user_id: UserId, limit: usize) -> AppResult<Vec<Operation>> {
+        self.db.read(|tx| {
+            let mut stmt = tx.prepare(
+                "SELECT ops.body FROM outbox JOIN ops USING (op_id) \
+                 ORDER BY ops.device_seq LIMIT ?1",
+            )?;
+            let bodies = stmt
+                .query_map([limit as i64], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut ops = Vec::with_capacity(bodies.len());
+            for body in &bodies {
+                let op = decode(body)?;
+                if op.user_id != user_id {
+                    break;
+                }
+                ops.push(op);
+            }
+            Ok(ops)
+        })
+    }
+
+    /// Drops ops the server has stored from the outbox.
+    pub(crate) fn ack(&self, op_ids: &[OperationId]) -> AppResult<()> {
+        self.db.write(|tx| {
+            for op_id in op_ids {
+                tx.execute("DELETE FROM outbox WHERE op_id = ?1", [op_id.to_string()])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets aside an op the server refused for good, with its reason, and
+    /// takes it out of the device's chain: the ops queued after it are
+    /// renumbered to follow the last op the server holds, and its entity's
+    /// state is recomputed without it.
+    pub(crate) fn reject(
+        &self,
+        op_id: OperationId,
+        reason: &RejectReason,
+        message: &str,
+    ) -> AppResult<()> {
+        let reason = serde_json::to_value(reason)
+            .ok()
+            .and_then(|reason| Some(reason.get("code")?.as_str()?.to_owned()))
+            .unwrap_or_default();
+        self.db.write(|tx| {
+            let body: String = tx.query_row(
+                "SELECT body FROM ops WHERE op_id = ?1",
+                [op_id.to_string()],
+                |row| row.get(0),
+            )?;
+            let op = decode(&body)?;
+            tx.execute(
+                "INSERT INTO rejected_ops (op_id, reason, message) VALUES (?1, ?2, ?3)",
+                (op_id.to_string(), reason, message),
+            )?;
+            tx.execute(
+                "UPDATE ops SET device_seq = -rowid WHERE op_id = ?1",
+                [op_id.to_string()],
+            )?;
+            tx.execute("DELETE FROM outbox WHERE op_id = ?1", [op_id.to_string()])?;
+            let last = last_pushed_op(tx, self.identity.device_id)?;
+            renumber(tx, &self.identity, unpushed(tx)?, last)?;
+            reducer::refold(tx, &op.entity)
+        })
+    }
+
+    /// Ops the server refused, newest first; see [`OpLog::reject`].
+    pub(crate) fn rejected(&self) -> AppResult<Vec<RejectedOp>> {
+        self.db.read(|tx| {
+            let mut stmt = tx.prepare(
+                "SELECT op_id, ops.entity_type, ops.entity_id, ops.op_type, \
+                 reason, message, rejected_at \
+                 FROM rejected_ops JOIN ops USING (op_id) \
+                 ORDER BY rejected_at DESC, rejected_ops.rowid DESC",
+            )?;
+            let rejected = stmt
+                .query_map([], |row| {
+                    Ok(RejectedOp {
+                        op_id: row.get(0)?,
+                        entity_type: row.get(1)?,
+                        entity_id: row.get(2)?,
+                        op_type: row.get(3)?,
+                        reason: row.get(4)?,
+                        message: row.get(5)?,
+                        rejected_at: row.get(6)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rejected)
+        })
+    }
+
+    pub(crate) fn sync_state(&self) -> AppResult<SyncState> {
+        self.db.read(|tx| {
+            tx.query_row(
+                "SELECT server_url, cursor, scope_revision, last_success_at, user_id \
+                 FROM sync_state",
+                [],
+                |row| {
+                    Ok(SyncState {
+                        server_url: row.get(0)?,
+                        cursor: row.get::<_, Option<i64>>(1)?.map(|c| Cursor(c as u64)),
+                        scope_revision: row.get::<_, Option<i64>>(2)?.map(|r| r as u64),
+                        last_success_at: row.get(3)?,
+                        user_id: row
+                            .get::<_, Option<String>>(4)?
+                            .map(|user_id| user_id.parse())
+                            .transpose()
+                            .map_err(|e| {
+                                rusqlite::Error::FromSqlConversionFailure(
+                                    4,
+                                    Type::Text,
+                                    Box::new(e),
+                                )
+                            })?,
+                    })
+                },
+            )
+            .map_err(AppError::from)
+        })
+    }
+
+    /// Remembers who this device signed in as, so syncing resumes against
+    /// the same server after a restart.
+    ///
+    /// What was pulled belongs to one server's log for one organization and
+    /// is filtered by the signed-in user's role, so signing in anywhere else,
+    /// as anyone else or with another role drops it as [`OpLog::restart_pull`]
+    /// does and starts the pull over. The new user's scope then decides what
+    /// comes back.
+    pub(crate) fn sign_in(&self, account: &Account) -> AppResult<()> {
+        self.db.write(|tx| {
+            let previous: [Option<String>; 4] = tx.query_row(
+                "SELECT server_url, organization_id, user_id, role FROM sync_state",
+                [],
+                |row| Ok([row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?]),
+            )?;
+            let current = [
+                &account.server_url,
+                &account.organization_id,
+                &account.user_id,
+                &account.role,
+            ];
+            if previous
+                .iter()
+                .zip(current)
+                .any(|(previous, current)| previous.as_ref() != Some(current))
+            {
+                tracing::info!("signed in as someone else, pulling from the start");
+                let nothing = SyncScope {
+                    patients: Some(vec![]),
+                    entity_types: Some(vec![]),
+                };
+                purge_outside(tx, &nothing)?;
+                tx.execute(
+                    "UPDATE sync_state SET cursor = NULL, scope_revision = NULL",
+                    [],
+                )?;
+            }
+            tx.execute(
+                "UPDATE sync_state \
+                 SET server_url = ?1, organization_id = ?2, user_id = ?3, role = ?4",
+                current,
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Notes that a sync run just completed.
+    pub(crate) fn record_sync_success(&self) -> AppResult<()> {
+        self.db.write(|tx| {
+            tx.execute(
+                "UPDATE sync_state \
+                 SET last_success_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')",
+                [],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// How many local ops wait to be pushed, and how many the server refused.
+    pub(crate) fn backlog(&self) -> AppResult<(u64, u64)> {
+        self.db.read(|tx| {
+            let (queued, rejected): (i64, i64) = tx.query_row(
+                "SELECT (SELECT count(*) FROM outbox), (SELECT count(*) FROM rejected_ops)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            Ok((queued as u64, rejected as u64))
+        })
+    }
+
+    /// Starts pulling again from the beginning of the log, for a device whose
+    /// sync scope changed to `scope`. Ops the new scope adds arrive with the
+    /// rest; those already held are skipped. Pulled ops it no longer covers
+    /// are deleted and the read model is rebuilt without their entities.
+    /// This device's own ops stay in the log, as the audit log refers to
+    /// them, but leave the read model too.
+    pub(crate) fn restart_pull(&self, scope: &DeviceScope) -> AppResult<()> {
+        self.db.write(|tx| {
+            purge_outside(tx, &scope.scope)?;
+            tx.execute(
+                "UPDATE sync_state SET cursor = NULL, scope_revision = ?1",
+                [scope.revision as i64],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// The identity this device authors and signs ops under.
+    pub(crate) fn identity(&self) -> &DeviceIdentity {
+        &self.identity
+    }
+
+    /// The merged state of `entity`; see [`reducer::entity`].
+    // Called by the front desk's patient list, still on localStorage.
+    #[allow(dead_code)]
+    pub(crate) fn entity(&self, entity: &EntityRef) -> AppResult<Projection> {
+        self.db.read(|tx| reducer::entity(tx, entity))
+    }
+}
+
+/// A sign-in, as the server reported it; see [`OpLog::sign_in`].
+pub(crate) struct Account {
+    pub server_url: String,
+    pub organization_id: String,
+    pub user_id: String,
+    pub role: String,
+}
+
+/// The op log, or why the local database could not be opened. Held as
+/// managed Tauri state in place of the [`OpLog`], so that a missing or wrong
+/// database key is explained in the UI rather than stopping the app.
+pub(crate) struct LocalStore(AppResult<OpLog>);
+
+impl LocalStore {
+    pub(crate) fn new(log: AppResult<OpLog>) -> Self {
+        LocalStore(log)
+    }
+
+    /// The op log, or the error opening it failed with.
+    pub(crate) fn log(&self) -> AppResult<&OpLog> {
+        self.0.as_ref().map_err(Clone::clone)
+    }
+}
+
+/// What the sync worker persists between runs; see [`OpLog::sync_state`].
+#[derive(Debug, Clone)]
+pub(crate) struct SyncState {
+    pub server_url: Option<String>,
+    pub cursor: Option<Cursor>,
+    pub scope_revision: Option<u64>,
+    /// RFC 3339; see [`OpLog::record_sync_success`].
+    pub last_success_at: Option<String>,
+    /// Who the device last signed in as; see [`OpLog::sign_in`].
+    pub user_id: Option<UserId>,
+}
+
+fn apply_ops(conn: &Connection, identity: &DeviceIdentity, ops: &[Operation]) -> AppResult<usize> {
+    let now = OffsetDateTime::now_utc();
+    let mut clock = clock(conn, identity.device_id)?;
+    let mut applied = 0;
+    for op in ops {
+        let new = if op.device_id == identity.device_id && seq_taken(conn, op)? {
+            rebase_unpushed(conn, identity, op)?;
+            true
+        } else {
+            insert(conn, op)?
+        };
+        if !new {
+            continue;
+        }
+        applied += 1;
+        if let Some(hlc) = op.hlc {
+            if let Err(e) = clock.observe(hlc, now) {
+                tracing::warn!(op_id = %op.op_id, "not following remote clock: {e}");
+            }
+        }
+        if let Err(e) = reducer::apply(conn, op) {
+            tracing::warn!(op_id = %op.op_id, "op kept out of the read model: {e}");
+        }
+    }
+    save_clock(conn, &clock)?;
+    Ok(applied)
+}
+
+/// Rebuilds the read model with only the entities `scope` covers and deletes
+/// the pulled ops of the rest; see [`OpLog::restart_pull`].
+fn purge_outside(conn: &Connection, scope: &SyncScope) -> AppResult<()> {
+    let excluded = reducer::rebuild(conn, scope)?;
+    for entity in &excluded {
+        conn.execute(
+            "DELETE FROM ops WHERE entity_type = ?1 AND entity_id = ?2 \
+             AND op_id NOT IN (SELECT op_id FROM audit_log)",
+            (&entity.entity_type, entity.entity_id.to_string()),
+        )?;
+    }
+    tracing::info!(
+        purged = excluded.len(),
+        "dropped entities outside the sync scope"
+    );
+    Ok(())
+}
+
+/// Stores `op` unless an op with its `op_id` is already stored; returns
+/// whether it was new.
+fn insert(conn: &Connection, op: &Operation) -> AppResult<bool> {
+    let body = serde_json::to_string(op).map_err(|e| AppError::Database {
+        message: e.to_string(),
+    })?;
+    let inserted = conn.execute(
+        "INSERT INTO ops \
+         (op_id, device_id, device_seq, entity_type, entity_id, op_type, hlc, body) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) \
+         ON CONFLICT (op_id) DO NOTHING",
+        (
+            op.op_id.to_string(),
+            op.device_id.to_string(),
+            op.device_seq as i64,
+            &op.entity.entity_type,
+            op.entity.entity_id.to_string(),
+            &op.op_type,
+            op.hlc.map(|hlc| hlc.to_string()),
+            body,
+        ),
+    )?;
+    Ok(inserted == 1)
+}
+
+/// Whether a local op other than `op` holds `op`'s `device_seq`.
+fn seq_taken(conn: &Connection, op: &Operation) -> AppResult<bool> {
+    let holder: Option<String> = conn
+        .query_row(
+            "SELECT op_id FROM ops WHERE device_id = ?1 AND device_seq = ?2",
+            (op.device_id.to_string(), op.device_seq as i64),
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(holder.is_some_and(|holder| holder != op.op_id.to_string()))
+}
+
+/// Stores `op`, which this device pushed before being restored from an older
+/// backup, and renumbers the unpushed ops to follow the last op the server
+/// holds from this device. Fails if an op the server acknowledged holds the
+/// `device_seq`, as the server never stores two.
+fn rebase_unpushed(conn: &Connection, identity: &DeviceIdentity, op: &Operation) -> AppResult<()> {
+    let unpushed = unpushed(conn)?;
+    if unpushed
+        .first()
+        .is_none_or(|first| first.device_seq > op.device_seq)
+    {
+        return Err(AppError::Database {
+            message: format!(
+                "op {} takes device_seq {} from an op the server acknowledged",
+                op.op_id, op.device_seq
+            ),
+        });
+    }
+
+    let last = match last_pushed_op(conn, identity.device_id)? {
+        Some(last) if last.device_seq > op.device_seq => last,
+        _ => LastOp {
+            device_seq: op.device_seq,
+            hash: op.hash,
+        },
+    };
+    renumber(conn, identity, unpushed, Some(last))?;
+    insert(conn, op)?;
+    tracing::warn!(
+        device_seq = op.device_seq,
+        "server holds ops this device wrote before a restore, renumbered unpushed ops"
+    );
+    Ok(())
+}
+
+/// The ops in the outbox, in chain order.
+fn unpushed(conn: &Connection) -> AppResult<Vec<Operation>> {
+    let bodies = conn
+        .prepare("SELECT ops.body FROM outbox JOIN ops USING (op_id) ORDER BY ops.device_seq")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    bodies.iter().map(|body| decode(body)).collect()
+}
+
+/// Renumbers `unpushed`, outbox ops in chain order, to follow `last`, then
+/// re-chains and signs each again. The outbox is a run of consecutive
+/// `device_seq`s, less any op just rejected, so every op moves the same way:
+/// ops are updated from the end of the run when they move up and from its
+/// start when they move down, and no two ever hold one `device_seq`.
+fn renumber(
+    conn: &Connection,
+    identity: &DeviceIdentity,
+    unpushed: Vec<Operation>,
+    last: Option<LastOp>,
+) -> AppResult<()> {
+    let (mut device_seq, mut prev_hash) = match last {
+        Some(last) => (last.device_seq + 1, last.hash),
+        None => (1, None),
+    };
+    let moving_up = unpushed
+        .first()
+        .is_some_and(|first| first.device_seq < device_seq);
+    let mut updates = Vec::with_capacity(unpushed.len());
+    for mut op in unpushed {
+        op.device_seq = device_seq;
+        op.prev_hash = prev_hash;
+        identity.sign(&mut op);
+        let body = serde_json::to_string(&op).map_err(|e| AppError::Database {
+            message: e.to_string(),
+        })?;
+        updates.push((device_seq as i64, body, op.op_id.to_string()));
+        device_seq += 1;
+        prev_hash = op.hash;
+    }
+    if moving_up {
+        updates.reverse();
+    }
+    for update in updates {
+        conn.execute(
+            "UPDATE ops SET device_seq = ?1, body = ?2 WHERE op_id = ?3",
+            update,
+        )?;
+    }
+    Ok(())
+}
+
+/// The `device_seq` and hash of an op this device authored.
+struct LastOp {
+    device_seq: u64,
+    hash: Option<OpHash>,
+}
+
+/// The last op in this device's chain. Rejected ops hold a negative
+/// `device_seq` and are not part of it.
+fn last_local_op(conn: &Connection, device_id: Uuid) -> AppResult<Option<LastOp>> {
+    last_op(
+        conn,
+        "SELECT body FROM ops WHERE device_id = ?1 AND device_seq > 0 \
+         ORDER BY device_seq DESC LIMIT 1",
+        device_id,
+    )
+}
+
+/// The last op in this device's chain that the server holds.
+fn last_pushed_op(conn: &Connection, device_id: Uuid) -> AppResult<Option<LastOp>> {
+    last_op(
+        conn,
+        "SELECT body FROM ops WHERE device_id = ?1 AND device_seq > 0 \
+         AND op_id NOT IN (SELECT op_id FROM outbox) \
+         ORDER BY device_seq DESC LIMIT 1",
+        device_id,
+    )
+}
+
+fn last_op(conn: &Connection, sql: &str, device_id: Uuid) -> AppResult<Option<LastOp>> {
+    let body: Option<String> = conn
+        .query_row(sql, [device_id.to_string()], |row| row.get(0))
+        .optional()?;
+    let Some(body) = body else {
+        return Ok(None);
+    };
+    let op = decode(&body)?;
+    Ok(Some(LastOp {
+        device_seq: op.device_seq,
+        hash: op.hash,
+    }))
+}
+
+fn decode(body: &str) -> AppResult<Operation> {
+    serde_json::from_str(body).map_err(|e| AppError::Database {
+        message: format!("stored op is invalid: {e}"),
+    })
+}
+
+fn clock(conn: &Connection, device_id: Uuid) -> AppResult<HlcClock> {
+    let last: Option<String> =
+        conn.query_row("SELECT hlc FROM store_info", [], |row| row.get(0))?;
+    match last {
+        Some(last) => {
+            let last = last.parse().map_err(|e| AppError::Database {
+                message: format!("stored hlc is invalid: {e}"),
+            })?;
+            Ok(HlcClock::resume(last))
+        }
+        None => Ok(HlcClock::new(device_id)),
+    }
+}
+
+fn save_clock(conn: &Connection, clock: &HlcClock) -> AppResult<()> {
+    conn.execute("UPDATE store_info SET hlc = ?1", [clock.last().to_string()])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use medxz_protocol::domain::{
+        NoteRevisionCreated, PatientDemographicsChanged, PatientRegistered,
+    };
+    use medxz_protocol::fhir::Reference;
+
+    use super::*;
+    use crate::core::db::DatabaseKey;
+
+    /// A directory removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("medxz-log-{}", Uuid::now_v7()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &TempDir, key: &DatabaseKey, identity: DeviceIdentity) -> OpLog {
+        let db = Db::open(&dir.0.join("medxz.db"), key, identity.device_id).unwrap();
+        OpLog::new(db, identity)
+    }
+
+    fn author() -> Author {
+        Author {
+            clinic_id: Uuid::now_v7(),
+            user_id: Uuid::now_v7(),
+        }
+    }
+
+    fn registered(family_name: &str) -> Payload {
+        Payload::PatientRegistered(PatientRegistered {
+            family_name: family_name.into(),
+            given_names: vec!["Jane".into()],
+            birth_date: None,
+            gender: None,
+            identifiers: vec![],
+        })
+    }
+
+    fn renamed(family_name: &str) -> Payload {
+        Payload::PatientDemographicsChanged(PatientDemographicsChanged {
+            based_on: vec![],
+            family_name: Some(family_name.into()),
+            given_names: None,
+            birth_date: None,
+            gender: None,
+            identifiers: None,
+        })
+    }
+
+    fn count(log: &OpLog, table: &str) -> i64 {
+        log.db
+            .read(|tx| {
+                let sql = format!("SELECT count(*) FROM {table}");
+                Ok(tx.query_row(&sql, [], |row| row.get(0))?)
+            })
+            .unwrap()
+    }
+
+    fn page(ops: Vec<Operation>) -> PullResponse {
+        PullResponse {
+            ops,
+            next_cursor: Some(Cursor(1)),
+            has_more: false,
+            scope_revision: 1,
+        }
+    }
+
+    #[test]
+    fn local_ops_continue_the_device_chain_across_restarts() {
+        let dir = TempDir::new();
+        let key = DatabaseKey::generate();
+        let log = open(&dir, &key, DeviceIdentity::generate());
+        let author = author();
+        let patient = Uuid::now_v7();
+
+        let first = log.record(&author, patient, registered("Doe")).unwrap();
+        let OpLog { db, identity } = log;
+        drop(db);
+        let log = open(&dir, &key, identity);
+        let second = log.record(&author, patient, renamed("Roe")).unwrap();
+
+        assert_eq!((first.device_seq, first.prev_hash), (1, None));
+        assert_eq!((second.device_seq, second.prev_hash), (2, first.hash));
+        assert!(second.hlc > first.hlc);
+        let key = log.identity.public_key().verifying_key().unwrap();
+        assert!(first.verify_signature(&key));
+        assert!(second.verify_signature(&key));
+    }
+
+    #[test]
+    fn applying_remote_ops_again_changes_nothing() {
+        let (dir, other_dir) = (TempDir::new(), TempDir::new());
+        let key = DatabaseKey::generate();
+        let log = open(&dir, &key, DeviceIdentity::generate());
+        let other = open(&other_dir, &key, DeviceIdentity::generate());
+
+        let own = log
+            .record(&author(), Uuid::now_v7(), registered("Doe"))
+            .unwrap();
+        let remote = other
+            .record(&author(), Uuid::now_v7(), registered("Roe"))
+            .unwrap();
+
+        assert_eq!(log.apply_remote(&[own.clone(), remote.clone()]).unwrap(), 1);
+        let state = log.entity(&remote.entity).unwrap();
+        assert_eq!(state.patients.len(), 1);
+        assert_eq!(log.apply_remote(&[own, remote.clone()]).unwrap(), 0);
+        assert_eq!(log.entity(&remote.entity).unwrap(), state);
+        assert_eq!(count(&log, "ops"), 2);
+        assert_eq!(count(&log, "outbox"), 1);
+    }
+
+    #[test]
+    fn a_write_the_read_model_rejects_leaves_no_op_outbox_or_audit_entry() {
+        let dir = TempDir::new();
+        let log = open(&dir, &DatabaseKey::generate(), DeviceIdentity::generate());
+        let author = author();
+        let tables = ["ops", "outbox", "audit_log", "entities"];
+
+        log.record(&author, Uuid::now_v7(), registered("Doe"))
+            .unwrap();
+        assert_eq!(tables.map(|table| count(&log, table)), [1; 4]);
+
+        // The op, outbox and audit entries are written before the read model
+        // refuses a blank family name.
+        let rejected = log.record(&author, Uuid::now_v7(), registered("  "));
+        assert!(matches!(rejected, Err(AppError::InvalidOperation { .. })));
+        assert_eq!(tables.map(|table| count(&log, table)), [1; 4]);
+        let next = log
+            .record(&author, Uuid::now_v7(), registered("Roe"))
+            .unwrap();
+        assert_eq!(next.device_seq, 2);
+    }
+
+    fn account(organization_id: &str, user_id: UserId, role: &str) -> Account {
+        Account {
+            server_url: "https://medxz.example".into(),
+            organization_id: organization_id.into(),
+            user_id: user_id.to_string(),
+            role: role.into(),
+        }
+    }
+
+    #[test]
+    fn signing_in_to_another_organization_starts_the_pull_over() {
+        let dir = TempDir::new();
+        let log = open(&dir, &DatabaseKey::generate(), DeviceIdentity::generate());
+        let ada = Uuid::now_v7();
+
+        log.sign_in(&account("acme", ada, "clinician")).unwrap();
+        log.apply_pulled(&page(vec![])).unwrap();
+        log.sign_in(&account("acme", ada, "clinician")).unwrap();
+        let state = log.sync_state().unwrap();
+        assert_eq!(
+            (state.cursor, state.scope_revision),
+            (Some(Cursor(1)), Some(1))
+        );
+        assert_eq!(state.user_id, Some(ada));
+
+        log.sign_in(&account("globex", ada, "clinician")).unwrap();
+        let state = log.sync_state().unwrap();
+        assert_eq!((state.cursor, state.scope_revision), (None, None));
+        assert_eq!(state.server_url.as_deref(), Some("https://medxz.example"));
+    }
+
+    #[test]
+    fn a_front_desk_user_signing_in_after_a_clinician_keeps_none_of_their_pull() {
+        let (dir, other_dir) = (TempDir::new(), TempDir::new());
+        let key = DatabaseKey::generate();
+        let log = open(&dir, &key, DeviceIdentity::generate());
+        let other = open(&other_dir, &key, DeviceIdentity::generate());
+        let (clinician, front_desk) = (author(), author());
+        let patient = Uuid::now_v7();
+
+        log.sign_in(&account("acme", clinician.user_id, "clinician"))
+            .unwrap();
+        let own = log.record(&clinician, patient, registered("Doe")).unwrap();
+        let note = other
+            .record(
+                &clinician,
+                Uuid::now_v7(),
+                Payload::NoteRevisionCreated(NoteRevisionCreated {
+                    patient: Reference {
+                        resource_type: "Patient".into(),
+                        id: patient,
+                    },
+                    encounter: None,
+                    supersedes: None,
+                    body: "Presents with a cough.".into(),
+                }),
+            )
+            .unwrap();
+        log.apply_pulled(&page(vec![note.clone()])).unwrap();
+        assert_eq!(log.entity(&note.entity).unwrap().notes.len(), 1);
+
+        log.sign_in(&account("acme", front_desk.user_id, "front_desk"))
+            .unwrap();
+        let state = log.sync_state().unwrap();
+        assert_eq!((state.cursor, state.scope_revision), (None, None));
+        assert_eq!(log.entity(&note.entity).unwrap(), Projection::new());
+        assert_eq!(count(&log, "entities"), 0);
+        // Only the op written here is kept, for the audit log and the outbox.
+        assert_eq!(count(&log, "ops"), 1);
+        assert_eq!(log.unacked(clinician.user_id, 10).unwrap(), [own]);
+    }
+
+    #[test]
+    fn pulled_ops_reusing_unpushed_seqs_move_the_unpushed_ops_after_them() {
+        let dir = TempDir::new();
+        let log = open(&dir, &DatabaseKey::generate(), DeviceIdentity::generate());
+        let author = author();
+        let patient = Uuid::now_v7();
+        let first = log.record(&author, patient, registered("Doe")).unwrap();
+        log.ack(&[first.op_id]).unwrap();
+
+        // Pushed before the device was restored from a backup taken after
+        // its first op.
+        let pushed = |device_seq, prev_hash, family_name| {
+            let mut op = first.clone();
+            op.op_id = Uuid::now_v7();
+            op.device_seq = device_seq;
+            op.prev_hash = prev_hash;
+            op.op_type = "patient.demographics_changed".into();
+            op.payload = renamed(family_name).to_json();
+            log.identity.sign(&mut op);
+            op
+        };
+        let second = pushed(2, first.hash, "Roe");
+        let third = pushed(3, second.hash, "Poe");
+        let unpushed = [
+            log.record(&author, patient, renamed("Loe")).unwrap(),
+            log.record(&author, patient, renamed("Moe")).unwrap(),
+        ];
+        assert_eq!(unpushed.each_ref().map(|op| op.device_seq), [2, 3]);
+
+        assert_eq!(log.apply_pulled(&page(vec![second.clone()])).unwrap(), 1);
+        assert_eq!(log.apply_pulled(&page(vec![third.clone()])).unwrap(), 1);
+        assert_eq!(
+            log.apply_pulled(&page(vec![second, third.clone()]))
+                .unwrap(),
+            0
+        );
+
+        let rebased = log.unacked(author.user_id, 10).unwrap();
+        assert_eq!(
+            rebased
+                .iter()
+                .map(|op| (op.op_id, op.device_seq))
+                .collect::<Vec<_>>(),
+            [(unpushed[0].op_id, 4), (unpushed[1].op_id, 5)]
+        );
+        assert_eq!(rebased[0].prev_hash, third.hash);
+        assert_eq!(rebased[1].prev_hash, rebased[0].hash);
+        let key = log.identity.public_key().verifying_key().unwrap();
+        assert!(rebased.iter().all(|op| op.verify_signature(&key)));
+        let next = log.record(&author, patient, renamed("Noe")).unwrap();
+        assert_eq!((next.device_seq, next.prev_hash), (6, rebased[1].hash));
+        assert_eq!(count(&log, "audit_log"), 4);
+    }
+
+    #[test]
+    fn only_the_signed_in_users_leading_ops_are_pushed() {
+        let dir = TempDir::new();
+        let log = open(&dir, &DatabaseKey::generate(), DeviceIdentity::generate());
+        let (ada, grace) = (author(), author());
+        let patient = Uuid::now_v7();
+
+        let first = log.record(&ada, patient, registered("Doe")).unwrap();
+        let second = log.record(&ada, patient, renamed("Roe")).unwrap();
+        log.record(&grace, patient, renamed("Poe")).unwrap();
+        log.record(&ada, patient, renamed("Loe")).unwrap();
+
+        let ids = |ops: Vec<Operation>| ops.iter().map(|op| op.op_id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(log.unacked(ada.user_id, 10).unwrap()),
+            [first.op_id, second.op_id]
+        );
+        assert!(log.unacked(grace.user_id, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_rejected_op_leaves_the_outbox_the_chain_and_the_read_model() {
+        let dir = TempDir::new();
+        let log = open(&dir, &DatabaseKey::generate(), DeviceIdentity::generate());
+        let author = author();
+        let patient = Uuid::now_v7();
+        let first = log.record(&author, patient, registered("Doe")).unwrap();
+        log.ack(&[first.op_id]).unwrap();
+        let refused = log.record(&author, patient, renamed("Roe")).unwrap();
+        let kept = log.record(&author, patient, renamed("Poe")).unwrap();
+
+        log.reject(
+            refused.op_id,
+            &RejectReason::PermissionDenied,
+            "not allowed",
+        )
+        .unwrap();
+
+        let rejected = log.rejected().unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].op_id, refused.op_id.to_string());
+        assert_eq!(rejected[0].reason, "permission_denied");
+        assert_eq!(log.backlog().unwrap(), (1, 1));
+
+        let queued = log.unacked(author.user_id, 10).unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].op_id, kept.op_id);
+        assert_eq!((queued[0].device_seq, queued[0].prev_hash), (2, first.hash));
+        let key = log.identity.public_key().verifying_key().unwrap();
+        assert!(queued[0].verify_signature(&key));
+
+        let mut expected = Projection::new();
+        expected.apply(&first).unwrap();
+        expected.apply(&kept).unwrap();
+        assert_eq!(log.entity(&first.entity).unwrap(), expected);
+
+        let next = log.record(&author, patient, renamed("Loe")).unwrap();
+        assert_eq!((next.device_seq, next.prev_hash), (3, queued[0].hash));
+    }
+
+    #[test]
+    fn a_pulled_op_reusing_an_acknowledged_seq_is_refused() {
+        let dir = TempDir::new();
+        let log = open(&dir, &DatabaseKey::generate(), DeviceIdentity::generate());
+        let first = log
+            .record(&author(), Uuid::now_v7(), registered("Doe"))
+            .unwrap();
+        log.ack(&[first.op_id]).unwrap();
+
+        let mut clash = first.clone();
+        clash.op_id = Uuid::now_v7();
+        log.identity.sign(&mut clash);
+
+        let result = log.apply_pulled(&page(vec![clash]));
+        assert!(matches!(result, Err(AppError::Database { .. })));
+        assert_eq!(log.sync_state().unwrap().cursor, None);
+    }
+}
@@ -0,0 +1,4 @@
+pub(crate) mod client;
+pub(crate) mod log;
+pub(crate) mod reducer;
+pub(crate) mod worker;
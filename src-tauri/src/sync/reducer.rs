@@ -0,0 +1,130 @@
+use medxz_protocol::projection::Projection;
+use medxz_protocol::{EntityRef, Operation, SyncScope};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::core::error::{AppError, AppResult};
+
+/// The merged state of `entity`, from the read model: a projection holding
+/// that entity alone, empty if no op for it has been applied.
+pub(crate) fn entity(conn: &Connection, entity: &EntityRef) -> AppResult<Projection> {
+    let state: Option<String> = conn
+        .query_row(
+            "SELECT state FROM entities WHERE entity_type = ?1 AND entity_id = ?2",
+            (&entity.entity_type, entity.entity_id.to_string()),
+            |row| row.get(0),
+        )
+        .optional()?;
+    match state {
+        Some(state) => serde_json::from_str(&state).map_err(|e| AppError::Database {
+            message: format!(
+                "stored state of {} {} is invalid: {e}",
+                entity.entity_type, entity.entity_id
+            ),
+        }),
+        None => Ok(Projection::new()),
+    }
+}
+
+/// Folds a local or pulled op into the read model. The payload is decoded
+/// through the protocol's payload registry, so ops written by older builds
+/// are upcast before they reach the read model; the protocol's projection
+/// makes re-applying an op, or applying ops out of order, safe.
+///
+/// An op only ever changes its own entity, so folding it into that entity's
+/// stored projection gives the state folding the whole log would.
+pub(crate) fn apply(conn: &Connection, op: &Operation) -> AppResult<()> {
+    let mut projection = entity(conn, &op.entity)?;
+    projection
+        .apply(op)
+        .map_err(|e| AppError::InvalidOperation {
+            op_id: op.op_id.to_string(),
+            message: e.to_string(),
+        })?;
+    save(conn, &op.entity, &projection)
+}
+
+/// Rebuilds the read model from the stored ops, keeping only the entities
+/// `scope` covers, and returns the ones it left out.
+pub(crate) fn rebuild(conn: &Connection, scope: &SyncScope) -> AppResult<Vec<EntityRef>> {
+    conn.execute("DELETE FROM entities", [])?;
+    let entities = conn
+        .prepare("SELECT DISTINCT entity_type, entity_id FROM ops")?
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut excluded = Vec::new();
+    for (entity_type, entity_id) in entities {
+        let entity = EntityRef {
+            entity_type,
+            entity_id: entity_id.parse().map_err(|e| AppError::Database {
+                message: format!("stored entity id is invalid: {e}"),
+            })?,
+        };
+        let mut projection = fold(conn, &entity)?;
+        projection.restrict(scope);
+        if projection == Projection::new() {
+            excluded.push(entity);
+        } else {
+            save(conn, &entity, &projection)?;
+        }
+    }
+    Ok(excluded)
+}
+
+/// Recomputes the state of `entity` from its stored ops, for an entity one of
+/// whose ops the server rejected.
+pub(crate) fn refold(conn: &Connection, entity: &EntityRef) -> AppResult<()> {
+    let projection = fold(conn, entity)?;
+    if projection == Projection::new() {
+        conn.execute(
+            "DELETE FROM entities WHERE entity_type = ?1 AND entity_id = ?2",
+            (&entity.entity_type, entity.entity_id.to_string()),
+        )?;
+        return Ok(());
+    }
+    save(conn, entity, &projection)
+}
+
+/// Folds every stored op of `entity` the server has not rejected.
+fn fold(conn: &Connection, entity: &EntityRef) -> AppResult<Projection> {
+    let mut projection = Projection::new();
+    for op in ops_of(conn, entity)? {
+        if let Err(e) = projection.apply(&op) {
+            tracing::warn!(op_id = %op.op_id, "op kept out of the read model: {e}");
+        }
+    }
+    Ok(projection)
+}
+
+fn ops_of(conn: &Connection, entity: &EntityRef) -> AppResult<Vec<Operation>> {
+    let bodies = conn
+        .prepare(
+            "SELECT body FROM ops WHERE entity_type = ?1 AND entity_id = ?2 \
+             AND op_id NOT IN (SELECT op_id FROM rejected_ops)",
+        )?
+        .query_map((&entity.entity_type, entity.entity_id.to_string()), |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    bodies
+        .iter()
+        .map(|body| {
+            serde_json::from_str(body).map_err(|e| AppError::Database {
+                message: format!("stored op is invalid: {e}"),
+            })
+        })
+        .collect()
+}
+
+fn save(conn: &Connection, entity: &EntityRef, projection: &Projection) -> AppResult<()> {
+    let state = serde_json::to_string(projection).map_err(|e| AppError::Database {
+        message: e.to_string(),
+    })?;
+    conn.execute(
+        "INSERT INTO entities (entity_type, entity_id, state) VALUES (?1, ?2, ?3) \
+         ON CONFLICT (entity_type, entity_id) DO UPDATE SET state = excluded.state",
+        (&entity.entity_type, entity.entity_id.to_string(), state),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,298 @@
+//! The background sync task.
+//!
+//! While a user is signed in and the app is unlocked, the task pushes the
+//! outbox, pulls everything after the persisted cursor, then waits
+//! [`POLL_INTERVAL`] or until woken. A failed run is retried after a jittered,
+//! exponentially growing delay. The outbox and the cursor live in the local
+//! database, so a restarted app carries on where it stopped.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use medxz_protocol::{PullRequest, PushOutcome, RejectReason};
+use rand::Rng;
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+use tokio::sync::Notify;
+
+use crate::commands::auth::load_session_token;
+use crate::commands::sync::{SyncFailed, SyncProgress, SyncStage};
+use crate::core::error::{AppError, AppResult};
+use crate::sync::client::SyncClient;
+use crate::sync::log::{LocalStore, OpLog};
+
+/// How long the task waits after a successful run before the next one.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Ops sent per push, well under the server's `max_push_ops`.
+const PUSH_BATCH: usize = 100;
+
+/// Wakes and pauses the background sync task. Held as managed Tauri state.
+#[derive(Default)]
+pub(crate) struct SyncHandle {
+    wake: Notify,
+    locked: AtomicBool,
+    last_error: Mutex<Option<AppError>>,
+}
+
+impl SyncHandle {
+    /// Runs a sync now instead of at the next poll or retry; if one is
+    /// running, another follows it.
+    pub(crate) fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Pauses syncing while the app is locked, and resumes it on unlock.
+    // Called by the lock screen, which is not written yet.
+    #[allow(dead_code)]
+    pub(crate) fn set_locked(&self, locked: bool) {
+        self.locked.store(locked, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Why the latest run failed, unless a later one completed.
+    pub(crate) fn last_error(&self) -> Option<AppError> {
+        self.last_error.lock().ok().and_then(|error| error.clone())
+    }
+
+    fn set_last_error(&self, error: Option<AppError>) {
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = error;
+        }
+    }
+
+    /// Waits for `delay`, or until woken.
+    async fn sleep(&self, delay: Duration) {
+        let _ = tokio::time::timeout(delay, self.wake.notified()).await;
+    }
+}
+
+/// Starts the sync task. [`LocalStore`] and [`SyncHandle`] must be managed,
+/// and the store open.
+pub(crate) fn spawn(app: AppHandle) {
+    tauri::async_runtime::spawn(run(app));
+}
+
+async fn run(app: AppHandle) {
+    let http = reqwest::Client::new();
+    let handle = app.state::<SyncHandle>();
+    let mut failures = 0u32;
+    loop {
+        let result = match client(&app, &http).await {
+            Ok(Some(client)) => sync(&app, &client).await,
+            Ok(None) => {
+                // Signed out or locked; signing in or unlocking wakes us.
+                handle.wake.notified().await;
+                continue;
+            }
+            Err(e) => Err(e),
+        };
+        let error = match result {
+            Ok(()) => {
+                failures = 0;
+                handle.set_last_error(None);
+                handle.sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            Err(e) => e,
+        };
+        handle.set_last_error(Some(error.clone()));
+        if let AppError::ServerError { status: 401, .. } = error {
+            tracing::info!("session expired, sync paused until the next sign-in");
+            emit(
+                &app,
+                SyncFailed {
+                    error,
+                    retry_in_secs: None,
+                },
+            );
+            handle.wake.notified().await;
+        } else {
+            failures = failures.saturating_add(1);
+            let delay = backoff(failures);
+            tracing::warn!(failures, ?delay, "sync failed: {error}");
+            emit(
+                &app,
+                SyncFailed {
+                    error,
+                    retry_in_secs: Some(delay.as_secs().try_into().unwrap_or(u32::MAX)),
+                },
+            );
+            handle.sleep(delay).await;
+        }
+    }
+}
+
+/// A client for the signed-in session, or `None` while syncing is paused.
+async fn client(app: &AppHandle, http: &reqwest::Client) -> AppResult<Option<SyncClient>> {
+    if app.state::<SyncHandle>().locked.load(Ordering::SeqCst) {
+        return Ok(None);
+    }
+    // The keychain can block on the OS credential store, so it is read on
+    // the blocking pool like the op log.
+    let token = tauri::async_runtime::spawn_blocking(load_session_token)
+        .await
+        .map_err(|e| AppError::Keychain {
+            message: e.to_string(),
+        })??;
+    let Some(token) = token else {
+        return Ok(None);
+    };
+    let Some(server_url) = with_log(app, |log| log.sync_state()).await?.server_url else {
+        return Ok(None);
+    };
+    let mut client = SyncClient::new(http.clone(), server_url, token);
+    client.negotiate().await?;
+    Ok(Some(client))
+}
+
+/// One run: the outbox is pushed before pulling, so the pull brings back
+/// what other devices wrote in the meantime along with this device's ops.
+async fn sync(app: &AppHandle, client: &SyncClient) -> AppResult<()> {
+    let mut progress = SyncProgress {
+        stage: SyncStage::Pushing,
+        pushed: 0,
+        pulled: 0,
+    };
+    // An op the server cannot take yet must not keep the device from
+    // pulling, which is what mends a chain broken by a restore from backup.
+    let stalled = push(app, client, &mut progress).await?;
+    pull(app, client, &mut progress).await?;
+    if let Some(stalled) = stalled {
+        return Err(stalled);
+    }
+    with_log(app, |log| log.record_sync_success()).await?;
+    progress.stage = SyncStage::Done;
+    emit(app, progress);
+    Ok(())
+}
+
+/// Pushes the signed-in user's ops from the outbox, oldest first, until none
+/// are left; see [`OpLog::unacked`]. An op the server refuses for good is set
+/// aside for the UI and the rest pushed after it. Stops at an op whose chain
+/// the server does not recognise, or whose kind it does not support yet, and
+/// returns why: those ops stay queued.
+async fn push(
+    app: &AppHandle,
+    client: &SyncClient,
+    progress: &mut SyncProgress,
+) -> AppResult<Option<AppError>> {
+    let Some(user_id) = with_log(app, |log| log.sync_state()).await?.user_id else {
+        return Ok(None);
+    };
+    loop {
+        let ops = with_log(app, move |log| log.unacked(user_id, PUSH_BATCH)).await?;
+        if ops.is_empty() {
+            return Ok(None);
+        }
+        let full = ops.len() == PUSH_BATCH;
+        let response = client.push(ops).await?;
+
+        // Ops after a rejected one chain to it, so the server rejects them
+        // too; they are pushed again once renumbered.
+        let mut acked = Vec::new();
+        let mut rejected = None;
+        for result in response.results {
+            match result.outcome {
+                PushOutcome::Rejected { reason, message } => {
+                    rejected = Some((result.op_id, reason, message));
+                    break;
+                }
+                _ => acked.push(result.op_id),
+            }
+        }
+        progress.pushed += acked.len() as u32;
+        with_log(app, move |log| log.ack(&acked)).await?;
+        emit(app, progress.clone());
+        match rejected {
+            Some((
+                op_id,
+                RejectReason::ChainBroken | RejectReason::UnsupportedOperation,
+                message,
+            )) => {
+                return Ok(Some(AppError::InvalidOperation {
+                    op_id: op_id.to_string(),
+                    message,
+                }));
+            }
+            Some((op_id, reason, message)) => {
+                tracing::warn!(%op_id, "server rejected op: {message}");
+                with_log(app, move |log| log.reject(op_id, &reason, &message)).await?;
+            }
+            None if !full => return Ok(None),
+            None => {}
+        }
+    }
+}
+
+/// Pulls and applies pages from the persisted cursor until the server has
+/// no more.
+async fn pull(app: &AppHandle, client: &SyncClient, progress: &mut SyncProgress) -> AppResult<()> {
+    progress.stage = SyncStage::Pulling;
+    loop {
+        let state = with_log(app, |log| log.sync_state()).await?;
+        let page = client
+            .pull(&PullRequest {
+                cursor: state.cursor,
+                limit: None,
+            })
+            .await?;
+        // A new scope can add ops from before the cursor, and drop ones
+        // already held.
+        if state
+            .scope_revision
+            .is_some_and(|revision| revision != page.scope_revision)
+        {
+            let scope = client.scope().await?;
+            tracing::info!(
+                revision = scope.revision,
+                "sync scope changed, pulling from the start"
+            );
+            with_log(app, move |log| log.restart_pull(&scope)).await?;
+            continue;
+        }
+        let has_more = page.has_more;
+        let applied = with_log(app, move |log| log.apply_pulled(&page)).await?;
+        tracing::debug!(applied, "applied pulled ops");
+        progress.pulled += applied as u32;
+        emit(app, progress.clone());
+        if !has_more {
+            return Ok(());
+        }
+    }
+}
+
+/// The delay before retrying after `failures` failed runs in a row: doubling
+/// from [`MIN_BACKOFF`] up to [`MAX_BACKOFF`], then shortened by a random
+/// amount of up to half, so that devices cut off together do not all retry
+/// together.
+fn backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    let delay = MIN_BACKOFF.saturating_mul(1 << doublings).min(MAX_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+fn emit<E: Event + Serialize + Clone>(app: &AppHandle, event: E) {
+    if let Err(e) = event.emit(app) {
+        tracing::warn!("failed to emit {}: {e}", E::NAME);
+    }
+}
+
+/// Runs `f` against the op log on the blocking pool, so a slow disk does
+/// not hold up the async runtime.
+pub(crate) async fn with_log<T: Send + 'static>(
+    app: &AppHandle,
+    f: impl FnOnce(&OpLog) -> AppResult<T> + Send + 'static,
+) -> AppResult<T> {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || f(app.state::<LocalStore>().log()?))
+        .await
+        .map_err(|e| AppError::Database {
+            message: e.to_string(),
+        })?
+}
@@ -3,15 +3,22 @@ import { TooltipProvider } from "@/components/ui/tooltip";
 import { AuthScreen } from "@/features/auth/AuthScreen";
 import { useAuthController } from "@/features/auth/useAuthController";
 import { Dashboard } from "@/features/dashboard/Dashboard";
+import { StoreUnavailable } from "@/features/localStore/StoreUnavailable";
+import { useLocalStoreStatus } from "@/features/localStore/useLocalStoreStatus";
 import { AppShell } from "./AppShell";
 
 function App() {
   const auth = useAuthController();
+  const storeError = useLocalStoreStatus();
 
   return (
     <>
       <TooltipProvider delayDuration={120}>
-        {auth.session ? (
+        {storeError ? (
+          <AppShell>
+            <StoreUnavailable error={storeError} />
+          </AppShell>
+        ) : auth.session ? (
           <Dashboard
             session={auth.session}
             serverUrl={auth.serverUrl}
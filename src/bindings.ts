@@ -39,12 +39,63 @@ async logout(serverUrl: string) : Promise<Result<null, AppError>> {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
+},
+/**
+ * Whether the local database opened at startup; the error explains why it
+ * did not, such as a database key missing from the keychain.
+ */
+async localStoreStatus() : Promise<Result<null, AppError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("local_store_status") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Starts a sync run now rather than at the next scheduled one.
+ */
+async syncNow() : Promise<Result<null, AppError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("sync_now") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async syncStatus() : Promise<Result<SyncStatus, AppError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("sync_status") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Local changes the server refused, newest first. They stay on this device
+ * but are never pushed again.
+ */
+async rejectedOps() : Promise<Result<RejectedOp[], AppError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("rejected_ops") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
 }
 }
 
 /** user-defined events **/
 
 
+export const events = __makeEvents__<{
+syncFailed: SyncFailed,
+syncProgress: SyncProgress
+}>({
+syncFailed: "sync-failed",
+syncProgress: "sync-progress"
+})
+
 
 /** user-defined constants **/
 
@@ -52,9 +103,57 @@ async logout(serverUrl: string) : Promise<Result<null, AppError>> {
 
 /** user-defined types **/
 
-export type AppError = { type: "EmptyName" } | { type: "InvalidServerUrl"; details: { message: string } } | { type: "Network"; details: { message: string } } | { type: "Keychain"; details: { message: string } } | { type: "ServerError"; details: { status: number; code: string; message: string } }
+export type AppError = { type: "EmptyName" } | { type: "InvalidServerUrl"; details: { message: string } } | { type: "Network"; details: { message: string } } | { type: "Keychain"; details: { message: string } } | { type: "ServerError"; details: { status: number; code: string; message: string } } | { type: "Database"; details: { message: string } } | { type: "DatabaseKeyMissing" } | { type: "DatabaseDecryptFailed"; details: { message: string } } | { type: "InvalidOperation"; details: { op_id: string; message: string } }
 export type OrganizationInfo = { id: string; code: string; name: string }
+/**
+ * A local change the server refused for good.
+ */
+export type RejectedOp = { op_id: string; entity_type: string; entity_id: string; op_type: string; 
+/**
+ * The server's reason code, such as `permission_denied`.
+ */
+reason: string; message: string; 
+/**
+ * RFC 3339.
+ */
+rejected_at: string }
 export type SessionInfo = { organization: OrganizationInfo; user: UserInfo }
+/**
+ * Emitted when a sync run fails.
+ */
+export type SyncFailed = { error: AppError; 
+/**
+ * When the run is retried, unless syncing is paused until the user
+ * signs in again.
+ */
+retry_in_secs: number | null }
+/**
+ * Emitted after each batch a sync run pushes or pulls, and once it is done.
+ * Counts are for the run so far.
+ */
+export type SyncProgress = { stage: SyncStage; pushed: number; pulled: number }
+export type SyncStage = "pushing" | "pulling" | "done"
+export type SyncStatus = { 
+/**
+ * RFC 3339 time the last sync run completed, if any has.
+ */
+last_success_at: string | null; 
+/**
+ * Local changes not yet pushed to the server.
+ */
+backlog: number; 
+/**
+ * Local changes the server refused; see `rejected_ops`.
+ */
+rejected: number; 
+/**
+ * Why the latest run failed; cleared by the next one that completes.
+ */
+last_error: AppError | null; 
+/**
+ * How far this device has pulled the server's log.
+ */
+cursor: string | null }
 export type UserInfo = { id: string; email: string; role: string }
 
 /** tauri-specta globals **/
@@ -16,6 +16,14 @@ export function formatAuthError(err: AppError, context: AuthErrorContext): strin
       return "We could not access the system keychain for your session.";
     case "ServerError":
       return formatServerError(err.details.code, context);
+    case "Database":
+      return "The local database could not be read or updated.";
+    case "DatabaseKeyMissing":
+      return "The key to this device's local data is missing from the system keychain.";
+    case "DatabaseDecryptFailed":
+      return "This device's local data cannot be unlocked with the stored key.";
+    case "InvalidOperation":
+      return "A change could not be saved because it is invalid.";
   }
 }
 
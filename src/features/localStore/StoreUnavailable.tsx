@@ -0,0 +1,23 @@
+import type { AppError } from "@/bindings";
+
+export function StoreUnavailable(props: { error: AppError }) {
+  return (
+    <div className="grid gap-2" role="alert">
+      <h1 className="text-2xl font-semibold tracking-tight">Local data unavailable</h1>
+      <p className="text-sm text-muted-foreground">{describe(props.error)}</p>
+    </div>
+  );
+}
+
+function describe(err: AppError): string {
+  switch (err.type) {
+    case "DatabaseKeyMissing":
+      return "The key to this device's local data is missing from the system keychain, so the data cannot be opened. Restore the keychain from a backup, or contact your administrator to set this device up again.";
+    case "DatabaseDecryptFailed":
+      return "This device's local data cannot be unlocked with the key stored in the system keychain. The keychain entry may belong to another installation. Restore it from a backup, or contact your administrator.";
+    case "Keychain":
+      return "The system keychain could not be read. Unlock it and restart medxz.";
+    default:
+      return "The local database could not be opened. Restart medxz; if this keeps happening, contact your administrator.";
+  }
+}
@@ -0,0 +1,19 @@
+import { useEffect, useState } from "react";
+import { type AppError, commands } from "@/bindings";
+
+/** The error the local database failed to open with at startup, if any. */
+export function useLocalStoreStatus(): AppError | null {
+  const [error, setError] = useState<AppError | null>(null);
+
+  useEffect(() => {
+    let cancelled = false;
+    void commands.localStoreStatus().then((result) => {
+      if (!cancelled && result.status === "error") setError(result.error);
+    });
+    return () => {
+      cancelled = true;
+    };
+  }, []);
+
+  return error;
+}